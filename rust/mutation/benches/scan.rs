@@ -0,0 +1,204 @@
+//! Compares the scan backends on a large region, and batch scans with separate scans
+//!
+//! Run with `cargo bench -p mutation`
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use mutation::scan::{batch_scan_all, Backend, Pattern};
+
+const REGION_SIZE: usize = 16 * 1024 * 1024;
+
+/// A region which looks somewhat like x86-64 code, with the match at the very end
+fn region(sig: &[u8]) -> Vec<u8> {
+    // common bytes in x86-64 code, so common anchors get a realistic amount of candidates.
+    // 0x00 is in here twice, since it's by far the most common byte
+    const COMMON: [u8; 8] = [0x00, 0x00, 0x48, 0xFF, 0x89, 0x0F, 0x8B, 0x24];
+
+    let mut state = 0x2545_F491_4F6C_DD1Du64;
+    let mut region = (0..REGION_SIZE)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+
+            // half of the bytes are common ones, the rest are uniformly random
+            if state & 1 == 0 {
+                COMMON[(state >> 8) as usize % COMMON.len()]
+            } else {
+                (state >> 16) as u8
+            }
+        })
+        .collect::<Vec<_>>();
+
+    region[REGION_SIZE - sig.len()..].copy_from_slice(sig);
+    region
+}
+
+fn backends(c: &mut Criterion) {
+    let sigs = [
+        // realistic function prologue with a rare byte
+        (
+            "rare anchor",
+            "48 89 5C 24 ?? 57 48 83 EC 20 8B D9",
+            [0x48, 0x89, 0x5C, 0x24, 0x08, 0x57, 0x48, 0x83, 0xEC, 0x20, 0x8B, 0xD9].as_slice(),
+        ),
+        // only common bytes are known, so the anchor has many candidates
+        (
+            "common anchor",
+            "48 8B ?? 24 ?? 48 89 ?? FF",
+            [0x48, 0x8B, 0x01, 0x24, 0x02, 0x48, 0x89, 0x03, 0xFF].as_slice(),
+        ),
+        // the only known byte is the most common byte
+        (
+            "zero anchor",
+            "00 ?? ?? ?? 00 00 ?? 00",
+            [0x00, 0x01, 0x02, 0x03, 0x00, 0x00, 0x04, 0x00].as_slice(),
+        ),
+        // longer than a single simd unit
+        (
+            "long",
+            "48 89 5C 24 ?? 48 89 74 24 ?? 57 48 83 EC 20 49 8B F8 8B DA 48 8B F1 E8 ?? ?? ?? ?? 84 C0 74 ?? 4C 8B C7",
+            [
+                0x48, 0x89, 0x5C, 0x24, 0x08, 0x48, 0x89, 0x74, 0x24, 0x10, 0x57, 0x48, 0x83, 0xEC,
+                0x20, 0x49, 0x8B, 0xF8, 0x8B, 0xDA, 0x48, 0x8B, 0xF1, 0xE8, 0x00, 0x00, 0x00, 0x00,
+                0x84, 0xC0, 0x74, 0x05, 0x4C, 0x8B, 0xC7,
+            ]
+            .as_slice(),
+        ),
+    ];
+
+    for (name, sig, bytes) in sigs {
+        let pattern = sig.parse::<Pattern>().unwrap();
+        let region = region(bytes);
+
+        let mut group = c.benchmark_group(name);
+        group.throughput(Throughput::Bytes(REGION_SIZE as u64));
+
+        for backend in Backend::ALL.into_iter().filter(|b| b.is_supported()) {
+            group.bench_function(BenchmarkId::new("backend", format!("{backend:?}")), |b| {
+                b.iter(|| unsafe { pattern.scan_with(backend, region.as_ptr(), region.len()) })
+            });
+        }
+
+        // the backend picked automatically
+        group.bench_function(BenchmarkId::new("backend", "Auto"), |b| {
+            b.iter(|| unsafe { pattern.scan(region.as_ptr(), region.len()) })
+        });
+
+        group.finish();
+    }
+}
+
+/// How often the anchor byte shows up decides whether the anchored backend beats the simd backends.
+/// Each region has the anchor at a fixed density, and the pattern only fully matches at the end
+fn anchor_density(c: &mut Criterion) {
+    const ANCHOR: u8 = 0x9A;
+
+    // the anchor is the only fully known byte, the rest are nibbles which never match early
+    let pattern = "9A 1? 2? 3? 4? 5? 6? 7? 8? 9? A? B? C?"
+        .parse::<Pattern>()
+        .unwrap();
+    let sig = [
+        0x9A, 0x10, 0x20, 0x30, 0x40, 0x50, 0x60, 0x70, 0x80, 0x90, 0xA0, 0xB0, 0xC0,
+    ];
+
+    // anchor density in parts per 10000
+    for density in [10, 100, 500, 1000, 1500, 2500, 5000] {
+        let mut state = 0x2545_F491_4F6C_DD1Du64;
+        let mut region = (0..REGION_SIZE)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+
+                if state % 10000 < density {
+                    ANCHOR
+                } else {
+                    // anything but the anchor, and never a nibble of the tail
+                    (state >> 32) as u8 & 0x0F
+                }
+            })
+            .collect::<Vec<_>>();
+
+        region[REGION_SIZE - sig.len()..].copy_from_slice(&sig);
+
+        let mut group = c.benchmark_group(format!("anchor density {:.1}%", density as f64 / 100.0));
+        group.throughput(Throughput::Bytes(REGION_SIZE as u64));
+
+        for backend in Backend::ALL.into_iter().filter(|b| b.is_supported()) {
+            group.bench_function(BenchmarkId::new("backend", format!("{backend:?}")), |b| {
+                b.iter(|| unsafe { pattern.scan_with(backend, region.as_ptr(), region.len()) })
+            });
+        }
+
+        group.finish();
+    }
+}
+
+/// Scanning once for many patterns against scanning for each of them on its own
+fn batch(c: &mut Criterion) {
+    // a mix of rare and common anchors, with some bytes unknown, like real signatures
+    let mut state = 0x9E37_79B9_7F4A_7C15u64;
+    let sigs = (0..64)
+        .map(|_| {
+            (0..12)
+                .map(|i| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+
+                    if i % 4 == 3 {
+                        "??".to_owned()
+                    } else {
+                        format!("{:02X}", state as u8)
+                    }
+                })
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .collect::<Vec<_>>();
+
+    let patterns = sigs
+        .iter()
+        .map(|sig| sig.parse::<Pattern>().unwrap())
+        .collect::<Vec<_>>();
+
+    let region = region(&[0x48, 0x89, 0x5C, 0x24, 0x08, 0x57]);
+
+    for count in [4, 16, 64] {
+        let patterns = &patterns[..count];
+        let names = sigs[..count].iter().map(String::as_str).collect::<Vec<_>>();
+
+        let mut group = c.benchmark_group(format!("batch of {count}"));
+        group.throughput(Throughput::Bytes(REGION_SIZE as u64));
+
+        group.bench_function("batch_scan_all", |b| {
+            b.iter(|| unsafe {
+                batch_scan_all(
+                    names.iter().copied().zip(patterns),
+                    region.as_ptr(),
+                    region.len(),
+                )
+            })
+        });
+
+        group.bench_function("separate scan_all", |b| {
+            b.iter(|| {
+                patterns
+                    .iter()
+                    .map(|pattern| {
+                        unsafe { pattern.scan_all(region.as_ptr(), region.len()) }.count()
+                    })
+                    .sum::<usize>()
+            })
+        });
+
+        group.finish();
+    }
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = backends, anchor_density, batch
+}
+criterion_main!(benches);
@@ -0,0 +1,65 @@
+//! Makes sure every scan backend returns the same results, and never reads outside the region
+//!
+//! Run with `cargo fuzz run scan_backends`
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mutation::scan::{Backend, Pattern};
+
+fuzz_target!(|input: &[u8]| {
+    // first byte splits the input into the pattern and the region to scan
+    let Some((&split, input)) = input.split_first() else {
+        return;
+    };
+
+    // pattern is pairs of data and mask bytes
+    let (pattern, binary) = input.split_at((split as usize * 2).min(input.len() & !1));
+    let (data, mask): (Vec<u8>, Vec<u8>) = pattern.chunks_exact(2).map(|b| (b[0], b[1])).unzip();
+
+    // build the sig out of nibbles, so nibble wildcards are covered too
+    let sig = data
+        .iter()
+        .zip(&mask)
+        .map(|(&data, &mask)| {
+            let nibble = |n: u8, known: bool| {
+                if known {
+                    format!("{n:X}")
+                } else {
+                    "?".to_owned()
+                }
+            };
+
+            nibble(data >> 4, mask & 0xF0 == 0xF0) + &nibble(data & 0xF, mask & 0x0F == 0x0F)
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let pattern = sig.parse::<Pattern>().unwrap();
+
+    // copy to an exactly sized allocation, so reading past the end is caught by the sanitizer
+    let binary = binary.to_vec().into_boxed_slice();
+
+    let mut results = Backend::ALL
+        .into_iter()
+        .filter(|backend| backend.is_supported())
+        .map(|backend| {
+            let first = unsafe { pattern.scan_with(backend, binary.as_ptr(), binary.len()) };
+            let all = unsafe { pattern.scan_all_with(backend, binary.as_ptr(), binary.len()) };
+
+            (
+                backend,
+                first.map(|s| s.addr),
+                all.map(|s| s.addr).collect::<Vec<_>>(),
+            )
+        });
+
+    let (_, first, all) = results.next().unwrap();
+
+    for (backend, other_first, other_all) in results {
+        assert_eq!(first, other_first, "{backend:?} first match differs");
+        assert_eq!(all, other_all, "{backend:?} matches differ");
+    }
+
+    assert_eq!(first, all.first().copied());
+});
@@ -3,9 +3,28 @@
 use core::slice;
 use std::fmt::{self, Display};
 
-use capstone::prelude::*;
-use capstone::Insn;
-use keystone_engine::{Arch, Keystone, Mode};
+use capstone::{Capstone, Insn};
+
+mod block;
+mod cave;
+mod cfg;
+mod detail;
+mod engine;
+mod fixups;
+mod listing;
+mod relocate;
+
+pub use self::block::{
+    assemble_block, AssembleError, AssembleErrorKind, ModuleResolver, SymbolResolver,
+};
+pub use self::cave::{find_caves, Cave, CaveAllocator};
+pub use self::cfg::{analyze_function, BasicBlock, Edge, EdgeKind, Function};
+use self::detail::Detail;
+pub use self::detail::{MemOperand, Operand};
+pub(crate) use self::engine::{capstone, capstone_plain, keystone};
+pub(crate) use self::fixups::{disassemble_fixups, FixupKind};
+pub use self::listing::{listing, Listing, ListingLine, Symbolizer};
+pub(crate) use self::relocate::{relocate, MAX_RELOCATED_SIZE};
 
 /// An error for the [asm](crate::asm) module
 #[derive(Copy, Clone, Debug, thiserror::Error)]
@@ -22,6 +41,12 @@ pub enum AsmError {
     /// no instructions were able to be disassembled
     #[error("there were no instructions to disassemble")]
     NoInstructions,
+    /// a relocated branch targets the inside of the code being relocated
+    #[error("a branch targets the inside of the relocated code")]
+    BranchIntoRelocated,
+    /// a relocated rip-relative operand or branch can't reach its target from the new address
+    #[error("relocated instruction's target is out of range")]
+    RelocationOutOfRange,
     /// a keystone error
     #[error(transparent)]
     Keystone(#[from] keystone_engine::KeystoneError),
@@ -43,11 +68,78 @@ pub struct Inst {
     pub mnemonic: Option<String>,
     /// the op str
     pub op_str: Option<String>,
+    /// the operands, in the same order as the op str
+    pub operands: Vec<Operand>,
+    /// the registers read, both explicitly and implicitly
+    pub regs_read: Vec<String>,
+    /// the registers written, both explicitly and implicitly
+    pub regs_written: Vec<String>,
+    /// the groups the instruction is in, e.g. `jump`, `call`, `ret`, and `branch_relative`
+    pub groups: Vec<String>,
 }
 
 unsafe impl Send for Inst {}
 unsafe impl Sync for Inst {}
 
+impl Inst {
+    /// Convert a disassembled instruction, along with its details
+    pub(crate) fn new(cs: &Capstone, insn: &Insn) -> Self {
+        let Detail {
+            operands,
+            regs_read,
+            regs_written,
+            groups,
+        } = Detail::new(cs, insn);
+
+        Self {
+            operands,
+            regs_read,
+            regs_written,
+            groups,
+            ..Self::from(insn)
+        }
+    }
+
+    /// Whether the instruction is in a group, e.g. `jump`
+    pub fn in_group(&self, group: &str) -> bool {
+        self.groups.iter().any(|g| g == group)
+    }
+
+    /// Whether this is a jump, conditional or not
+    pub fn is_jump(&self) -> bool {
+        self.in_group("jump")
+    }
+
+    /// Whether this is a call
+    pub fn is_call(&self) -> bool {
+        self.in_group("call")
+    }
+
+    /// Whether this is a return
+    pub fn is_ret(&self) -> bool {
+        self.in_group("ret")
+    }
+
+    /// Whether this is a relative call or jump
+    pub fn is_relative_branch(&self) -> bool {
+        self.in_group("branch_relative")
+    }
+
+    /// The address this instruction references, if any.
+    ///
+    /// This is the target of a relative call or jump, or where a rip-relative memory operand points,
+    /// e.g. the global in `mov rax, [rip + 0x1234]`
+    pub fn target(&self) -> Option<*const u8> {
+        self.operands.iter().find_map(|op| match op {
+            Operand::Imm(imm) if self.is_relative_branch() => Some(*imm as *const u8),
+            Operand::Mem(mem) => mem.target,
+            _ => None,
+        })
+    }
+}
+
+/// Convert a disassembled instruction without its details. An [`Insn`] doesn't know which
+/// capstone instance it came from, so the operands, registers, and groups are left empty
 impl<'a> From<&'a Insn<'a>> for Inst {
     fn from(value: &Insn) -> Self {
         Self {
@@ -56,6 +148,10 @@ impl<'a> From<&'a Insn<'a>> for Inst {
             bytes: value.bytes().to_vec(),
             mnemonic: value.mnemonic().map(ToOwned::to_owned),
             op_str: value.op_str().map(ToOwned::to_owned),
+            operands: Vec::new(),
+            regs_read: Vec::new(),
+            regs_written: Vec::new(),
+            groups: Vec::new(),
         }
     }
 }
@@ -102,7 +198,7 @@ pub fn assemble(code: &str) -> Result<Inst, AsmError> {
         return Err(AsmError::BadAsm);
     }
 
-    let ks = Keystone::new(Arch::X86, Mode::MODE_64)?;
+    let ks = keystone()?;
 
     let output = ks.asm(code.into(), 0)?;
 
@@ -110,11 +206,7 @@ pub fn assemble(code: &str) -> Result<Inst, AsmError> {
         return Err(AsmError::BadAsm);
     }
 
-    let cs = Capstone::new()
-        .x86()
-        .mode(arch::x86::ArchMode::Mode64)
-        .syntax(arch::x86::ArchSyntax::Intel)
-        .build()?;
+    let cs = capstone()?;
 
     let insts = cs.disasm_count(&output.bytes, 0, 1)?;
 
@@ -122,20 +214,22 @@ pub fn assemble(code: &str) -> Result<Inst, AsmError> {
         return Err(AsmError::NoInstructions);
     };
 
-    Ok(inst.into())
+    Ok(Inst::new(&cs, inst))
 }
 
 /// Assemble code string to instructions, with runtime_addr
 ///
+/// Use [`assemble_block`] for labels, names, and errors with the line and column they happened at
+///
 /// ```rust,ignore
-/// assemble("jmp [rip]", 0x112233445566);
+/// assemble_ex("jmp [rip]", 0x112233445566);
 /// ```
 pub fn assemble_ex(code: &str, runtime_addr: usize) -> Result<Vec<Inst>, AsmError> {
     if code.is_empty() {
         return Err(AsmError::BadAsm);
     }
 
-    let ks = Keystone::new(Arch::X86, Mode::MODE_64)?;
+    let ks = keystone()?;
 
     let output = ks.asm(code.into(), runtime_addr as u64)?;
 
@@ -154,11 +248,7 @@ pub fn assemble_ex(code: &str, runtime_addr: usize) -> Result<Vec<Inst>, AsmErro
 /// - Address must be valid ptr
 /// - Address must be valid for max 16 bytes read
 pub unsafe fn disassemble(addr: *const u8) -> Result<Inst, AsmError> {
-    let cs = Capstone::new()
-        .x86()
-        .mode(arch::x86::ArchMode::Mode64)
-        .syntax(arch::x86::ArchSyntax::Intel)
-        .build()?;
+    let cs = capstone()?;
 
     let code = unsafe { slice::from_raw_parts(addr, 16) };
 
@@ -168,7 +258,78 @@ pub unsafe fn disassemble(addr: *const u8) -> Result<Inst, AsmError> {
         return Err(AsmError::NoInstructions);
     };
 
-    Ok(inst.into())
+    Ok(Inst::new(&cs, inst))
+}
+
+/// Lazily disassemble instructions one at a time starting at the target address, with
+/// `runtime_addr` as the address of the first instruction
+///
+/// Unlike [`disassemble_ex`], no size is needed up front. The iterator ends at the first
+/// instruction which can't be disassembled.
+///
+/// ```rust,ignore
+/// let insts = unsafe { disassemble_iter(addr, addr as usize) };
+///
+/// for inst in insts.take_while(|inst| inst.mnemonic.as_deref() != Some("ret")) {
+///     println!("{inst}");
+/// }
+/// ```
+///
+/// # Safety
+/// - Address must be valid ptr
+/// - Address must be valid for reads of every instruction which is disassembled, plus 16 bytes
+pub unsafe fn disassemble_iter(addr: *const u8, runtime_addr: usize) -> DisassembleIter {
+    DisassembleIter {
+        addr,
+        runtime_addr,
+        done: addr.is_null(),
+    }
+}
+
+/// An iterator which disassembles one instruction at a time. See [`disassemble_iter`]
+#[derive(Debug, Clone)]
+pub struct DisassembleIter {
+    /// the address of the next instruction
+    addr: *const u8,
+    /// the runtime address of the next instruction
+    runtime_addr: usize,
+    done: bool,
+}
+
+unsafe impl Send for DisassembleIter {}
+unsafe impl Sync for DisassembleIter {}
+
+impl Iterator for DisassembleIter {
+    type Item = Inst;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let inst = capstone().ok().and_then(|cs| {
+            // SAFETY: the creator of the iterator guaranteed 16 bytes past each instruction are readable
+            let code = unsafe { slice::from_raw_parts(self.addr, 16) };
+
+            let insts = cs.disasm_count(code, self.runtime_addr as u64, 1).ok()?;
+            let inst = insts
+                .as_ref()
+                .iter()
+                .next()
+                .map(|inst| Inst::new(&cs, inst));
+            inst
+        });
+
+        let Some(inst) = inst else {
+            self.done = true;
+            return None;
+        };
+
+        self.addr = self.addr.wrapping_add(inst.size);
+        self.runtime_addr = self.runtime_addr.wrapping_add(inst.size);
+
+        Some(inst)
+    }
 }
 
 /// Disassemble all instructions from target address up to `size`, with
@@ -227,17 +388,13 @@ pub fn disassemble_bytes_count(
 
 /// Disassemble all instructions from bytes, with`runtime_addr`
 pub fn disassemble_bytes_ex(code: &[u8], runtime_addr: usize) -> Result<Vec<Inst>, AsmError> {
-    let cs = Capstone::new()
-        .x86()
-        .mode(arch::x86::ArchMode::Mode64)
-        .syntax(arch::x86::ArchSyntax::Intel)
-        .build()?;
+    let cs = capstone()?;
 
     let insts = cs.disasm_all(code, runtime_addr as u64)?;
 
     let mut buffer = Vec::new();
     for inst in insts.as_ref() {
-        let inst: Inst = inst.into();
+        let inst = Inst::new(&cs, inst);
         buffer.push(inst);
     }
 
@@ -250,17 +407,13 @@ pub fn disassemble_bytes_ex_count(
     runtime_addr: usize,
     instruction_count: usize,
 ) -> Result<Vec<Inst>, AsmError> {
-    let cs = Capstone::new()
-        .x86()
-        .mode(arch::x86::ArchMode::Mode64)
-        .syntax(arch::x86::ArchSyntax::Intel)
-        .build()?;
+    let cs = capstone()?;
 
     let insts = cs.disasm_count(code, runtime_addr as u64, instruction_count)?;
 
     let mut buffer = Vec::new();
     for inst in insts.as_ref() {
-        let inst: Inst = inst.into();
+        let inst = Inst::new(&cs, inst);
         buffer.push(inst);
     }
 
@@ -271,19 +424,28 @@ pub fn disassemble_bytes_ex_count(
 ///
 /// # Safety
 /// - address must be valid address for reads for min_len up to the returned usize (16 bytes max)
-pub unsafe fn code_len(mut addr: *const u8, min_len: usize) -> Result<usize, AsmError> {
+pub unsafe fn code_len(addr: *const u8, min_len: usize) -> Result<usize, AsmError> {
     if addr.is_null() {
         return Err(AsmError::BadAddress);
     }
 
+    let cs = capstone_plain()?;
+
     let mut len = 0;
     while len < min_len {
-        let Ok(inst) = (unsafe { disassemble(addr) }) else {
+        // SAFETY: the caller guarantees address is valid for reads up to the returned length
+        let code = unsafe { slice::from_raw_parts(addr.add(len), 16) };
+
+        let size = cs
+            .disasm_count(code, addr as u64 + len as u64, 1)
+            .ok()
+            .and_then(|insts| insts.as_ref().first().map(Insn::len));
+
+        let Some(size) = size else {
             return Ok(0);
         };
 
-        len += inst.size;
-        addr = unsafe { addr.add(inst.size) };
+        len += size;
     }
 
     Ok(len)
@@ -291,11 +453,12 @@ pub unsafe fn code_len(mut addr: *const u8, min_len: usize) -> Result<usize, Asm
 
 /// Get the closest valid length of bytes in byte slice to `min_len` without overwriting any asm instructions
 pub fn code_bytes_len(bytes: &[u8], min_len: usize) -> Result<usize, AsmError> {
-    let insts = disassemble_bytes(bytes)?;
+    let cs = capstone_plain()?;
+    let insts = cs.disasm_all(bytes, 0)?;
 
     let mut len = 0;
-    for inst in insts {
-        len += inst.size;
+    for inst in insts.as_ref() {
+        len += inst.len();
 
         if len >= min_len {
             break;
@@ -304,3 +467,12 @@ pub fn code_bytes_len(bytes: &[u8], min_len: usize) -> Result<usize, AsmError> {
 
     Ok(len)
 }
+
+/// The byte size of the instructions which decode from the start of `code`, up to the first one
+/// which doesn't
+pub(crate) fn decoded_len(code: &[u8], runtime_addr: usize) -> Result<usize, AsmError> {
+    let cs = capstone_plain()?;
+    let insts = cs.disasm_all(code, runtime_addr as u64)?;
+
+    Ok(insts.as_ref().iter().map(Insn::len).sum())
+}
@@ -0,0 +1,587 @@
+//! Assemble whole blocks of asm with labels, resolving symbols like `user32.MessageBoxA` to addresses
+
+use std::collections::HashMap;
+
+use super::{disassemble_bytes_ex, keystone, relocate::rel32, AsmError, Inst};
+use crate::{
+    modules::{self, Module, ModuleError},
+    symbols,
+};
+
+/// How many times instructions are reassembled, waiting for their sizes to settle
+const MAX_PASSES: usize = 16;
+
+/// An error for [`assemble_block`], with where in the code it happened
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("line {line}, column {column}: {kind}")]
+pub struct AssembleError {
+    /// the line, starting at 1
+    pub line: usize,
+    /// the column, starting at 1
+    pub column: usize,
+    /// what went wrong
+    pub kind: AssembleErrorKind,
+}
+
+/// What went wrong in an [`AssembleError`]
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum AssembleErrorKind {
+    /// the instruction couldn't be assembled
+    #[error("failed to assemble `{0}`: {1}")]
+    Asm(String, AsmError),
+    /// a name with a `.` in it isn't a label, and the resolver doesn't know it
+    #[error("unknown symbol `{0}`")]
+    Symbol(String),
+    /// the same label is defined twice
+    #[error("label `{0}` is defined more than once")]
+    DuplicateLabel(String),
+    /// a call or jump's target is more than 2GB away, so a rel32 displacement can't reach it
+    #[error("branch target `{0}` is out of rel32 range. load it into a register first, e.g. `mov rax, {0}` then `call rax`")]
+    BranchOutOfRange(String),
+    /// the instruction sizes kept changing, so the labels never settled
+    #[error("instruction sizes did not settle")]
+    Unstable,
+}
+
+/// Resolves names used in asm to addresses
+pub trait SymbolResolver {
+    /// The address of `name`, if it's known
+    fn resolve(&self, name: &str) -> Option<usize>;
+}
+
+impl<F: Fn(&str) -> Option<usize>> SymbolResolver for F {
+    fn resolve(&self, name: &str) -> Option<usize> {
+        self(name)
+    }
+}
+
+/// Resolves module names and exports of the loaded modules.
+///
+/// * `game.exe` and `user32.dll` - the module's base address
+/// * `user32.MessageBoxA` or `user32.dll.MessageBoxA` - an exported symbol of the module
+///
+/// Module names aren't case sensitive, but symbol names are.
+#[derive(Debug, Clone)]
+pub struct ModuleResolver {
+    modules: Vec<Module>,
+}
+
+impl ModuleResolver {
+    /// A resolver for the currently loaded modules
+    pub fn new() -> Result<Self, ModuleError> {
+        let modules = modules::enum_modules()?;
+        Ok(Self { modules })
+    }
+
+    fn module(&self, name: &str) -> Option<&Module> {
+        let matches = |module: &&Module| {
+            let stem = module
+                .name
+                .rsplit_once('.')
+                .map_or(&*module.name, |(stem, _)| stem);
+            module.name.eq_ignore_ascii_case(name) || stem.eq_ignore_ascii_case(name)
+        };
+
+        // an exact match wins over a match without the extension
+        self.modules
+            .iter()
+            .find(|module| module.name.eq_ignore_ascii_case(name))
+            .or_else(|| self.modules.iter().find(matches))
+    }
+}
+
+impl SymbolResolver for ModuleResolver {
+    fn resolve(&self, name: &str) -> Option<usize> {
+        if let Some(module) = self
+            .modules
+            .iter()
+            .find(|module| module.name.eq_ignore_ascii_case(name))
+        {
+            return Some(module.base as usize);
+        }
+
+        let (module, symbol) = name.rsplit_once('.')?;
+        let module = self.module(module)?;
+
+        let symbol = symbols::find_symbol_address(module, symbol).ok()??;
+        Some(symbol.address as usize)
+    }
+}
+
+/// A single statement of the block
+#[derive(Debug)]
+struct Statement<'a> {
+    /// the line, starting at 1
+    line: usize,
+    /// the column of the first character of `text`, starting at 1
+    column: usize,
+    text: &'a str,
+}
+
+/// Assemble a block of asm at `runtime_addr`.
+///
+/// Statements are separated by newlines or `;`, and `#` starts a comment. A label is defined with
+/// `name:`, either on its own or before a statement, and can be used before or after it's defined.
+///
+/// Names which aren't labels are looked up with `resolver`, e.g. `call user32.MessageBoxA`.
+/// Calls and jumps are rel32, so they fail if their target is more than 2GB away.
+/// A memory operand with no registers other than `rip` which uses a label or symbol is made rip-relative,
+/// so `mov rax, [game.exe+0x1234]` and `lea rcx, [rip + message]` both address the symbol.
+///
+/// ```rust,ignore
+/// let code = "
+///     mov rcx, 10
+/// again:
+///     call game.exe+0x1234
+///     loop again
+///     ret
+/// ";
+///
+/// let insts = assemble_block(code, 0x140001000, &ModuleResolver::new()?)?;
+/// ```
+pub fn assemble_block(
+    code: &str,
+    runtime_addr: usize,
+    resolver: &impl SymbolResolver,
+) -> Result<Vec<Inst>, AssembleError> {
+    let ks = keystone().map_err(|e| AssembleError {
+        line: 1,
+        column: 1,
+        kind: AssembleErrorKind::Asm(String::new(), e),
+    })?;
+
+    let (statements, label_indexes) = parse(code)?;
+
+    let mut symbols = HashMap::new();
+    let mut lookup = |name: &str, labels: &HashMap<&str, usize>| {
+        if let Some(&addr) = labels.get(name) {
+            return Some(addr);
+        }
+
+        *symbols
+            .entry(name.to_owned())
+            .or_insert_with(|| resolver.resolve(name))
+    };
+
+    // every label starts out at runtime_addr, and moves as the instruction sizes become known
+    let mut sizes = vec![0; statements.len()];
+    let mut bytes = Vec::new();
+
+    for _ in 0..MAX_PASSES {
+        let mut addrs = Vec::with_capacity(statements.len() + 1);
+        let mut addr = runtime_addr;
+        for size in &sizes {
+            addrs.push(addr);
+            addr += size;
+        }
+        addrs.push(addr);
+
+        let labels = label_indexes
+            .iter()
+            .map(|(&label, &index)| (label, addrs[index]))
+            .collect::<HashMap<_, _>>();
+
+        bytes.clear();
+        let mut new_sizes = Vec::with_capacity(statements.len());
+
+        for (i, statement) in statements.iter().enumerate() {
+            let next = addrs[i] + sizes[i];
+            let text = substitute(statement, next, |name| lookup(name, &labels))?;
+
+            let output = ks
+                .asm(text.clone(), addrs[i] as u64)
+                .map_err(|e| AssembleError {
+                    line: statement.line,
+                    column: statement.column,
+                    kind: AssembleErrorKind::Asm(text, e.into()),
+                })?;
+
+            new_sizes.push(output.bytes.len());
+            bytes.extend_from_slice(&output.bytes);
+        }
+
+        if new_sizes == sizes {
+            return disassemble_bytes_ex(&bytes, runtime_addr).map_err(|e| AssembleError {
+                line: 1,
+                column: 1,
+                kind: AssembleErrorKind::Asm(code.to_owned(), e),
+            });
+        }
+
+        sizes = new_sizes;
+    }
+
+    Err(AssembleError {
+        line: 1,
+        column: 1,
+        kind: AssembleErrorKind::Unstable,
+    })
+}
+
+/// Split the code into statements, and find which statement each label points at
+fn parse(code: &str) -> Result<(Vec<Statement>, HashMap<&str, usize>), AssembleError> {
+    let mut statements = Vec::new();
+    let mut labels = HashMap::new();
+
+    for (line_index, line) in code.lines().enumerate() {
+        let line_number = line_index + 1;
+        let line = line.split_once('#').map_or(line, |(code, _)| code);
+
+        let mut offset = 0;
+        for part in line.split(';') {
+            let part_offset = offset;
+            offset += part.len() + 1;
+
+            let mut text = part.trim_start();
+            let mut column = part_offset + (part.len() - text.len()) + 1;
+
+            // any amount of labels can come before the statement
+            while let Some((label, rest)) = text.split_once(':') {
+                let label = label.trim_end();
+                if label.is_empty() || !label.chars().all(is_ident_char) {
+                    break;
+                }
+
+                if labels.insert(label, statements.len()).is_some() {
+                    return Err(AssembleError {
+                        line: line_number,
+                        column,
+                        kind: AssembleErrorKind::DuplicateLabel(label.to_owned()),
+                    });
+                }
+
+                let rest_trimmed = rest.trim_start();
+                column += text.len() - rest_trimmed.len();
+                text = rest_trimmed;
+            }
+
+            let text = text.trim_end();
+            if !text.is_empty() {
+                statements.push(Statement {
+                    line: line_number,
+                    column,
+                    text,
+                });
+            }
+        }
+    }
+
+    Ok((statements, labels))
+}
+
+/// Replace every label and symbol in the statement's operands with its address.
+///
+/// Memory operands with no registers besides `rip` which use a label or symbol are made relative
+/// to `next`, the address of the next instruction. Fails if a relative branch's target is out of
+/// rel32 range from `next`.
+fn substitute(
+    statement: &Statement,
+    next: usize,
+    mut lookup: impl FnMut(&str) -> Option<usize>,
+) -> Result<String, AssembleError> {
+    let text = statement.text;
+
+    // the mnemonic is never a symbol
+    let operands_start = text.find(char::is_whitespace).unwrap_or(text.len());
+    let mut out = String::from(&text[..operands_start]);
+
+    // the memory operand currently being built, and the same with rip as 0.
+    // it's made rip-relative if it uses a symbol and no other registers
+    let mut mem: Option<(String, String)> = None;
+    let mut mem_symbol = false;
+    let mut mem_register = false;
+
+    let mut chars = text[operands_start..].char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let start = operands_start + i;
+
+        let token = if c.is_ascii_digit() {
+            // numbers, including ones like 0x12ab and 12abh
+            let mut end = start + c.len_utf8();
+            while let Some(&(j, c)) = chars.peek() {
+                if !is_ident_char(c) {
+                    break;
+                }
+
+                end = operands_start + j + c.len_utf8();
+                chars.next();
+            }
+
+            text[start..end].to_owned()
+        } else if is_ident_start(c) {
+            let mut end = start + c.len_utf8();
+            while let Some(&(j, c)) = chars.peek() {
+                if !is_ident_char(c) {
+                    break;
+                }
+
+                end = operands_start + j + c.len_utf8();
+                chars.next();
+            }
+
+            let name = &text[start..end];
+
+            match lookup(name) {
+                Some(addr) => {
+                    mem_symbol = true;
+                    format!("{addr:#x}")
+                }
+
+                None if name.eq_ignore_ascii_case("rip") && mem.is_some() => {
+                    if let Some((inner, relative)) = &mut mem {
+                        inner.push_str(name);
+                        relative.push('0');
+                    }
+
+                    continue;
+                }
+
+                None if name.contains('.') && !name.starts_with('.') => {
+                    return Err(AssembleError {
+                        line: statement.line,
+                        column: statement.column + start,
+                        kind: AssembleErrorKind::Symbol(name.to_owned()),
+                    });
+                }
+
+                None => {
+                    mem_register = true;
+                    name.to_owned()
+                }
+            }
+        } else if c == '[' {
+            mem = Some((String::new(), String::new()));
+            mem_symbol = false;
+            mem_register = false;
+            continue;
+        } else if c == ']' {
+            let Some((inner, relative)) = mem.take() else {
+                out.push(c);
+                continue;
+            };
+
+            if mem_symbol && !mem_register {
+                out.push_str(&format!("[rip + ({relative}) - {next:#x}]"));
+            } else {
+                out.push_str(&format!("[{inner}]"));
+            }
+
+            continue;
+        } else {
+            c.to_string()
+        };
+
+        match &mut mem {
+            Some((inner, relative)) => {
+                inner.push_str(&token);
+                relative.push_str(&token);
+            }
+
+            None => out.push_str(&token),
+        }
+    }
+
+    if is_relative_branch(&text[..operands_start]) {
+        let operand = text[operands_start..].trim();
+
+        let out_of_range =
+            evaluate(&out[operands_start..]).is_some_and(|target| rel32(next, target).is_none());
+
+        if out_of_range {
+            return Err(AssembleError {
+                line: statement.line,
+                column: statement.column + text.len() - text[operands_start..].trim_start().len(),
+                kind: AssembleErrorKind::BranchOutOfRange(operand.to_owned()),
+            });
+        }
+    }
+
+    Ok(out)
+}
+
+/// Whether the mnemonic is a call or jump which can only encode a displacement, e.g. `jnz`
+fn is_relative_branch(mnemonic: &str) -> bool {
+    let mnemonic = mnemonic.to_ascii_lowercase();
+    mnemonic == "call" || mnemonic.starts_with('j') || mnemonic.starts_with("loop")
+}
+
+/// The value of an operand made of numbers added and subtracted, e.g. `0x7ff61000+0x20`.
+/// Anything else, like a register or a memory operand, is None
+fn evaluate(operand: &str) -> Option<usize> {
+    let mut total = None::<usize>;
+    let mut negative = false;
+    let mut number = String::new();
+
+    for c in operand.chars().filter(|c| !c.is_whitespace()).chain(['+']) {
+        if c != '+' && c != '-' {
+            number.push(c);
+            continue;
+        }
+
+        if !number.is_empty() {
+            let value = parse_number(&number)?;
+            let sum = total.unwrap_or(0);
+
+            total = Some(if negative {
+                sum.wrapping_sub(value)
+            } else {
+                sum.wrapping_add(value)
+            });
+
+            number.clear();
+        }
+
+        negative = c == '-';
+    }
+
+    total
+}
+
+/// Parse a number the way keystone does, e.g. `0x12ab`, `12abh`, or `123`
+fn parse_number(number: &str) -> Option<usize> {
+    let number = number.to_ascii_lowercase();
+
+    if let Some(hex) = number.strip_prefix("0x") {
+        usize::from_str_radix(hex, 16).ok()
+    } else if let Some(hex) = number.strip_suffix('h') {
+        usize::from_str_radix(hex, 16).ok()
+    } else {
+        number.parse().ok()
+    }
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || matches!(c, '_' | '.' | '$' | '@' | '?')
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '$' | '@' | '?')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NEXT: usize = 0x1007;
+
+    fn lookup(name: &str) -> Option<usize> {
+        match name {
+            "end" => Some(0x1010),
+            "player" => Some(0x2000),
+            "user32.MessageBoxA" => Some(0x7FF8_0000_1000),
+            _ => None,
+        }
+    }
+
+    fn substitute_text(text: &str) -> Result<String, AssembleError> {
+        let statement = Statement {
+            line: 3,
+            column: 5,
+            text,
+        };
+
+        substitute(&statement, NEXT, lookup)
+    }
+
+    #[test]
+    fn parse_statements() {
+        let code = "start:\n    mov rax, 1 # comment\n  a: b: nop; ret\n\n    end:";
+        let (statements, labels) = parse(code).unwrap();
+
+        let statements = statements
+            .iter()
+            .map(|s| (s.line, s.column, s.text))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            statements,
+            [(2, 5, "mov rax, 1"), (3, 9, "nop"), (3, 14, "ret")]
+        );
+
+        assert_eq!(
+            labels,
+            HashMap::from([("start", 0), ("a", 1), ("b", 1), ("end", 3)])
+        );
+    }
+
+    #[test]
+    fn parse_duplicate_label() {
+        let e = parse("x: nop\n  x: ret").unwrap_err();
+
+        assert_eq!((e.line, e.column), (2, 3));
+        assert!(matches!(e.kind, AssembleErrorKind::DuplicateLabel(label) if label == "x"));
+    }
+
+    #[test]
+    fn substitute_names() {
+        let check = |text: &str, expected: &str| {
+            assert_eq!(substitute_text(text).unwrap(), expected, "{text}");
+        };
+
+        check("jmp end", "jmp 0x1010");
+        check("mov rax, end+8", "mov rax, 0x1010+8");
+
+        // only memory operands without registers become rip-relative
+        check("mov rax, [player]", "mov rax, [rip + (0x2000) - 0x1007]");
+        check(
+            "lea rcx, [rip + player]",
+            "lea rcx, [rip + (0 + 0x2000) - 0x1007]",
+        );
+        check("mov rax, [rcx + player]", "mov rax, [rcx + 0x2000]");
+        check("mov rax, [rcx + 8]", "mov rax, [rcx + 8]");
+        check("mov eax, 0x10", "mov eax, 0x10");
+    }
+
+    #[test]
+    fn substitute_unknown_symbol() {
+        let e = substitute_text("call user32.Nope").unwrap_err();
+
+        assert_eq!((e.line, e.column), (3, 10));
+        assert!(matches!(e.kind, AssembleErrorKind::Symbol(name) if name == "user32.Nope"));
+
+        // names without a `.` are left to keystone, e.g. registers
+        assert_eq!(substitute_text("call rax").unwrap(), "call rax");
+    }
+
+    #[test]
+    fn substitute_branch_out_of_range() {
+        for text in [
+            "call user32.MessageBoxA",
+            "jmp user32.MessageBoxA",
+            "jnz user32.MessageBoxA+0x10",
+            "call 0x7ff800001000",
+        ] {
+            let e = substitute_text(text).unwrap_err();
+
+            assert_eq!((e.line, e.column), (3, 5 + text.find(' ').unwrap() + 1));
+            assert!(
+                matches!(e.kind, AssembleErrorKind::BranchOutOfRange(_)),
+                "{text}"
+            );
+        }
+
+        // loading the address first works
+        assert_eq!(
+            substitute_text("mov rax, user32.MessageBoxA").unwrap(),
+            "mov rax, 0x7ff800001000"
+        );
+
+        assert_eq!(
+            substitute_text("call [user32.MessageBoxA]").unwrap(),
+            "call [rip + (0x7ff800001000) - 0x1007]"
+        );
+    }
+
+    #[test]
+    fn evaluate_operands() {
+        assert_eq!(evaluate("0x10"), Some(0x10));
+        assert_eq!(evaluate("0x1000 + 0x20 - 8"), Some(0x1018));
+        assert_eq!(evaluate("1000h+10"), Some(0x100A));
+        assert_eq!(evaluate("-0x10"), Some(0usize.wrapping_sub(0x10)));
+
+        assert_eq!(evaluate(""), None);
+        assert_eq!(evaluate("rax"), None);
+        assert_eq!(evaluate("rax + 8"), None);
+        assert_eq!(evaluate("[0x10]"), None);
+    }
+}
@@ -0,0 +1,354 @@
+//! Find unused padding in a module's code, which small patches and near trampolines can be written to
+
+use std::{collections::HashMap, ops::ControlFlow, slice};
+
+use capstone::arch::{x86::X86OperandType, ArchOperand};
+use capstone::prelude::*;
+use capstone::{Insn, InsnGroupId, InsnGroupType, RegIdInt};
+use pelite::image::IMAGE_SCN_MEM_EXECUTE;
+use pelite::pe64::{Pe, PeView};
+
+use super::{capstone, AsmError};
+use crate::{modules::Module, scan::sweep};
+
+/// The bytes compilers and linkers pad code with: int3, nop, and zeros
+const PADDING: [u8; 3] = [0xCC, 0x90, 0x00];
+const INT3: u8 = 0xCC;
+
+/// How far a rel32 jump or call can reach
+const REL32_RANGE: u64 = i32::MAX as u64;
+
+/// A run of padding in a module's code which nothing jumps to or falls through to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cave {
+    /// the start of the cave
+    pub base: *mut u8,
+    /// the size of the cave
+    pub size: usize,
+    /// the name of the section the cave is in
+    pub section: String,
+}
+
+unsafe impl Send for Cave {}
+unsafe impl Sync for Cave {}
+
+/// Find every code cave of at least `min_size` bytes in the module's executable sections.
+///
+/// A cave is a run of `CC`, `90`, or `00` bytes, including the zeroed slack between the end of a
+/// section and its alignment. Parts of a run which code could reach, by falling through into it or by
+/// a jump, call, or rip-relative reference into it, are left out. Execution which reaches a run stops
+/// at the first `int3`, so everything after that is still free.
+///
+/// Caves are in executable memory, so their protection needs to be changed before writing to them.
+pub fn find_caves(module: &Module, min_size: usize) -> Result<Vec<Cave>, AsmError> {
+    let min_size = min_size.max(1);
+
+    // this base address is crate private, so it is guaranteed
+    let base = module.handle.base;
+
+    // SAFETY: module field is crate private, it cannot be changed
+    //         and we only support 64-bit. Additionally, each module is backed by
+    //         an increased refcount, which keeps them valid for the duration of Module
+    let view = unsafe { PeView::module(base.cast()) };
+
+    let alignment = view.optional_header().SectionAlignment.max(1) as usize;
+    let image_end = base as usize + view.optional_header().SizeOfImage as usize;
+
+    let cs = capstone()?;
+    let rip = RegId(arch::x86::X86Reg::X86_REG_RIP as RegIdInt);
+
+    let sections = view
+        .section_headers()
+        .iter()
+        .filter(|section| section.Characteristics & IMAGE_SCN_MEM_EXECUTE != 0)
+        .map(|section| {
+            let start = base as usize + section.VirtualAddress as usize;
+            // the rest of the section's last page is mapped too, and filled with zeros
+            let size = (section.VirtualSize as usize).next_multiple_of(alignment);
+            let end = (start + size).min(image_end);
+
+            (section.name().unwrap_or_default().to_owned(), start, end)
+        })
+        .collect::<Vec<_>>();
+
+    // runs of padding, and every address in code which is reachable from elsewhere
+    let mut runs = Vec::new();
+    let mut entries = Vec::new();
+
+    for (name, start, end) in &sections {
+        let mut cursor = *start;
+        let mut run: Option<Run> = None;
+        // whether the last instruction before the current run can continue into it
+        let mut falls_through = false;
+
+        let visit = |insn: Option<&Insn>| {
+            let (addr, size) = match insn {
+                Some(insn) => (insn.address() as usize, insn.bytes().len()),
+                // an invalid instruction's first byte was skipped
+                None => (cursor, 1),
+            };
+
+            cursor = addr + size;
+
+            // SAFETY: the sweep only visits bytes inside of the section
+            let bytes = unsafe { slice::from_raw_parts(addr as *const u8, size) };
+
+            if bytes.iter().all(|byte| PADDING.contains(byte)) {
+                let run = run.get_or_insert(Run {
+                    start: addr,
+                    end: addr,
+                    falls_through,
+                });
+
+                run.end = addr + size;
+                return Ok(ControlFlow::Continue(()));
+            }
+
+            runs.extend(run.take().map(|run| (name.as_str(), run)));
+
+            falls_through = match insn {
+                Some(insn) => {
+                    let flow = flow(&cs, insn, rip)?;
+                    entries.extend(flow.target);
+                    !flow.stops
+                }
+
+                // nothing can run it, so nothing falls through it
+                None => false,
+            };
+
+            Ok(ControlFlow::Continue(()))
+        };
+
+        // SAFETY: code sections are mapped for as long as the module is loaded
+        unsafe { sweep::sweep(&cs, *start, end - start, visit)? };
+
+        runs.extend(run.map(|run| (name.as_str(), run)));
+    }
+
+    entries.sort_unstable();
+
+    let mut caves = Vec::new();
+
+    for (section, run) in runs {
+        let first = entries.partition_point(|&entry| entry < run.start);
+        let run_entries = entries[first..]
+            .iter()
+            .copied()
+            .take_while(|&entry| entry < run.end);
+
+        // falling through reaches the run at its start, which comes before every other entry
+        let reachable = run.falls_through.then_some(run.start);
+        let entries = reachable.into_iter().chain(run_entries).collect::<Vec<_>>();
+
+        for range in run.free(&entries) {
+            if range.1 - range.0 >= min_size {
+                caves.push(Cave {
+                    base: range.0 as _,
+                    size: range.1 - range.0,
+                    section: section.to_owned(),
+                });
+            }
+        }
+    }
+
+    Ok(caves)
+}
+
+/// A run of padding bytes
+#[derive(Debug)]
+struct Run {
+    start: usize,
+    end: usize,
+    /// whether the code right before the run can continue into it
+    falls_through: bool,
+}
+
+impl Run {
+    /// The parts of the run which can't be reached from any of the sorted `entries`
+    fn free(&self, entries: &[usize]) -> Vec<(usize, usize)> {
+        let mut free = Vec::new();
+        let mut start = self.start;
+
+        for &entry in entries {
+            if entry < start {
+                continue;
+            }
+
+            if entry > start {
+                free.push((start, entry));
+            }
+
+            // execution continues through the padding until it hits an int3
+            // SAFETY: entry..self.end is inside of the run, which is readable
+            let bytes = unsafe { slice::from_raw_parts(entry as *const u8, self.end - entry) };
+
+            start = match bytes.iter().position(|&byte| byte == INT3) {
+                Some(int3) => entry + int3 + 1,
+                None => self.end,
+            };
+        }
+
+        if start < self.end {
+            free.push((start, self.end));
+        }
+
+        free
+    }
+}
+
+/// How an instruction affects the code around it
+struct Flow {
+    /// whether execution never continues to the next instruction
+    stops: bool,
+    /// the address the instruction jumps to or references, if any
+    target: Option<usize>,
+}
+
+fn flow(cs: &Capstone, insn: &Insn, rip: RegId) -> Result<Flow, AsmError> {
+    let detail = cs.insn_detail(insn)?;
+
+    let in_group = |ty: InsnGroupType::Type| detail.groups().contains(&InsnGroupId(ty as u8));
+
+    let mnemonic = insn.mnemonic().unwrap_or_default();
+    let stops = in_group(InsnGroupType::CS_GRP_RET)
+        || in_group(InsnGroupType::CS_GRP_IRET)
+        || mnemonic == "jmp"
+        || matches!(mnemonic, "ud2" | "hlt");
+
+    let next = insn.address() + insn.bytes().len() as u64;
+    let relative = in_group(InsnGroupType::CS_GRP_BRANCH_RELATIVE);
+
+    let target = detail
+        .arch_detail()
+        .operands()
+        .into_iter()
+        .find_map(|op| match op {
+            // capstone reports relative branches as the absolute target
+            ArchOperand::X86Operand(op) => match op.op_type {
+                X86OperandType::Imm(imm) if relative => Some(imm as usize),
+                X86OperandType::Mem(mem) if mem.base() == rip => {
+                    Some(next.wrapping_add(mem.disp() as u64) as usize)
+                }
+                _ => None,
+            },
+
+            _ => None,
+        });
+
+    Ok(Flow { stops, target })
+}
+
+/// Hands out pieces of [`Cave`]s, and takes them back when they're freed.
+///
+/// The allocator only keeps track of which bytes are in use. It doesn't change their protection,
+/// and doesn't restore their original bytes when they're freed.
+#[derive(Debug, Clone, Default)]
+pub struct CaveAllocator {
+    /// free ranges, sorted by address and never touching each other
+    free: Vec<(usize, usize)>,
+    /// the size of each allocation by its address
+    allocated: HashMap<usize, usize>,
+}
+
+unsafe impl Send for CaveAllocator {}
+unsafe impl Sync for CaveAllocator {}
+
+impl CaveAllocator {
+    /// An allocator which hands out pieces of `caves`
+    pub fn new(caves: impl IntoIterator<Item = Cave>) -> Self {
+        let mut this = Self::default();
+        for cave in caves {
+            this.insert(cave.base as usize, cave.base as usize + cave.size);
+        }
+
+        this
+    }
+
+    /// Allocate `size` bytes aligned to `align`, from the first cave they fit in
+    pub fn alloc(&mut self, size: usize, align: usize) -> Option<*mut u8> {
+        self.alloc_where(size, align, |_, _| true)
+    }
+
+    /// Allocate `size` bytes aligned to `align`, where every byte can be reached from `near`
+    /// with a rel32 jump or call, and the other way around
+    pub fn alloc_near(&mut self, near: *const u8, size: usize, align: usize) -> Option<*mut u8> {
+        let near = near as u64;
+        self.alloc_where(size, align, |start, end| {
+            near.abs_diff(start as u64) <= REL32_RANGE && near.abs_diff(end as u64) <= REL32_RANGE
+        })
+    }
+
+    /// Give back an allocation made by this allocator. Returns whether `addr` was allocated
+    pub fn free(&mut self, addr: *mut u8) -> bool {
+        let Some(size) = self.allocated.remove(&(addr as usize)) else {
+            return false;
+        };
+
+        self.insert(addr as usize, addr as usize + size);
+        true
+    }
+
+    /// The total amount of free bytes
+    pub fn available(&self) -> usize {
+        self.free.iter().map(|(start, end)| end - start).sum()
+    }
+
+    fn alloc_where(
+        &mut self,
+        size: usize,
+        align: usize,
+        allowed: impl Fn(usize, usize) -> bool,
+    ) -> Option<*mut u8> {
+        let size = size.max(1);
+        let align = align.max(1);
+
+        let (index, start) =
+            self.free
+                .iter()
+                .enumerate()
+                .find_map(|(index, &(free_start, free_end))| {
+                    let start = free_start.checked_next_multiple_of(align)?;
+                    let end = start.checked_add(size)?;
+                    (end <= free_end && allowed(start, end)).then_some((index, start))
+                })?;
+
+        let (free_start, free_end) = self.free.remove(index);
+        let end = start + size;
+
+        // whatever is left on either side stays free
+        let leftovers = [(free_start, start), (end, free_end)];
+        for (offset, (left_start, left_end)) in leftovers
+            .into_iter()
+            .filter(|(start, end)| start < end)
+            .enumerate()
+        {
+            self.free.insert(index + offset, (left_start, left_end));
+        }
+
+        self.allocated.insert(start, size);
+        Some(start as _)
+    }
+
+    /// Add a free range, merging it with the ranges it touches
+    fn insert(&mut self, mut start: usize, mut end: usize) {
+        if start >= end {
+            return;
+        }
+
+        let index = self.free.partition_point(|&(_, free_end)| free_end < start);
+
+        // every range from index on which overlaps or touches is merged into this one
+        let merged = self.free[index..]
+            .iter()
+            .take_while(|&&(free_start, _)| free_start <= end)
+            .count();
+
+        for &(free_start, free_end) in &self.free[index..index + merged] {
+            start = start.min(free_start);
+            end = end.max(free_end);
+        }
+
+        self.free.splice(index..index + merged, [(start, end)]);
+    }
+}
@@ -0,0 +1,455 @@
+//! Find a function's basic blocks and the control flow between them
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    slice,
+};
+
+use capstone::Capstone;
+use pelite::pe64::{Pe, PeView};
+
+use super::{capstone, AsmError, Inst};
+use crate::modules;
+
+/// Analysis stops after this many instructions, in case it ran off into data
+const MAX_INSTS: usize = 100_000;
+
+/// A run of instructions which is only entered at the start, and only left at the end
+#[derive(Debug, Clone)]
+pub struct BasicBlock {
+    /// the address of the first instruction
+    pub start: *const u8,
+    /// the address right after the last instruction
+    pub end: *const u8,
+    /// the instructions, in order
+    pub insts: Vec<Inst>,
+}
+
+unsafe impl Send for BasicBlock {}
+unsafe impl Sync for BasicBlock {}
+
+/// How control flows from one [`BasicBlock`] to another
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EdgeKind {
+    /// an unconditional jump
+    Jump,
+    /// a conditional branch which was taken
+    Taken,
+    /// a conditional branch which wasn't taken, continuing to the next instruction
+    NotTaken,
+    /// the block ends because another block starts right after it
+    Fallthrough,
+}
+
+/// Control flow from one [`BasicBlock`] to another
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Edge {
+    /// the start of the block control flows from
+    pub from: *const u8,
+    /// the start of the block control flows to
+    pub to: *const u8,
+    /// how control flows
+    pub kind: EdgeKind,
+}
+
+unsafe impl Send for Edge {}
+unsafe impl Sync for Edge {}
+
+/// The control flow graph of a function, see [`analyze_function`]
+#[derive(Debug, Clone)]
+pub struct Function {
+    /// the function's entry
+    pub start: *const u8,
+    /// the address right after the function's last instruction
+    pub end: *const u8,
+    /// the basic blocks, in ascending address order. the first block is the entry
+    pub blocks: Vec<BasicBlock>,
+    /// the control flow between the blocks
+    pub edges: Vec<Edge>,
+    /// the targets of jumps which leave the function
+    pub tail_calls: Vec<*const u8>,
+    /// the function's bounds from the module's exception directory, if it has an entry for it
+    pub unwind_bounds: Option<(*const u8, *const u8)>,
+}
+
+unsafe impl Send for Function {}
+unsafe impl Sync for Function {}
+
+/// Where control goes after an instruction
+enum Flow {
+    /// to the next instruction
+    Next,
+    /// nowhere in this function, e.g. ret, or an indirect jump
+    Stop,
+    /// to the target
+    Jump(usize),
+    /// to the target or the next instruction
+    Branch(usize),
+}
+
+impl Flow {
+    fn of(inst: &Inst) -> Self {
+        let mnemonic = inst.mnemonic.as_deref().unwrap_or_default();
+
+        if inst.is_ret() || inst.in_group("iret") || matches!(mnemonic, "int3" | "ud2" | "hlt") {
+            return Self::Stop;
+        }
+
+        if !inst.is_jump() {
+            return Self::Next;
+        }
+
+        // indirect jumps are tail calls or jump tables, neither of which can be followed
+        let target = match inst.target() {
+            Some(target) if inst.is_relative_branch() => target as usize,
+            _ => return Self::Stop,
+        };
+
+        if mnemonic == "jmp" {
+            Self::Jump(target)
+        } else {
+            Self::Branch(target)
+        }
+    }
+}
+
+/// Build the control flow graph of the function starting at `addr` by recursive descent.
+///
+/// Every conditional and unconditional branch is followed, and each path ends at a ret,
+/// an indirect jump, or a jump which leaves the function. Calls are assumed to return.
+///
+/// If the module has an exception directory entry for the function, its bounds are used to tell
+/// which jumps leave the function. Otherwise, jumps to before `addr`, to the start of another
+/// function in the exception directory, or past the next one are treated as leaving it. So is an
+/// unconditional jump past all of the function's code and conditional branch targets, which is
+/// how a tail call to a later function looks.
+///
+/// # Safety
+/// - the code reachable from `addr` must be valid for reads, plus 16 bytes past each instruction
+pub unsafe fn analyze_function(addr: *const u8) -> Result<Function, AsmError> {
+    if addr.is_null() {
+        return Err(AsmError::BadAddress);
+    }
+
+    let cs = capstone()?;
+
+    let start = addr as usize;
+    let functions = function_table(start).unwrap_or_default();
+    // only an entry for this exact function confirms its bounds
+    let unwind_bounds = functions
+        .binary_search_by_key(&start, |&(begin, _)| begin)
+        .ok()
+        .map(|index| functions[index]);
+
+    // without bounds, the function at least ends where the next one starts
+    let next_function = functions
+        .get(functions.partition_point(|&(begin, _)| begin <= start))
+        .map_or(usize::MAX, |&(begin, _)| begin);
+
+    let in_function = |target: usize| match unwind_bounds {
+        Some((begin, end)) => (begin..end).contains(&target),
+        None => {
+            (start..next_function).contains(&target)
+                && (target == start
+                    || functions
+                        .binary_search_by_key(&target, |&(begin, _)| begin)
+                        .is_err())
+        }
+    };
+
+    // discover every reachable instruction, and where blocks must start
+    let mut insts = BTreeMap::new();
+    let mut leaders = BTreeSet::from([start]);
+    let mut tail_calls = Vec::new();
+    let mut work = vec![start];
+
+    // without bounds, unconditional jumps are only followed once they're known to stay inside of
+    // the code found so far, which ends at `extent`
+    let mut jumps = Vec::new();
+    let mut extent = start;
+
+    loop {
+        while let Some(mut cursor) = work.pop() {
+            while !insts.contains_key(&cursor) && insts.len() < MAX_INSTS && in_function(cursor) {
+                // SAFETY: the caller guarantees the reachable code is readable
+                let Some(inst) = (unsafe { decode(&cs, cursor) }) else {
+                    break;
+                };
+
+                let next = cursor + inst.size;
+                let flow = Flow::of(&inst);
+                insts.insert(cursor, inst);
+                extent = extent.max(next);
+
+                let target = match flow {
+                    Flow::Next => {
+                        cursor = next;
+                        continue;
+                    }
+
+                    Flow::Stop => break,
+                    Flow::Jump(target) if unwind_bounds.is_none() && in_function(target) => {
+                        jumps.push(target);
+                        break;
+                    }
+
+                    Flow::Jump(target) => target,
+                    Flow::Branch(target) => {
+                        leaders.insert(next);
+                        work.push(next);
+                        target
+                    }
+                };
+
+                if in_function(target) {
+                    leaders.insert(target);
+                    work.push(target);
+                    extent = extent.max(target + 1);
+                } else {
+                    tail_calls.push(target as *const u8);
+                }
+
+                break;
+            }
+        }
+
+        // following a jump can find more code, which can bring more jumps inside of the extent
+        let (inside, outside) = jumps
+            .into_iter()
+            .partition::<Vec<_>, _>(|&target| target < extent);
+
+        jumps = outside;
+
+        if inside.is_empty() {
+            break;
+        }
+
+        leaders.extend(&inside);
+        work.extend(inside);
+    }
+
+    tail_calls.extend(jumps.into_iter().map(|target| target as *const u8));
+
+    if insts.is_empty() {
+        return Err(AsmError::NoInstructions);
+    }
+
+    // split the instructions into blocks at every leader and after every branch
+    let mut blocks = Vec::new();
+    let mut edges = Vec::new();
+    let mut current: Option<BasicBlock> = None;
+
+    for (&addr, inst) in &insts {
+        let contiguous = current
+            .as_ref()
+            .is_some_and(|block| block.end as usize == addr);
+
+        if !contiguous || leaders.contains(&addr) {
+            if let Some(block) = current.take() {
+                if contiguous {
+                    edges.push(Edge {
+                        from: block.start,
+                        to: addr as _,
+                        kind: EdgeKind::Fallthrough,
+                    });
+                }
+
+                blocks.push(block);
+            }
+
+            current = Some(BasicBlock {
+                start: addr as _,
+                end: addr as _,
+                insts: Vec::new(),
+            });
+        }
+
+        let Some(block) = current.as_mut() else {
+            unreachable!("a block was just started");
+        };
+
+        block.insts.push(inst.clone());
+        block.end = (addr + inst.size) as _;
+
+        let (target, kind) = match Flow::of(inst) {
+            Flow::Next => continue,
+            Flow::Stop => (None, EdgeKind::Jump),
+            Flow::Jump(target) => (Some(target), EdgeKind::Jump),
+            Flow::Branch(target) => {
+                edges.push(Edge {
+                    from: block.start,
+                    to: block.end,
+                    kind: EdgeKind::NotTaken,
+                });
+
+                (Some(target), EdgeKind::Taken)
+            }
+        };
+
+        if let Some(target) = target.filter(|target| leaders.contains(target)) {
+            edges.push(Edge {
+                from: block.start,
+                to: target as _,
+                kind,
+            });
+        }
+
+        blocks.extend(current.take());
+    }
+
+    blocks.extend(current);
+
+    let end = match unwind_bounds {
+        Some((_, end)) => end,
+        None => blocks
+            .iter()
+            .map(|block| block.end as usize)
+            .max()
+            .unwrap_or(start),
+    };
+
+    tail_calls.sort_unstable();
+    tail_calls.dedup();
+
+    Ok(Function {
+        start: addr,
+        end: end as _,
+        blocks,
+        edges,
+        tail_calls,
+        unwind_bounds: unwind_bounds.map(|(begin, end)| (begin as _, end as _)),
+    })
+}
+
+/// Disassemble the instruction at `addr`
+///
+/// # Safety
+/// `addr` must be valid for 16 bytes of reads
+unsafe fn decode(cs: &Capstone, addr: usize) -> Option<Inst> {
+    // SAFETY: the caller guarantees addr is valid for 16 bytes of reads
+    let code = unsafe { slice::from_raw_parts(addr as *const u8, 16) };
+
+    let insts = cs.disasm_count(code, addr as u64, 1).ok()?;
+    let inst = insts.as_ref().first().map(|insn| Inst::new(cs, insn));
+    inst
+}
+
+/// The bounds of every function in the exception directory of the module containing `addr`,
+/// sorted by address
+fn function_table(addr: usize) -> Option<Vec<(usize, usize)>> {
+    let modules = modules::enum_modules().ok()?;
+    let module = modules
+        .iter()
+        .find(|module| (module.base as usize..module.end as usize).contains(&addr))?;
+
+    // this base address is crate private, so it is guaranteed
+    let base = module.handle.base;
+
+    // SAFETY: module field is crate private, it cannot be changed
+    //         and we only support 64-bit. Additionally, each module is backed by
+    //         an increased refcount, which keeps them valid for the duration of Module
+    let view = unsafe { PeView::module(base.cast()) };
+
+    // the table is sorted by address. pelite's lookup_function_entry compares the wrong way around,
+    // so it isn't used
+    let functions = view
+        .exception()
+        .ok()?
+        .image()
+        .iter()
+        .map(|function| {
+            (
+                base as usize + function.BeginAddress as usize,
+                base as usize + function.EndAddress as usize,
+            )
+        })
+        .collect();
+
+    Some(functions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Analyze `code` as a function which isn't in any module, returning it along with the base
+    /// address it was analyzed at
+    fn analyze(code: &[u8]) -> (Function, usize) {
+        // the decoder reads 16 bytes past each instruction
+        let mut buf = code.to_vec();
+        buf.resize(code.len() + 16, 0xCC);
+
+        // SAFETY: the buffer holds the code and 16 bytes of padding
+        let function = unsafe { analyze_function(buf.as_ptr()) }.unwrap();
+        (function, buf.as_ptr() as usize)
+    }
+
+    fn blocks(function: &Function, base: usize) -> Vec<(usize, usize)> {
+        function
+            .blocks
+            .iter()
+            .map(|block| (block.start as usize - base, block.end as usize - base))
+            .collect()
+    }
+
+    #[test]
+    fn jump_to_join() {
+        let code = [
+            0x85, 0xC9, // test ecx, ecx
+            0x74, 0x04, // jz 8
+            0x31, 0xC0, // xor eax, eax
+            0xEB, 0x02, // jmp 0xa
+            0xFF, 0xC0, // inc eax
+            0xC3, // ret
+        ];
+
+        let (function, base) = analyze(&code);
+
+        assert_eq!(
+            blocks(&function, base),
+            [(0, 4), (4, 8), (8, 0xA), (0xA, 0xB)]
+        );
+        assert!(function.tail_calls.is_empty());
+        assert_eq!(function.end as usize - base, 0xB);
+        assert!(function
+            .edges
+            .iter()
+            .any(|edge| edge.kind == EdgeKind::Jump && edge.to as usize - base == 0xA));
+    }
+
+    #[test]
+    fn tail_jump_to_next_function() {
+        let mut code = vec![
+            0x85, 0xC9, // test ecx, ecx
+            0x74, 0x03, // jz 7
+            0x31, 0xC0, // xor eax, eax
+            0xC3, // ret
+            0xEB, 0x17, // jmp 0x20
+        ];
+
+        // padding, then the next function
+        code.resize(0x20, 0xCC);
+        code.extend_from_slice(&[0x31, 0xC0, 0xC3]);
+
+        let (function, base) = analyze(&code);
+
+        assert_eq!(blocks(&function, base), [(0, 4), (4, 7), (7, 9)]);
+        assert_eq!(function.tail_calls, [(base + 0x20) as *const u8]);
+        assert_eq!(function.end as usize - base, 9);
+    }
+
+    #[test]
+    fn loop_back() {
+        let code = [
+            0xFF, 0xC9, // dec ecx
+            0x74, 0x02, // jz 6
+            0xEB, 0xFA, // jmp 0
+            0xC3, // ret
+        ];
+
+        let (function, base) = analyze(&code);
+
+        assert_eq!(blocks(&function, base), [(0, 4), (4, 6), (6, 7)]);
+        assert!(function.tail_calls.is_empty());
+    }
+}
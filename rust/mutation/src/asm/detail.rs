@@ -0,0 +1,157 @@
+//! The operands, registers, and groups of a disassembled instruction
+
+use capstone::arch::{
+    x86::{X86OpMem, X86OperandType},
+    ArchOperand,
+};
+use capstone::prelude::*;
+use capstone::{Insn, RegIdInt};
+
+/// An operand of an [`Inst`](super::Inst)
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    /// a register, e.g. `rax`
+    Reg(String),
+    /// an immediate. relative branches have the absolute target address here
+    Imm(i64),
+    /// a memory operand, e.g. `qword ptr [rax + rcx*8 + 0x10]`
+    Mem(MemOperand),
+}
+
+/// A memory operand, e.g. `qword ptr [rax + rcx*8 + 0x10]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemOperand {
+    /// the segment register, e.g. `gs`
+    pub segment: Option<String>,
+    /// the base register
+    pub base: Option<String>,
+    /// the index register
+    pub index: Option<String>,
+    /// what the index is multiplied by
+    pub scale: i32,
+    /// the displacement
+    pub disp: i64,
+    /// how many bytes are accessed
+    pub size: u8,
+    /// the address a rip-relative operand points at
+    pub target: Option<*const u8>,
+}
+
+unsafe impl Send for MemOperand {}
+unsafe impl Sync for MemOperand {}
+
+/// The details of an instruction
+#[derive(Debug, Default)]
+pub(super) struct Detail {
+    pub(super) operands: Vec<Operand>,
+    pub(super) regs_read: Vec<String>,
+    pub(super) regs_written: Vec<String>,
+    pub(super) groups: Vec<String>,
+}
+
+impl Detail {
+    /// The instruction's details, or none if capstone was built without them
+    pub(super) fn new(cs: &Capstone, insn: &Insn) -> Self {
+        let Ok(detail) = cs.insn_detail(insn) else {
+            return Self::default();
+        };
+
+        let name = |reg: RegId| if reg.0 == 0 { None } else { cs.reg_name(reg) };
+        let rip = RegId(arch::x86::X86Reg::X86_REG_RIP as RegIdInt);
+        let next = insn.address() + insn.bytes().len() as u64;
+
+        let mut this = Self::default();
+
+        // implicit registers come first, e.g. rsp for push
+        this.regs_read
+            .extend(detail.regs_read().iter().filter_map(|&reg| name(reg)));
+        this.regs_written
+            .extend(detail.regs_write().iter().filter_map(|&reg| name(reg)));
+
+        for op in detail.arch_detail().operands() {
+            let ArchOperand::X86Operand(op) = op else {
+                continue;
+            };
+
+            let operand = match op.op_type {
+                X86OperandType::Reg(reg) => {
+                    let Some(reg) = name(reg) else {
+                        continue;
+                    };
+
+                    // without access info, an explicit register is assumed to be read
+                    let access = op.access;
+                    if access.map_or(true, |access| access.is_readable()) {
+                        this.regs_read.push(reg.clone());
+                    }
+
+                    if access.is_some_and(|access| access.is_writable()) {
+                        this.regs_written.push(reg.clone());
+                    }
+
+                    Operand::Reg(reg)
+                }
+
+                X86OperandType::Imm(imm) => Operand::Imm(imm),
+
+                X86OperandType::Mem(mem) => {
+                    let mem = mem_operand(mem, op.size, name, rip, next);
+
+                    // registers used to calculate the address are always read
+                    this.regs_read
+                        .extend(mem.base.iter().chain(&mem.index).cloned());
+
+                    Operand::Mem(mem)
+                }
+
+                _ => continue,
+            };
+
+            this.operands.push(operand);
+        }
+
+        dedup(&mut this.regs_read);
+        dedup(&mut this.regs_written);
+
+        this.groups = detail
+            .groups()
+            .iter()
+            .filter_map(|&group| cs.group_name(group))
+            .collect();
+
+        this
+    }
+}
+
+fn mem_operand(
+    mem: X86OpMem,
+    size: u8,
+    name: impl Fn(RegId) -> Option<String>,
+    rip: RegId,
+    next: u64,
+) -> MemOperand {
+    let target = (mem.base() == rip).then(|| next.wrapping_add(mem.disp() as u64) as *const u8);
+
+    MemOperand {
+        segment: name(mem.segment()),
+        base: name(mem.base()),
+        index: name(mem.index()),
+        scale: mem.scale(),
+        disp: mem.disp(),
+        size,
+        target,
+    }
+}
+
+/// Remove repeated registers, keeping the first of each
+fn dedup(regs: &mut Vec<String>) {
+    let mut seen = Vec::new();
+    regs.retain(|reg| {
+        if seen.contains(reg) {
+            return false;
+        }
+
+        seen.push(reg.clone());
+        true
+    });
+}
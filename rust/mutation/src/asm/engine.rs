@@ -0,0 +1,54 @@
+//! Capstone and Keystone instances, which are expensive to create, so they're cached per thread
+
+use std::{cell::OnceCell, rc::Rc};
+
+use capstone::prelude::*;
+use keystone_engine::{Arch, Keystone, Mode};
+
+use super::AsmError;
+
+thread_local! {
+    static CAPSTONE: OnceCell<Rc<Capstone>> = const { OnceCell::new() };
+    static CAPSTONE_PLAIN: OnceCell<Rc<Capstone>> = const { OnceCell::new() };
+    static KEYSTONE: OnceCell<Rc<Keystone>> = const { OnceCell::new() };
+}
+
+/// This thread's x64 Intel syntax capstone instance, with instruction details
+pub(crate) fn capstone() -> Result<Rc<Capstone>, AsmError> {
+    CAPSTONE.with(|cell| get_or_try_init(cell, || build_capstone(true)))
+}
+
+/// This thread's x64 Intel syntax capstone instance, without instruction details. Decoding is
+/// much faster without them, so use this when only the instructions' sizes and text are needed
+pub(crate) fn capstone_plain() -> Result<Rc<Capstone>, AsmError> {
+    CAPSTONE_PLAIN.with(|cell| get_or_try_init(cell, || build_capstone(false)))
+}
+
+/// This thread's x64 keystone instance
+pub(crate) fn keystone() -> Result<Rc<Keystone>, AsmError> {
+    KEYSTONE.with(|cell| get_or_try_init(cell, || Ok(Keystone::new(Arch::X86, Mode::MODE_64)?)))
+}
+
+fn build_capstone(detail: bool) -> Result<Capstone, AsmError> {
+    let cs = Capstone::new()
+        .x86()
+        .mode(arch::x86::ArchMode::Mode64)
+        .syntax(arch::x86::ArchSyntax::Intel)
+        .detail(detail)
+        .build()?;
+
+    Ok(cs)
+}
+
+/// Get the cell's value, or create it. If creating it fails, the next call tries again
+fn get_or_try_init<T>(
+    cell: &OnceCell<Rc<T>>,
+    init: impl FnOnce() -> Result<T, AsmError>,
+) -> Result<Rc<T>, AsmError> {
+    if let Some(value) = cell.get() {
+        return Ok(value.clone());
+    }
+
+    let value = Rc::new(init()?);
+    Ok(cell.get_or_init(|| value).clone())
+}
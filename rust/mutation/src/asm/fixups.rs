@@ -0,0 +1,235 @@
+//! Find the displacements and immediates encoded inside an instruction
+
+use capstone::arch::{x86::X86OperandType, ArchOperand};
+use capstone::{Insn, InsnGroupId, InsnGroupType};
+use capstone_sys::{cs_insn, cs_x86_encoding};
+
+use super::AsmError;
+
+/// What a value encoded inside an instruction is
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum FixupKind {
+    /// the displacement of a relative branch, e.g. `call rel32`
+    Branch,
+    /// the displacement of a rip-relative memory operand
+    RipRelative,
+    /// the displacement of any other memory operand
+    Displacement,
+    /// an immediate operand
+    Immediate,
+}
+
+/// A value encoded inside an instruction's bytes
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Fixup {
+    pub(crate) kind: FixupKind,
+    /// offset of the value from the start of the instruction
+    pub(crate) offset: usize,
+    /// byte size of the value
+    pub(crate) size: usize,
+    /// the value. for branches and rip-relative operands, this is the absolute target address
+    pub(crate) value: u64,
+}
+
+/// Disassemble the first instruction in `code` at `runtime_addr`, returning its size along with
+/// where its displacements and immediates are encoded
+pub(crate) fn disassemble_fixups(
+    code: &[u8],
+    runtime_addr: usize,
+) -> Result<(usize, Vec<Fixup>), AsmError> {
+    let cs = super::capstone()?;
+
+    let insts = cs.disasm_count(code, runtime_addr as u64, 1)?;
+
+    let Some(insn) = insts.as_ref().iter().next() else {
+        return Err(AsmError::NoInstructions);
+    };
+
+    let detail = cs.insn_detail(insn)?;
+    let operands = detail
+        .arch_detail()
+        .operands()
+        .into_iter()
+        .filter_map(|op| match op {
+            ArchOperand::X86Operand(op) => Some(op.op_type),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    let relative = detail
+        .groups()
+        .contains(&InsnGroupId(InsnGroupType::CS_GRP_BRANCH_RELATIVE as u8));
+
+    let encoding = encoding(insn);
+    let next_addr = insn.address() + insn.bytes().len() as u64;
+
+    let mut fixups = Vec::new();
+
+    // an offset of 0 means the instruction has no displacement, the opcode always comes first
+    if encoding.disp_offset != 0 {
+        let mem = operands.iter().find_map(|op| match op {
+            X86OperandType::Mem(mem) => Some(mem),
+            _ => None,
+        });
+
+        let disp = mem.map_or(0, |mem| mem.disp());
+        let rip = mem.is_some_and(|mem| cs.reg_name(mem.base()).as_deref() == Some("rip"));
+
+        fixups.push(Fixup {
+            kind: if rip {
+                FixupKind::RipRelative
+            } else {
+                FixupKind::Displacement
+            },
+            offset: encoding.disp_offset as usize,
+            size: encoding.disp_size as usize,
+            value: if rip {
+                next_addr.wrapping_add(disp as u64)
+            } else {
+                disp as u64
+            },
+        });
+    }
+
+    if encoding.imm_offset != 0 {
+        // capstone reports relative branches as the absolute target
+        let imm = operands.iter().find_map(|op| match op {
+            X86OperandType::Imm(imm) => Some(*imm as u64),
+            _ => None,
+        });
+
+        fixups.push(Fixup {
+            kind: if relative {
+                FixupKind::Branch
+            } else {
+                FixupKind::Immediate
+            },
+            offset: encoding.imm_offset as usize,
+            size: encoding.imm_size as usize,
+            value: imm.unwrap_or_default(),
+        });
+    }
+
+    Ok((insn.len(), fixups))
+}
+
+/// Where the displacement and immediate of an instruction are encoded. The capstone bindings
+/// don't expose this, so it's read from the raw instruction detail
+fn encoding(insn: &Insn) -> cs_x86_encoding {
+    // SAFETY: Insn is repr(transparent) over cs_insn. the engine is x86 with details turned on,
+    //         so detail points to a valid cs_detail whose arch union is the x86 detail
+    unsafe {
+        let insn = &*(insn as *const Insn).cast::<cs_insn>();
+        (*insn.detail).__bindgen_anon_1.x86.encoding
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDR: usize = 0x1000;
+
+    fn fixups(code: &[u8]) -> Vec<(FixupKind, usize, usize, u64)> {
+        let (size, fixups) = disassemble_fixups(code, ADDR).unwrap();
+        assert_eq!(size, code.len());
+
+        fixups
+            .into_iter()
+            .map(|fixup| (fixup.kind, fixup.offset, fixup.size, fixup.value))
+            .collect()
+    }
+
+    #[test]
+    fn rip_relative() {
+        // mov rax, [rip + 0x10]
+        assert_eq!(
+            fixups(&[0x48, 0x8B, 0x05, 0x10, 0x00, 0x00, 0x00]),
+            [(FixupKind::RipRelative, 3, 4, 0x1017)]
+        );
+
+        // lea rcx, [rip - 0x20]
+        assert_eq!(
+            fixups(&[0x48, 0x8D, 0x0D, 0xE0, 0xFF, 0xFF, 0xFF]),
+            [(FixupKind::RipRelative, 3, 4, 0xFE7)]
+        );
+
+        // cmp byte ptr [rip + 0x10], 5
+        assert_eq!(
+            fixups(&[0x80, 0x3D, 0x10, 0x00, 0x00, 0x00, 0x05]),
+            [
+                (FixupKind::RipRelative, 2, 4, 0x1017),
+                (FixupKind::Immediate, 6, 1, 5)
+            ]
+        );
+    }
+
+    #[test]
+    fn displacement() {
+        // mov rax, [rcx + 0x10]
+        assert_eq!(
+            fixups(&[0x48, 0x8B, 0x41, 0x10]),
+            [(FixupKind::Displacement, 3, 1, 0x10)]
+        );
+
+        // mov rax, [rsp + 0x100]
+        assert_eq!(
+            fixups(&[0x48, 0x8B, 0x84, 0x24, 0x00, 0x01, 0x00, 0x00]),
+            [(FixupKind::Displacement, 4, 4, 0x100)]
+        );
+
+        // mov rax, [rcx]
+        assert_eq!(fixups(&[0x48, 0x8B, 0x01]), []);
+    }
+
+    #[test]
+    fn immediate() {
+        // add rsp, 8
+        assert_eq!(
+            fixups(&[0x48, 0x83, 0xC4, 0x08]),
+            [(FixupKind::Immediate, 3, 1, 8)]
+        );
+
+        // mov eax, 0x12345678
+        assert_eq!(
+            fixups(&[0xB8, 0x78, 0x56, 0x34, 0x12]),
+            [(FixupKind::Immediate, 1, 4, 0x12345678)]
+        );
+
+        // mov rax, 0x1122334455667788
+        assert_eq!(
+            fixups(&[0x48, 0xB8, 0x88, 0x77, 0x66, 0x55, 0x44, 0x33, 0x22, 0x11]),
+            [(FixupKind::Immediate, 2, 8, 0x1122334455667788)]
+        );
+    }
+
+    #[test]
+    fn branch() {
+        // jmp rel8
+        assert_eq!(fixups(&[0xEB, 0x10]), [(FixupKind::Branch, 1, 1, 0x1012)]);
+
+        // jne rel8, backwards
+        assert_eq!(fixups(&[0x75, 0xFE]), [(FixupKind::Branch, 1, 1, 0x1000)]);
+
+        // call rel32
+        assert_eq!(
+            fixups(&[0xE8, 0x00, 0x01, 0x00, 0x00]),
+            [(FixupKind::Branch, 1, 4, 0x1105)]
+        );
+
+        // je rel32
+        assert_eq!(
+            fixups(&[0x0F, 0x84, 0x00, 0xF0, 0xFF, 0xFF]),
+            [(FixupKind::Branch, 2, 4, 0x6)]
+        );
+
+        // call [rip + 0x10] is rip-relative, not a relative branch
+        assert_eq!(
+            fixups(&[0xFF, 0x15, 0x10, 0x00, 0x00, 0x00]),
+            [(FixupKind::RipRelative, 2, 4, 0x1016)]
+        );
+
+        // ret
+        assert_eq!(fixups(&[0xC3]), []);
+    }
+}
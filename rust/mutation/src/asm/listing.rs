@@ -0,0 +1,315 @@
+//! Disassembly listings with addresses described as modules, exports, and strings
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Write},
+    ops::Range,
+};
+
+use pelite::pe64::{Pe, PeView};
+
+use super::{disassemble_iter, AsmError, Inst, Operand};
+use crate::{
+    memory,
+    modules::{self, Module, ModuleError},
+    symbols,
+};
+
+/// How many bytes are read when looking for a string
+const STRING_READ: usize = 128;
+/// Strings shorter than this aren't shown, because almost any bytes look like a short string
+const MIN_STRING: usize = 4;
+/// Strings longer than this are cut off in the preview
+const MAX_PREVIEW: usize = 48;
+
+/// Describes addresses by the module they're in, the nearest export before them, and the
+/// string they point at. Modules and their exports are only looked up once
+#[derive(Debug, Default)]
+pub struct Symbolizer {
+    modules: Vec<Module>,
+    /// each module's exports, by module index. only filled in once an address in it is described
+    exports: HashMap<usize, ModuleExports>,
+}
+
+/// The exports of a module, sorted by address, and its section ranges
+#[derive(Debug, Default)]
+struct ModuleExports {
+    exports: Vec<(usize, String)>,
+    sections: Vec<Range<usize>>,
+}
+
+impl Symbolizer {
+    /// A symbolizer for the currently loaded modules
+    pub fn new() -> Result<Self, ModuleError> {
+        Ok(Self {
+            modules: modules::enum_modules()?,
+            exports: HashMap::new(),
+        })
+    }
+
+    /// Describe an address as `module+rva`, or `module!Export+offset` if it's in the same section
+    /// as an earlier export. Returns `None` if the address isn't in a module
+    pub fn symbolize(&mut self, addr: usize) -> Option<String> {
+        let index = self
+            .modules
+            .iter()
+            .position(|module| (module.base as usize..module.end as usize).contains(&addr))?;
+
+        let module = &self.modules[index];
+        let exports = self
+            .exports
+            .entry(index)
+            .or_insert_with(|| ModuleExports::new(module));
+
+        let name = &module.name;
+        let rva = addr - module.base as usize;
+
+        let Some((export, export_addr)) = exports.nearest(addr) else {
+            return Some(format!("{name}+{rva:#x}"));
+        };
+
+        let symbol = match addr - export_addr {
+            0 => format!("{name}!{export}"),
+            offset => format!("{name}!{export}+{offset:#x}"),
+        };
+
+        Some(symbol)
+    }
+
+    /// Describe an instruction's target: the address it branches to or references, what it
+    /// points at if it's a call or jump through memory, and the string it references, if any
+    pub fn annotate(&mut self, inst: &Inst) -> Vec<String> {
+        let mut annotations = Vec::new();
+
+        let Some(target) = inst.target() else {
+            return annotations;
+        };
+
+        let target = target as usize;
+        annotations.push(
+            self.symbolize(target)
+                .unwrap_or_else(|| format!("{target:#x}")),
+        );
+
+        let mem_size = inst.operands.iter().find_map(|op| match op {
+            Operand::Mem(mem) if mem.target.is_some() => Some(mem.size),
+            _ => None,
+        });
+
+        // only rip-relative memory operands point at data
+        let Some(mem_size) = mem_size else {
+            return annotations;
+        };
+
+        if let Some(string) = string_at(target) {
+            annotations.push(string);
+            return annotations;
+        }
+
+        // a pointer to a function, e.g. an import, or a pointer to a string
+        if mem_size == 8 && inst.mnemonic.as_deref() != Some("lea") {
+            let mut pointer = [0; 8];
+            if memory::read_bytes_checked(target as _, &mut pointer).is_ok() {
+                let pointer = usize::from_ne_bytes(pointer);
+
+                if inst.is_call() || inst.is_jump() {
+                    if let Some(symbol) = self.symbolize(pointer) {
+                        annotations.push(format!("-> {symbol}"));
+                    }
+                } else if let Some(string) = string_at(pointer) {
+                    annotations.push(format!("-> {string}"));
+                }
+            }
+        }
+
+        annotations
+    }
+}
+
+impl ModuleExports {
+    fn new(module: &Module) -> Self {
+        // this base address is crate private, so it is guaranteed
+        let base = module.handle.base;
+
+        // SAFETY: module field is crate private, it cannot be changed
+        //         and we only support 64-bit. Additionally, each module is backed by
+        //         an increased refcount, which keeps them valid for the duration of Module
+        let view = unsafe { PeView::module(base.cast()) };
+
+        let sections = view
+            .section_headers()
+            .iter()
+            .map(|section| {
+                let start = base as usize + section.VirtualAddress as usize;
+                start..start + section.VirtualSize as usize
+            })
+            .collect();
+
+        let mut exports = symbols::enum_symbols_demangled(module)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|symbol| (symbol.address as usize, symbol.name))
+            .collect::<Vec<_>>();
+
+        exports.sort_unstable();
+
+        Self { exports, sections }
+    }
+
+    /// The closest export at or before `addr`, if it's in the same section
+    fn nearest(&self, addr: usize) -> Option<(&str, usize)> {
+        let index = self.exports.partition_point(|&(export, _)| export <= addr);
+        let (export, name) = self.exports.get(index.checked_sub(1)?)?;
+
+        let same_section = self
+            .sections
+            .iter()
+            .any(|section| section.contains(&addr) && section.contains(export));
+
+        same_section.then_some((name.as_str(), *export))
+    }
+}
+
+/// A line of a [`Listing`]
+#[derive(Debug, Clone)]
+pub struct ListingLine {
+    /// the instruction
+    pub inst: Inst,
+    /// the instruction's address, described as `module+rva`
+    pub location: Option<String>,
+    /// what the instruction's target is, e.g. an export, or a string it loads
+    pub annotations: Vec<String>,
+}
+
+/// Disassembled instructions with their addresses described, see [`listing`]
+#[derive(Debug, Clone)]
+pub struct Listing {
+    /// each instruction, in order
+    pub lines: Vec<ListingLine>,
+}
+
+impl Display for Listing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rows = self
+            .lines
+            .iter()
+            .map(|line| {
+                let inst = &line.inst;
+
+                let location = match &line.location {
+                    Some(location) => location.clone(),
+                    None => format!("{:#018x}", inst.address as usize),
+                };
+
+                let mut bytes = String::new();
+                for byte in &inst.bytes {
+                    _ = write!(bytes, "{byte:02x} ");
+                }
+
+                let mut code = inst.mnemonic.clone().unwrap_or_else(|| "??".to_owned());
+                if let Some(op_str) = inst.op_str.as_deref().filter(|op_str| !op_str.is_empty()) {
+                    code.push(' ');
+                    code.push_str(op_str);
+                }
+
+                (location, bytes, code, line.annotations.join(" "))
+            })
+            .collect::<Vec<_>>();
+
+        // line the columns up
+        let location_width = rows.iter().map(|row| row.0.len()).max().unwrap_or_default();
+        let bytes_width = rows.iter().map(|row| row.1.len()).max().unwrap_or_default();
+        let code_width = rows.iter().map(|row| row.2.len()).max().unwrap_or_default();
+
+        for (location, bytes, code, annotations) in rows {
+            if annotations.is_empty() {
+                let line = format!("{location:<location_width$}  {bytes:<bytes_width$} {code}");
+                writeln!(f, "{}", line.trim_end())?;
+            } else {
+                writeln!(
+                    f,
+                    "{location:<location_width$}  {bytes:<bytes_width$} {code:<code_width$}  ; {annotations}"
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Disassemble `count` instructions at `addr` into a listing, describing each instruction's address
+/// as `module+rva`, and annotating each call, jump, and rip-relative operand with the module, export,
+/// and string it points at.
+///
+/// ```text
+/// game.exe+0x1000  48 8d 0d f9 1f 00 00     lea rcx, [rip + 0x1ff9]   ; game.exe+0x3000 "Hello world"
+/// game.exe+0x1007  ff 15 f3 2f 00 00        call qword ptr [rip + 0x2ff3]   ; game.exe+0x4000 -> user32.dll!MessageBoxA
+/// ```
+///
+/// The listing stops early at the first instruction which can't be disassembled.
+///
+/// # Safety
+/// - `addr` must be valid for reads of every disassembled instruction, plus 16 bytes
+pub unsafe fn listing(addr: *const u8, count: usize) -> Result<Listing, AsmError> {
+    if addr.is_null() {
+        return Err(AsmError::BadAddress);
+    }
+
+    // without modules, addresses are just left undescribed
+    let mut symbolizer = Symbolizer::new().unwrap_or_default();
+
+    // SAFETY: the caller guarantees addr is valid for reads of the instructions
+    let insts = unsafe { disassemble_iter(addr, addr as usize) };
+
+    let lines = insts
+        .take(count)
+        .map(|inst| ListingLine {
+            location: symbolizer.symbolize(inst.address as usize),
+            annotations: symbolizer.annotate(&inst),
+            inst,
+        })
+        .collect::<Vec<_>>();
+
+    if lines.is_empty() && count > 0 {
+        return Err(AsmError::NoInstructions);
+    }
+
+    Ok(Listing { lines })
+}
+
+/// A quoted preview of the nul terminated ascii or utf-16 string at `addr`, if there is one
+fn string_at(addr: usize) -> Option<String> {
+    // read as much as possible, as the string could be right before unreadable memory
+    let mut buf = [0; STRING_READ];
+    let len = [STRING_READ, 32, 16]
+        .into_iter()
+        .find(|&len| memory::read_bytes_checked(addr as _, &mut buf[..len]).is_ok())?;
+    let buf = &buf[..len];
+
+    let is_text = |c: u8| c.is_ascii_graphic() || matches!(c, b' ' | b'\t' | b'\n' | b'\r');
+
+    let ascii = buf.iter().take_while(|&&c| is_text(c)).count();
+    let wide = buf
+        .chunks_exact(2)
+        .take_while(|pair| is_text(pair[0]) && pair[1] == 0)
+        .count();
+
+    // a string needs its nul terminator, unless it's cut off by the end of what was read
+    let terminated =
+        |end: usize, nul: &[u8]| buf.get(end..end + nul.len()).map_or(true, |b| b == nul);
+
+    let (text, prefix) = if ascii >= MIN_STRING && terminated(ascii, &[0]) {
+        (buf[..ascii].to_vec(), "")
+    } else if wide >= MIN_STRING && terminated(wide * 2, &[0, 0]) {
+        (buf.iter().step_by(2).take(wide).copied().collect(), "L")
+    } else {
+        return None;
+    };
+
+    let mut text = String::from_utf8(text).ok()?;
+    let cut = text.len() > MAX_PREVIEW;
+    text.truncate(MAX_PREVIEW);
+
+    let ellipsis = if cut { "..." } else { "" };
+    Some(format!("{prefix}{text:?}{ellipsis}"))
+}
@@ -0,0 +1,279 @@
+//! Re-encode instructions to run at a different address
+
+use super::{disassemble_fixups, AsmError, FixupKind};
+
+/// The most bytes a single instruction can grow to when relocated, a rel8-only branch with an absolute jump
+pub(crate) const MAX_RELOCATED_SIZE: usize = 18;
+
+/// `jmp [rip]`, followed by the absolute address
+const JMP_ABS: [u8; 6] = [0xFF, 0x25, 0x00, 0x00, 0x00, 0x00];
+/// `call [rip + 2]`, then `jmp +8` over the absolute address which follows
+const CALL_ABS: [u8; 8] = [0xFF, 0x15, 0x02, 0x00, 0x00, 0x00, 0xEB, 0x08];
+
+/// Re-encode the whole instructions in `code`, which are at `from`, so they work the same at `to`.
+///
+/// Relative branches and rip-relative operands are adjusted for the new address. Short branches are
+/// widened to rel32, and branches whose target is out of rel32 range from `to` become absolute jumps.
+///
+/// Fails if a branch targets the inside of `code`, including its start, as those bytes won't be
+/// there anymore, or if a rip-relative operand is out of rel32 range from `to`.
+pub(crate) fn relocate(code: &[u8], from: usize, to: usize) -> Result<Vec<u8>, AsmError> {
+    let code_range = from..from + code.len();
+    let mut out = Vec::with_capacity(code.len());
+    let mut offset = 0;
+
+    while offset < code.len() {
+        let addr = from + offset;
+        let (size, fixups) = disassemble_fixups(&code[offset..], addr)?;
+
+        let bytes = &code[offset..offset + size];
+        offset += size;
+
+        let new_addr = to + out.len();
+
+        if let Some(fixup) = fixups.iter().find(|f| f.kind == FixupKind::Branch) {
+            let target = fixup.value as usize;
+
+            // including the start, which now holds whatever replaced the code, e.g. a hook's jump
+            if code_range.contains(&target) {
+                return Err(AsmError::BranchIntoRelocated);
+            }
+
+            let opcode = bytes[fixup.offset - 1];
+            let escape = fixup.offset.checked_sub(2).map(|i| bytes[i]);
+
+            let relocated = match (escape, opcode) {
+                // call rel32
+                (_, 0xE8) => rel32_or(&[0xE8], new_addr, target, || {
+                    let mut call = CALL_ABS.to_vec();
+                    call.extend_from_slice(&target.to_le_bytes());
+                    call
+                }),
+
+                // jmp rel8 and jmp rel32
+                (_, 0xEB | 0xE9) => rel32_or(&[0xE9], new_addr, target, || jmp_abs(target)),
+
+                // jcc rel8 and jcc rel32
+                (_, 0x70..=0x7F) | (Some(0x0F), 0x80..=0x8F) => {
+                    let cc = opcode & 0x0F;
+
+                    rel32_or(&[0x0F, 0x80 | cc], new_addr, target, || {
+                        // the opposite condition skips over the absolute jump
+                        let mut jcc = vec![0x70 | (cc ^ 1), JMP_ABS.len() as u8 + 8];
+                        jcc.extend_from_slice(&jmp_abs(target));
+                        jcc
+                    })
+                }
+
+                // loop, loope, loopne, and jrcxz only have a rel8 form. so they branch to an
+                // absolute jump right after them, and a short jmp skips it when they don't
+                (_, 0xE0..=0xE3) if fixup.size == 1 => {
+                    let mut branch = bytes.to_vec();
+                    branch[fixup.offset] = 2;
+                    branch.extend_from_slice(&[0xEB, JMP_ABS.len() as u8 + 8]);
+                    branch.extend_from_slice(&jmp_abs(target));
+                    branch
+                }
+
+                // anything else only works if its displacement is already rel32
+                _ if fixup.size == 4 => {
+                    let next = new_addr + bytes.len();
+                    let disp = rel32(next, target).ok_or(AsmError::RelocationOutOfRange)?;
+
+                    let mut branch = bytes.to_vec();
+                    branch[fixup.offset..fixup.offset + 4].copy_from_slice(&disp.to_le_bytes());
+                    branch
+                }
+
+                _ => return Err(AsmError::RelocationOutOfRange),
+            };
+
+            out.extend_from_slice(&relocated);
+            continue;
+        }
+
+        if let Some(fixup) = fixups.iter().find(|f| f.kind == FixupKind::RipRelative) {
+            let next = new_addr + bytes.len();
+            let disp = rel32(next, fixup.value as usize).ok_or(AsmError::RelocationOutOfRange)?;
+
+            let mut relocated = bytes.to_vec();
+            relocated[fixup.offset..fixup.offset + 4].copy_from_slice(&disp.to_le_bytes());
+
+            out.extend_from_slice(&relocated);
+            continue;
+        }
+
+        out.extend_from_slice(bytes);
+    }
+
+    Ok(out)
+}
+
+/// The rel32 displacement from `next` to `target`, if it's in range
+pub(super) fn rel32(next: usize, target: usize) -> Option<i32> {
+    (target as isize)
+        .checked_sub(next as isize)
+        .and_then(|disp| disp.try_into().ok())
+}
+
+/// Encode `opcode rel32` at `addr` if `target` is in range, otherwise use `fallback`
+fn rel32_or(
+    opcode: &[u8],
+    addr: usize,
+    target: usize,
+    fallback: impl FnOnce() -> Vec<u8>,
+) -> Vec<u8> {
+    let next = addr + opcode.len() + 4;
+
+    match rel32(next, target) {
+        Some(disp) => {
+            let mut branch = opcode.to_vec();
+            branch.extend_from_slice(&disp.to_le_bytes());
+            branch
+        }
+
+        None => fallback(),
+    }
+}
+
+/// `jmp [rip]` to an absolute address
+fn jmp_abs(target: usize) -> Vec<u8> {
+    let mut jmp = JMP_ABS.to_vec();
+    jmp.extend_from_slice(&target.to_le_bytes());
+    jmp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FROM: usize = 0x1_4000_1000;
+    /// in rel32 range of FROM
+    const NEAR: usize = 0x1_4100_0000;
+    /// out of rel32 range of FROM
+    const FAR: usize = 0x7FF0_0000_0000;
+
+    /// `opcode` followed by the rel32 displacement from `next` to `target`
+    fn rel(opcode: &[u8], next: usize, target: usize) -> Vec<u8> {
+        let mut bytes = opcode.to_vec();
+        bytes.extend_from_slice(&rel32(next, target).unwrap().to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn plain() {
+        // mov rax, [rcx + 8]; ret
+        let code = [0x48, 0x8B, 0x41, 0x08, 0xC3];
+        assert_eq!(relocate(&code, FROM, FAR).unwrap(), code);
+    }
+
+    #[test]
+    fn rip_relative() {
+        // mov rax, [rip + 0x100]
+        let code = [0x48, 0x8B, 0x05, 0x00, 0x01, 0x00, 0x00];
+        let target = FROM + 7 + 0x100;
+
+        assert_eq!(
+            relocate(&code, FROM, NEAR).unwrap(),
+            rel(&[0x48, 0x8B, 0x05], NEAR + 7, target)
+        );
+
+        assert!(matches!(
+            relocate(&code, FROM, FAR),
+            Err(AsmError::RelocationOutOfRange)
+        ));
+    }
+
+    #[test]
+    fn near_branches() {
+        // jmp rel8 is widened
+        let target = FROM + 2 + 0x10;
+        assert_eq!(
+            relocate(&[0xEB, 0x10], FROM, NEAR).unwrap(),
+            rel(&[0xE9], NEAR + 5, target)
+        );
+
+        // jz rel8 is widened
+        assert_eq!(
+            relocate(&[0x74, 0x10], FROM, NEAR).unwrap(),
+            rel(&[0x0F, 0x84], NEAR + 6, target)
+        );
+
+        // call rel32
+        let target = FROM + 5 + 0x100;
+        assert_eq!(
+            relocate(&[0xE8, 0x00, 0x01, 0x00, 0x00], FROM, NEAR).unwrap(),
+            rel(&[0xE8], NEAR + 5, target)
+        );
+
+        // jnz rel32
+        let target = FROM + 6 + 0x100;
+        assert_eq!(
+            relocate(&[0x0F, 0x85, 0x00, 0x01, 0x00, 0x00], FROM, NEAR).unwrap(),
+            rel(&[0x0F, 0x85], NEAR + 6, target)
+        );
+    }
+
+    #[test]
+    fn far_branches() {
+        let target = FROM + 5 + 0x100;
+
+        // call rel32 becomes call [rip + 2], jmp over the address
+        let mut call = CALL_ABS.to_vec();
+        call.extend_from_slice(&target.to_le_bytes());
+        assert_eq!(
+            relocate(&[0xE8, 0x00, 0x01, 0x00, 0x00], FROM, FAR).unwrap(),
+            call
+        );
+
+        // jmp rel32 becomes jmp [rip]
+        assert_eq!(
+            relocate(&[0xE9, 0x00, 0x01, 0x00, 0x00], FROM, FAR).unwrap(),
+            jmp_abs(target)
+        );
+
+        // jz rel8 becomes jnz over an absolute jmp
+        let target = FROM + 2 + 0x10;
+        let mut jz = vec![0x75, 14];
+        jz.extend_from_slice(&jmp_abs(target));
+        assert_eq!(relocate(&[0x74, 0x10], FROM, FAR).unwrap(), jz);
+    }
+
+    #[test]
+    fn rel8_only_branches() {
+        // jrcxz branches to an absolute jmp, which a short jmp skips otherwise
+        let target = FROM + 2 + 0x10;
+
+        let mut jrcxz = vec![0xE3, 0x02, 0xEB, 14];
+        jrcxz.extend_from_slice(&jmp_abs(target));
+
+        assert_eq!(relocate(&[0xE3, 0x10], FROM, NEAR).unwrap(), jrcxz);
+        assert_eq!(relocate(&[0xE3, 0x10], FROM, FAR).unwrap(), jrcxz);
+    }
+
+    #[test]
+    fn branch_into_relocated() {
+        // jz to the nop, which isn't at the start
+        assert!(matches!(
+            relocate(&[0x74, 0x00, 0x90], FROM, NEAR),
+            Err(AsmError::BranchIntoRelocated)
+        ));
+    }
+
+    #[test]
+    fn branch_to_start() {
+        // nop; jmp back to the nop. the start is overwritten, e.g. by a hook's jump to its detour
+        for to in [NEAR, FAR] {
+            assert!(matches!(
+                relocate(&[0x90, 0xEB, 0xFD], FROM, to),
+                Err(AsmError::BranchIntoRelocated)
+            ));
+        }
+
+        // call rel32 to the start
+        assert!(matches!(
+            relocate(&[0xE8, 0xFB, 0xFF, 0xFF, 0xFF], FROM, NEAR),
+            Err(AsmError::BranchIntoRelocated)
+        ));
+    }
+}
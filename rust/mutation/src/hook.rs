@@ -35,6 +35,8 @@ pub struct Trampoline {
     _code: Arc<Alloc>,
     // the original ptr + length that was replaced
     from: (*mut u8, usize),
+    // the original bytes that were replaced
+    original: Arc<Vec<u8>>,
     mutex: Mutex<()>,
     /// the trampoline address
     pub address: *const u8,
@@ -61,6 +63,7 @@ impl Clone for Trampoline {
         Self {
             _code: self._code.clone(),
             from: self.from,
+            original: self.original.clone(),
             mutex: Mutex::default(),
             address: self.address,
             size: self.size,
@@ -104,9 +107,8 @@ impl Trampoline {
         let _guard = self.mutex.lock().unwrap();
 
         trace!(
-            "unhook copying {} bytes from {:?} -> {:?}",
+            "unhook restoring {} bytes -> {:?}",
             self.from.1,
-            self._code.addr(),
             self.from.0
         );
 
@@ -115,7 +117,7 @@ impl Trampoline {
 
         // replace original fn code back to original location
         unsafe {
-            memory::write_raw(self._code.addr() as _, self.from.0, self.from.1);
+            memory::write_bytes(&self.original, self.from.0);
         }
 
         // restore memory protection
@@ -164,25 +166,25 @@ fn make_jmp(from: *mut u8, to: *const u8, force_64: bool) -> ArrayVec<u8, 14> {
 }
 
 /// Starting at from address, finds next whole instruction and replaces it with
-/// jmp to target address. The replaced instruction is placed inside the trampoline,
-/// so caller must verify no relative instructions are replaced, as these are not
-/// valid if they're in another location.
+/// jmp to target address. The replaced instructions are placed inside the trampoline,
+/// followed by a jmp back to the rest of the original function.
+///
+/// Relative instructions which get replaced are re-encoded for the trampoline. Short branches are widened,
+/// and branches which can't reach their target from the trampoline become absolute jumps. The trampoline is
+/// allocated within 2GB of `from` when possible, so that rip-relative operands can still reach their target.
+/// If a replaced branch jumps back into the replaced instructions, or a rip-relative operand can't reach its
+/// target, nothing is hooked and an error is returned.
 ///
 /// If `to` address is within 32-bits of `from`, uses relative 32-bit jmp (5 bytes), otherwise
 /// will take 14 bytes for a full 64-bit jmp
 ///
 /// # Safety
 /// - Must manually verify `from`` location enough space for 14 or 5 bytes jmp to be written
-/// - Must verify instruction that gets replaced is not relative
-/// - Instruction that gets replaced should be able to ran in a different area of memory
+/// - Nothing else may jump into the middle of the instructions which get replaced
 pub unsafe fn hook(from: *mut u8, to: *const u8) -> Result<Trampoline, HookError> {
     debug_assert!(!from.is_null(), "from must not be null");
     debug_assert!(!to.is_null(), "to must not be null");
 
-    //
-    // copy the jmp to the original function to redirect it
-    //
-
     // generate 5 or 14 byte jmp, whichever is possible
     let jmp = make_jmp(from, to, false);
 
@@ -190,6 +192,40 @@ pub unsafe fn hook(from: *mut u8, to: *const u8) -> Result<Trampoline, HookError
     let code_len = unsafe { asm::code_len(from, jmp.len())? };
     let orig_bytes = unsafe { memory::read_bytes(from, code_len) };
 
+    //
+    // generate the trampoline first, so nothing is hooked if it can't be made
+    //
+
+    // every relocated instruction fits in this, plus the full 64-bit jmp back
+    let max_len = orig_bytes.len() * asm::MAX_RELOCATED_SIZE + 14;
+    let trampoline = alloc_near(from, max_len)?;
+
+    let code = asm::relocate(&orig_bytes, from as usize, trampoline.addr() as usize)?;
+
+    // generate full 64-bit jmp for trampoline
+    // when force is on, `from` addr is not used
+    let target = unsafe { from.add(code_len) };
+    let jmp_back = make_jmp(ptr::null_mut(), target, true);
+
+    let trampoline_len = code.len() + jmp_back.len();
+
+    trace!("trampoline @ {:?} jmp -> {:?}", trampoline.addr(), target);
+
+    // write relocated original code to trampoline
+    unsafe { memory::write_bytes(&code, trampoline.addr()) };
+
+    // now write jmp
+    unsafe { memory::write_bytes(&jmp_back, trampoline.addr().add(code.len())) };
+
+    // make it executable and readonly
+    unsafe {
+        memory::prot(trampoline.addr().cast(), trampoline_len, Prot::XR)?;
+    }
+
+    //
+    // copy the jmp to the original function to redirect it
+    //
+
     trace!(
         "jmp -> {to:?} used {} bytes spanning {from:?}-0x{:x}",
         jmp.len(),
@@ -210,38 +246,13 @@ pub unsafe fn hook(from: *mut u8, to: *const u8) -> Result<Trampoline, HookError
         memory::prot(from.cast(), prot_size, old)?;
     }
 
-    //
-    // generate the trampoline
-    //
-
-    // generate full 64-bit jmp for trampoline
-    // when force is on, `from` addr is not used
-    let target = unsafe { from.add(code_len) };
-    let jmp = make_jmp(ptr::null_mut(), target, true);
-
-    // allocate some memory for our trampoline
-    let trampoline_len = orig_bytes.len() + jmp.len();
-    let trampoline = memory::alloc(trampoline_len, Prot::XRW)?;
-
-    trace!("trampoline @ {:?} jmp -> {:?}", trampoline.addr(), target);
-
-    // write original code to trampoline
-    unsafe { memory::write_bytes(&orig_bytes, trampoline.addr()) };
-
-    // now write jmp
-    unsafe { memory::write_bytes(&jmp, trampoline.addr().add(orig_bytes.len())) };
-
-    // make it executable and readonly
-    unsafe {
-        memory::prot(trampoline.addr().cast(), trampoline_len, Prot::XR)?;
-    }
-
     //
     // end
     //
 
     let trampoline = Trampoline {
         from: (from, code_len),
+        original: Arc::new(orig_bytes),
         address: trampoline.addr(),
         _code: Arc::new(trampoline),
         size: trampoline_len,
@@ -250,3 +261,18 @@ pub unsafe fn hook(from: *mut u8, to: *const u8) -> Result<Trampoline, HookError
 
     Ok(trampoline)
 }
+
+/// Allocate executable memory within 2GB of `addr` if possible, otherwise anywhere
+fn alloc_near(addr: *mut u8, size: usize) -> Result<Alloc, HookError> {
+    let gb = 1024 * 1024 * 1024;
+
+    // leave room for the size of the allocation, so all of it stays in range
+    let begin = (addr as usize).saturating_sub(gb * 2 - size);
+    let end = (addr as usize).saturating_add(gb * 2 - size);
+
+    if let Ok(alloc) = memory::alloc_in(begin as _, end as _, size, 0, Prot::XRW) {
+        return Ok(alloc);
+    }
+
+    Ok(memory::alloc(size, Prot::XRW)?)
+}
@@ -17,6 +17,7 @@ pub mod hook;
 pub mod iat;
 pub mod memory;
 pub mod modules;
+pub mod patch;
 pub mod scan;
 pub mod segments;
 pub mod symbols;
@@ -51,6 +52,26 @@ pub enum Prot {
     Other,
 }
 
+impl Prot {
+    /// Whether memory with this protection can be read
+    pub fn is_readable(self) -> bool {
+        matches!(
+            self,
+            Self::R | Self::W | Self::XR | Self::XW | Self::RW | Self::XRW
+        )
+    }
+
+    /// Whether memory with this protection can be written to
+    pub fn is_writable(self) -> bool {
+        matches!(self, Self::W | Self::XW | Self::RW | Self::XRW)
+    }
+
+    /// Whether memory with this protection can be executed
+    pub fn is_executable(self) -> bool {
+        matches!(self, Self::X | Self::XR | Self::XW | Self::XRW)
+    }
+}
+
 impl From<Prot> for PAGE_PROTECTION_FLAGS {
     fn from(value: Prot) -> Self {
         match value {
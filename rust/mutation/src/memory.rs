@@ -8,12 +8,14 @@ use tracing::error;
 use windows::Win32::{
     Foundation::{GetLastError, WIN32_ERROR},
     System::{
+        Diagnostics::Debug::ReadProcessMemory,
         Memory::{
             MemExtendedParameterAddressRequirements, VirtualAlloc, VirtualAlloc2, VirtualFree,
             VirtualProtect, MEM_ADDRESS_REQUIREMENTS, MEM_COMMIT, MEM_EXTENDED_PARAMETER,
             MEM_RELEASE, MEM_RESERVE, PAGE_PROTECTION_FLAGS,
         },
         SystemInformation::{GetSystemInfo, SYSTEM_INFO},
+        Threading::GetCurrentProcess,
     },
 };
 
@@ -122,6 +124,28 @@ pub unsafe fn read_bytes(src: *const u8, count: usize) -> Vec<u8> {
     buffer
 }
 
+/// Read bytes from address into `buf`. Unlike [`read_bytes`], this fails instead of crashing
+/// if any of the memory isn't readable, so it's safe to call on any address
+pub fn read_bytes_checked(src: *const u8, buf: &mut [u8]) -> Result<(), MemError> {
+    let mut read = 0;
+
+    unsafe {
+        ReadProcessMemory(
+            GetCurrentProcess(),
+            src.cast(),
+            buf.as_mut_ptr().cast(),
+            buf.len(),
+            Some(&mut read),
+        )?;
+    }
+
+    if read != buf.len() {
+        return Err(MemError::BadAddress);
+    }
+
+    Ok(())
+}
+
 /// Write T to dst
 ///
 /// # Safety
@@ -332,7 +356,7 @@ pub fn alloc_in(
 /// offsets to a base address and dereferencing intermediate pointers.
 ///
 /// - `base` is the starting address from which to calculate the deep pointer
-/// - `offsets` is an array of offsets used to navigate through the memory addresses.
+/// - `offsets` is an array of byte offsets used to navigate through the memory addresses.
 ///
 /// # Safety
 /// - `base` must be a valid pointer pointing to a pointer
@@ -347,7 +371,7 @@ pub unsafe fn deep_pointer(
 
     for offset in offsets {
         base = unsafe { read(base.cast()) };
-        base = unsafe { base.add(*offset) };
+        base = base.wrapping_byte_add(*offset);
     }
 
     Ok(base.cast())
@@ -361,3 +385,21 @@ fn get_page_size() -> u32 {
 
     sysinfo.dwPageSize
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deep_pointer_byte_offsets() {
+        // the target is 0x3c bytes into a struct, which is pointed to from 0x10 bytes into another
+        let inner = [0u8; 0x40];
+        let outer = [0, 0, inner.as_ptr() as usize, 0];
+        let outer_ptr = outer.as_ptr();
+
+        let base = ptr::addr_of!(outer_ptr).cast::<*const ()>();
+        let target = unsafe { deep_pointer(base, &[0x10, 0x3C]) }.unwrap();
+
+        assert_eq!(target, inner.as_ptr().wrapping_add(0x3C).cast());
+    }
+}
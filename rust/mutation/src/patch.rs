@@ -0,0 +1,213 @@
+//! This module allows one to patch code in place, and revert it later
+
+use std::{fmt, sync::Mutex};
+
+use tracing::trace;
+
+use crate::{
+    asm::{self, AsmError, AssembleError, ModuleResolver},
+    memory::{self, MemError},
+    modules::ModuleError,
+    Prot,
+};
+
+/// An error for the [patch](crate::patch) module
+#[derive(Debug, thiserror::Error)]
+pub enum PatchError {
+    /// a memory error
+    #[error(transparent)]
+    MemError(#[from] MemError),
+    /// an asm error
+    #[error(transparent)]
+    AsmError(#[from] AsmError),
+    /// the patch's asm failed to assemble
+    #[error(transparent)]
+    AssembleError(#[from] AssembleError),
+    /// the loaded modules couldn't be listed to resolve symbols
+    #[error(transparent)]
+    ModuleError(#[from] ModuleError),
+    /// the patch has no bytes
+    #[error("patch is empty")]
+    Empty,
+}
+
+/// A patch of code written over an address, which can be turned on and off.
+///
+/// The patch is padded with nops up to the next instruction boundary, so no instruction is left
+/// half overwritten. The original bytes are saved, and the patch is reverted when this type is dropped.
+pub struct Patch {
+    /// the patched address
+    pub address: *mut u8,
+    /// the patch bytes, including the nop padding
+    bytes: Vec<u8>,
+    /// the bytes which were at the address before patching
+    original: Vec<u8>,
+    /// whether the patch is written
+    enabled: Mutex<bool>,
+}
+
+impl fmt::Debug for Patch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Patch")
+            .field("address", &self.address)
+            .field("size", &self.bytes.len())
+            .field("enabled", &self.is_enabled())
+            .finish()
+    }
+}
+
+unsafe impl Send for Patch {}
+unsafe impl Sync for Patch {}
+
+impl fmt::Display for Patch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Patch {{ address: {:?}, size: {}, enabled: {} }}",
+            self.address,
+            self.bytes.len(),
+            self.is_enabled()
+        )
+    }
+}
+
+impl Patch {
+    /// Write `bytes` over the code at `address`, padding it with nops to the next instruction boundary
+    ///
+    /// # Safety
+    /// - `address` must be valid code, which can be read from and written to for the padded size
+    /// - no thread may be executing the overwritten instructions while they're being written
+    pub unsafe fn new(address: *mut u8, bytes: &[u8]) -> Result<Self, PatchError> {
+        if address.is_null() {
+            return Err(MemError::BadAddress.into());
+        }
+
+        if bytes.is_empty() {
+            return Err(PatchError::Empty);
+        }
+
+        let size = unsafe { asm::code_len(address, bytes.len())? };
+        // the code ran out before the patch did
+        if size < bytes.len() {
+            return Err(AsmError::BadDis.into());
+        }
+
+        let original = unsafe { memory::read_bytes(address, size) };
+
+        trace!(
+            "patching {size} bytes at {address:?}, {} of them nop padding",
+            size - bytes.len()
+        );
+
+        let mut bytes = bytes.to_vec();
+        bytes.resize(size, 0x90);
+
+        let patch = Self {
+            address,
+            bytes,
+            original,
+            enabled: Mutex::new(false),
+        };
+
+        unsafe {
+            patch.enable()?;
+        }
+
+        Ok(patch)
+    }
+
+    /// Assemble `code` at `address`, and write it over the code there, padding it with nops to the
+    /// next instruction boundary. `code` can have multiple lines, labels, and symbols, like
+    /// [`assemble_block`](crate::asm::assemble_block)
+    ///
+    /// # Safety
+    /// - `address` must be valid code, which can be read from and written to for the padded size
+    /// - no thread may be executing the overwritten instructions while they're being written
+    pub unsafe fn asm(address: *mut u8, code: &str) -> Result<Self, PatchError> {
+        let resolver = ModuleResolver::new()?;
+        let insts = asm::assemble_block(code, address as usize, &resolver)?;
+
+        let bytes = insts
+            .iter()
+            .flat_map(|inst| &inst.bytes)
+            .copied()
+            .collect::<Vec<_>>();
+
+        unsafe { Self::new(address, &bytes) }
+    }
+
+    /// The patch bytes, including the nop padding
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// The bytes which were at the address before patching
+    pub fn original(&self) -> &[u8] {
+        &self.original
+    }
+
+    /// Whether the patch is currently written
+    pub fn is_enabled(&self) -> bool {
+        *self.enabled.lock().unwrap()
+    }
+
+    /// Write the patch. Does nothing if it's already written
+    ///
+    /// # Safety
+    /// This overwrites code. There is no synchronization with threads executing it.
+    pub unsafe fn enable(&self) -> Result<(), PatchError> {
+        let mut enabled = self.enabled.lock().unwrap();
+        if *enabled {
+            return Ok(());
+        }
+
+        unsafe {
+            self.write(&self.bytes)?;
+        }
+
+        *enabled = true;
+        Ok(())
+    }
+
+    /// Restore the original bytes. Does nothing if the patch isn't written
+    ///
+    /// # Safety
+    /// This overwrites code. There is no synchronization with threads executing it.
+    pub unsafe fn disable(&self) -> Result<(), PatchError> {
+        let mut enabled = self.enabled.lock().unwrap();
+        if !*enabled {
+            return Ok(());
+        }
+
+        unsafe {
+            self.write(&self.original)?;
+        }
+
+        *enabled = false;
+        Ok(())
+    }
+
+    unsafe fn write(&self, bytes: &[u8]) -> Result<(), PatchError> {
+        trace!("writing {} bytes to {:?}", bytes.len(), self.address);
+
+        // remove memory protection
+        let old = unsafe { memory::prot(self.address.cast(), bytes.len(), Prot::XRW)? };
+
+        unsafe {
+            memory::write_bytes(bytes, self.address);
+        }
+
+        // restore memory protection
+        unsafe {
+            memory::prot(self.address.cast(), bytes.len(), old)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Patch {
+    fn drop(&mut self) {
+        _ = unsafe { self.disable() };
+    }
+}
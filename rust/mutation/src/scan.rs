@@ -1,10 +1,42 @@
 //! This module allows one to scan memory for specific data
 
+use std::{borrow::Cow, fmt};
+
 mod aligned_bytes;
 mod backends;
+mod batch;
+mod cache;
+mod chunks;
+mod insn;
+mod notation;
 mod pattern;
+mod pointer;
+mod process;
+mod region;
+mod signature;
+pub(crate) mod sweep;
+mod value;
+mod xref;
 
-use self::pattern::{Pattern, PatternError};
+use self::backends::Matches;
+pub use self::{
+    backends::Backend,
+    batch::{batch_scan, batch_scan_all},
+    cache::{CacheError, Fingerprint, SigCache},
+    insn::{insn_scan, Capture, InsnMatch, InsnPattern, InsnPatternError},
+    notation::Notation,
+    pattern::{Pattern, PatternError},
+    pointer::{
+        intersect_chains, load_chains, pointer_scan, save_chains, PointerChain, PointerScanError,
+    },
+    process::{
+        sig_scan_process, sig_scan_process_all, ModuleFilter, ProcessFilter, ProcessScanError,
+    },
+    region::{Region, RegionError, RegionScan},
+    signature::{make_signature, Signature, SignatureError},
+    value::{Filter, Value, ValueScan, ValueScanError, ValueType},
+    xref::{find_string_xrefs, find_xrefs, Xref, XrefKind},
+};
 
 /// Scanning errors
 #[derive(Debug, thiserror::Error)]
@@ -24,10 +56,30 @@ pub struct Scan {
 unsafe impl Send for Scan {}
 unsafe impl Sync for Scan {}
 
+/// An iterator over every match of a scan, in ascending address order.
+///
+/// Overlapping matches are all returned. Use [`Iterator::take`] to limit the amount of results.
+pub struct Scans<'a>(Option<Matches<Cow<'a, Pattern>>>);
+
+impl fmt::Debug for Scans<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Scans").finish_non_exhaustive()
+    }
+}
+
+impl Iterator for Scans<'_> {
+    type Item = Scan;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.as_mut()?.next()
+    }
+}
+
 /// Single result IDA-style pattern scanner
 ///
 /// A pattern scanner that searches for an IDA-style pattern
 /// and returns the pointer to the first occurrence in the binary.
+/// Patterns in any other [`Notation`] are detected and parsed too.
 ///
 /// Find the first occurence of the pattern in the binary
 ///
@@ -59,6 +111,21 @@ pub unsafe fn sig_scan(pattern: &str, addr: *const u8, size: usize) -> Option<Sc
     unsafe { backends::find(&pattern, addr, size) }
 }
 
+/// Single result IDA-style pattern scanner, which splits the work across multiple threads
+///
+/// This returns the same match as [`sig_scan`], but is faster on large regions.
+///
+/// # Safety
+///
+/// * `addr` - is a valid pointer
+///
+/// * `size` - corresponds to a valid size of `binary`
+pub unsafe fn sig_scan_parallel(pattern: &str, addr: *const u8, size: usize) -> Option<Scan> {
+    let pattern = pattern.try_into().ok()?;
+    // SAFETY: safe to call as long as the safety conditions were met for this function
+    unsafe { backends::find_parallel(&pattern, addr, size) }
+}
+
 /// Scan address for data.
 ///
 /// Find the first occurence of the pattern in the binary
@@ -125,3 +192,67 @@ pub unsafe fn pattern_scan(data: &[u8], mask: &str, addr: *const u8, size: usize
     let pattern = Pattern::from_data_with_mask(data, mask).ok()?;
     unsafe { backends::find(&pattern, addr, size) }
 }
+
+/// Multi result IDA-style pattern scanner
+///
+/// Same as [`sig_scan`], but returns an iterator over every occurrence of the pattern.
+/// The pattern is only parsed once. If the pattern is invalid, the iterator is empty.
+///
+/// # Safety
+///
+/// * `addr` - is a valid pointer for as long as the iterator is alive
+///
+/// * `size` - corresponds to a valid size of `binary`
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let binary = [0x48, 0x89, 0x5c, 0x24, 0xee, 0x48, 0x89, 0x5c, 0x24, 0x11];
+///
+/// let results = unsafe { sig_scan_all("48 89 5c 24 ??", binary.as_ptr(), binary.len()) };
+///
+/// for result in results.take(10) {
+///     println!("{:?}", result);
+/// }
+/// ```
+pub unsafe fn sig_scan_all(pattern: &str, addr: *const u8, size: usize) -> Scans<'static> {
+    let pattern = Pattern::try_from(pattern).ok();
+    // SAFETY: safe to call as long as the safety conditions were met for this function
+    Scans(pattern.map(|pattern| unsafe { backends::find_all(Cow::Owned(pattern), addr, size) }))
+}
+
+/// Scan address for every occurrence of data.
+///
+/// Same as [`data_scan`], but returns an iterator over every occurrence of the data.
+///
+/// # Safety
+///
+/// * `addr` - is a valid pointer for as long as the iterator is alive
+///
+/// * `size` - corresponds to a valid size of `binary`
+pub unsafe fn data_scan_all(data: &[u8], addr: *const u8, size: usize) -> Scans<'static> {
+    let pattern = Cow::Owned(data.into());
+    // SAFETY: safe to call as long as the safety conditions were met for this function
+    Scans(Some(unsafe { backends::find_all(pattern, addr, size) }))
+}
+
+/// Scan address for every occurrence of data with a mask
+///
+/// Same as [`pattern_scan`], but returns an iterator over every occurrence of the pattern.
+/// If the mask is invalid, the iterator is empty.
+///
+/// # Safety
+///
+/// * `addr` - is a valid pointer for as long as the iterator is alive
+///
+/// * `size` - corresponds to a valid size of `binary`
+pub unsafe fn pattern_scan_all(
+    data: &[u8],
+    mask: &str,
+    addr: *const u8,
+    size: usize,
+) -> Scans<'static> {
+    let pattern = Pattern::from_data_with_mask(data, mask).ok();
+    // SAFETY: safe to call as long as the safety conditions were met for this function
+    Scans(pattern.map(|pattern| unsafe { backends::find_all(Cow::Owned(pattern), addr, size) }))
+}
@@ -23,6 +23,8 @@ pub struct AlignedBytes {
 }
 
 unsafe impl Send for AlignedBytes {}
+// SAFETY: the bytes are never mutated after creation
+unsafe impl Sync for AlignedBytes {}
 
 impl AlignedBytes {
     /// Create a new `AlignedBytes` instance from a slice
@@ -64,6 +66,15 @@ impl AlignedBytes {
     }
 }
 
+impl Clone for AlignedBytes {
+    fn clone(&self) -> Self {
+        let align = self.layout.map(|l| l.align()).unwrap_or(1);
+
+        // SAFETY: the layout this was made with already proved align is valid
+        unsafe { Self::new(self, align).unwrap_unchecked() }
+    }
+}
+
 impl Deref for AlignedBytes {
     type Target = [u8];
 
@@ -1,28 +1,430 @@
+mod anchored;
 #[cfg(target_arch = "x86_64")]
 mod avx2;
 mod scalar;
 #[cfg(target_arch = "x86_64")]
 mod sse42;
 
+use std::{
+    borrow::Borrow,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc, Condvar, Mutex, OnceLock, PoisonError,
+    },
+    thread,
+};
+
 use super::{pattern::Pattern, Scan};
 
+pub(crate) use self::anchored::rarest_byte;
+
+/// The signature of every backend's `find`
+type FindFn = unsafe fn(&Pattern, *const u8, usize) -> Option<Scan>;
+
+/// Find the first match, resolved to the pattern's result marker
+///
 /// # Safety
 /// address must be valid for up to size reads
 pub(crate) unsafe fn find(pattern: &Pattern, ptr: *const u8, size: usize) -> Option<Scan> {
-    #[cfg(target_arch = "x86_64")]
-    {
-        let avx2 = is_x86_feature_detected!("avx2");
-        let sse42 = is_x86_feature_detected!("sse4.2");
+    // SAFETY: safe to call as long as the safety conditions were met for this function
+    let scan = unsafe { find_start(pattern, ptr, size)? };
+    // SAFETY: the match is inside the region the caller guaranteed is readable
+    Some(unsafe { pattern.resolve(scan) })
+}
+
+/// Chunks smaller than this aren't worth sending to another thread
+const MIN_CHUNK_SIZE: usize = 1024 * 1024;
+/// Regions smaller than this are scanned on the calling thread, since they'd only be a chunk or two
+const MIN_PARALLEL_SIZE: usize = 4 * MIN_CHUNK_SIZE;
+/// More chunks than threads keeps all threads busy, and lets work after a found match be skipped
+const CHUNKS_PER_THREAD: usize = 4;
+
+/// Find the first match by splitting the region into chunks and scanning them on multiple threads.
+/// The result is always the same as [`find`].
+///
+/// # Safety
+/// address must be valid for up to size reads
+pub(crate) unsafe fn find_parallel(pattern: &Pattern, ptr: *const u8, size: usize) -> Option<Scan> {
+    let pool = Pool::get();
+    // the calling thread scans chunks too
+    let threads = pool.workers + 1;
+
+    if pool.workers == 0 || size < MIN_PARALLEL_SIZE {
+        // SAFETY: safe to call as long as the safety conditions were met for this function
+        return unsafe { find(pattern, ptr, size) };
+    }
+
+    let chunk_size = size
+        .div_ceil(threads * CHUNKS_PER_THREAD)
+        .max(MIN_CHUNK_SIZE);
+    let chunks = size.div_ceil(chunk_size);
+
+    let job = Arc::new(Job {
+        pattern: pattern.clone(),
+        base: ptr as usize,
+        size,
+        chunk_size,
+        chunks,
+        next_chunk: AtomicUsize::new(0),
+        first_chunk: AtomicUsize::new(usize::MAX),
+        results: Mutex::new(vec![None; chunks]),
+        state: Mutex::new(JobState::default()),
+        idle: Condvar::new(),
+    });
+
+    pool.submit(&job);
+
+    // SAFETY: the caller guaranteed the region is valid, and it is until this call returns
+    unsafe { job.run() };
+    job.close();
+
+    // chunks are in address order, and every chunk before the first chunk with a match was scanned,
+    // so this is always the same match a single threaded scan finds
+    let addr = job
+        .results
+        .lock()
+        .unwrap()
+        .iter()
+        .flatten()
+        .next()
+        .copied()?;
+    let scan = Scan {
+        addr: addr as *const u8,
+    };
+
+    // SAFETY: the match is inside the region the caller guaranteed is readable
+    Some(unsafe { pattern.resolve(scan) })
+}
+
+/// Worker threads shared by every parallel scan, started on the first one
+struct Pool {
+    sender: Mutex<mpsc::Sender<Arc<Job>>>,
+    workers: usize,
+}
+
+impl Pool {
+    fn get() -> &'static Self {
+        static POOL: OnceLock<Pool> = OnceLock::new();
+
+        POOL.get_or_init(|| {
+            let threads = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+
+            let (sender, receiver) = mpsc::channel::<Arc<Job>>();
+            let receiver = Arc::new(Mutex::new(receiver));
+
+            let mut workers = 0;
+            for i in 1..threads {
+                let receiver = receiver.clone();
+
+                let spawned = thread::Builder::new()
+                    .name(format!("scan worker {i}"))
+                    .spawn(move || loop {
+                        let Ok(job) = receiver.lock().unwrap().recv() else {
+                            break;
+                        };
+
+                        job.work();
+                    });
+
+                // scans still work with fewer workers, the calling thread scans too
+                if spawned.is_ok() {
+                    workers += 1;
+                }
+            }
+
+            Self {
+                sender: Mutex::new(sender),
+                workers,
+            }
+        })
+    }
+
+    /// Hand the job to every worker
+    fn submit(&self, job: &Arc<Job>) {
+        let sender = self.sender.lock().unwrap();
+
+        for _ in 0..self.workers {
+            // workers never stop, so the receiver is always alive
+            _ = sender.send(job.clone());
+        }
+    }
+}
+
+#[derive(Default)]
+struct JobState {
+    // workers currently scanning the region
+    active: usize,
+    // the region may not be valid anymore, so workers which pick up the job now have to skip it
+    closed: bool,
+}
+
+/// A region split into chunks, which the calling thread and the workers take until none are left
+struct Job {
+    pattern: Pattern,
+    base: usize,
+    size: usize,
+    chunk_size: usize,
+    chunks: usize,
+    next_chunk: AtomicUsize,
+    // the lowest chunk index which had a match
+    first_chunk: AtomicUsize,
+    results: Mutex<Vec<Option<usize>>>,
+    state: Mutex<JobState>,
+    // notified when the last active worker is done
+    idle: Condvar,
+}
+
+impl Job {
+    /// Scan chunks on a worker, unless the job was already closed
+    fn work(&self) {
+        /// Marks the worker as done even if scanning panics, so [`Job::close`] doesn't wait forever
+        struct Active<'a>(&'a Job);
+
+        impl Drop for Active<'_> {
+            fn drop(&mut self) {
+                let mut state = self.0.state.lock().unwrap_or_else(PoisonError::into_inner);
+                state.active -= 1;
+                if state.active == 0 {
+                    self.0.idle.notify_all();
+                }
+            }
+        }
+
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.closed {
+                return;
+            }
+
+            state.active += 1;
+        }
+
+        let _active = Active(self);
+
+        // SAFETY: the region stays valid until the job is closed, which waits for active workers
+        unsafe { self.run() };
+    }
+
+    /// Keep scanning chunks until there are none left
+    ///
+    /// # Safety
+    /// the region must be valid for reads
+    unsafe fn run(&self) {
+        // a match starting at the end of a chunk continues into the next chunk
+        let overlap = self.pattern.unpadded_size.saturating_sub(1);
+
+        loop {
+            let chunk = self.next_chunk.fetch_add(1, Ordering::Relaxed);
+
+            // a match in a later chunk can never be the first match
+            if chunk >= self.chunks || chunk > self.first_chunk.load(Ordering::Relaxed) {
+                break;
+            }
+
+            let start = chunk * self.chunk_size;
+            let len = (self.chunk_size + overlap).min(self.size - start);
+
+            // SAFETY: start + len never exceeds size, which the caller guaranteed is valid
+            let scan = unsafe { find_start(&self.pattern, (self.base + start) as *const u8, len) };
 
-        if avx2 {
-            // SAFETY: safe to call as long as the safety conditions were met for this function
-            return unsafe { avx2::find(pattern, ptr, size) };
-        } else if sse42 {
-            // SAFETY: safe to call as long as the safety conditions were met for this function
-            return unsafe { sse42::find(pattern, ptr, size) };
+            if let Some(scan) = scan {
+                self.results.lock().unwrap()[chunk] = Some(scan.addr as usize);
+                self.first_chunk.fetch_min(chunk, Ordering::Relaxed);
+            }
         }
     }
 
+    /// Stop workers from starting on the job, and wait for the ones already scanning
+    fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+
+        while state.active > 0 {
+            state = self.idle.wait(state).unwrap();
+        }
+    }
+}
+
+/// A pattern scanning implementation
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Backend {
+    /// Portable byte by byte scanning
+    Scalar,
+    /// SSE4.2 scanning, 16 bytes at a time
+    Sse42,
+    /// AVX2 scanning, 32 bytes at a time
+    Avx2,
+    /// Searches for the pattern's rarest fully known byte with memchr,
+    /// and only compares the whole pattern where that byte is found
+    Anchored,
+}
+
+impl Backend {
+    /// Every backend
+    pub const ALL: [Self; 4] = [Self::Scalar, Self::Sse42, Self::Avx2, Self::Anchored];
+
+    /// The fastest backend the currently running CPU supports for comparing every offset
+    pub fn detect() -> Self {
+        [Self::Avx2, Self::Sse42]
+            .into_iter()
+            .find(|backend| backend.is_supported())
+            .unwrap_or(Self::Scalar)
+    }
+
+    /// The backend expected to be the fastest for this pattern
+    pub fn select(pattern: &Pattern) -> Self {
+        if anchored::is_preferred(pattern) {
+            Self::Anchored
+        } else {
+            Self::detect()
+        }
+    }
+
+    /// Whether the currently running CPU supports this backend
+    pub fn is_supported(self) -> bool {
+        match self {
+            Self::Scalar | Self::Anchored => true,
+            #[cfg(target_arch = "x86_64")]
+            Self::Sse42 => is_x86_feature_detected!("sse4.2"),
+            #[cfg(target_arch = "x86_64")]
+            Self::Avx2 => is_x86_feature_detected!("avx2"),
+            #[cfg(not(target_arch = "x86_64"))]
+            _ => false,
+        }
+    }
+
+    /// The backend's `find`. Only safe to call if the backend is supported
+    pub(crate) fn find_fn(self) -> FindFn {
+        match self {
+            Self::Scalar => scalar::find,
+            Self::Anchored => anchored::find,
+            #[cfg(target_arch = "x86_64")]
+            Self::Sse42 => sse42::find,
+            #[cfg(target_arch = "x86_64")]
+            Self::Avx2 => avx2::find,
+            #[cfg(not(target_arch = "x86_64"))]
+            _ => scalar::find,
+        }
+    }
+}
+
+/// The number of offsets a pattern can start at while still fully fitting inside the binary
+fn start_count(pattern: &Pattern, size: usize) -> usize {
+    // an empty pattern matches at every offset
+    size.saturating_sub(pattern.unpadded_size.saturating_sub(1))
+}
+
+/// Find the start of the first match
+///
+/// # Safety
+/// address must be valid for up to size reads
+unsafe fn find_start(pattern: &Pattern, ptr: *const u8, size: usize) -> Option<Scan> {
+    // SAFETY: safe to call as long as the safety conditions were met for this function,
+    //         and the selected backend is always supported
+    unsafe { (Backend::select(pattern).find_fn())(pattern, ptr, size) }
+}
+
+/// Find the first match using a specific backend, resolved to the pattern's result marker
+///
+/// # Safety
+/// address must be valid for up to size reads, and the backend must be supported
+pub(crate) unsafe fn find_with(
+    backend: Backend,
+    pattern: &Pattern,
+    ptr: *const u8,
+    size: usize,
+) -> Option<Scan> {
     // SAFETY: safe to call as long as the safety conditions were met for this function
-    unsafe { scalar::find(pattern, ptr, size) }
+    let scan = unsafe { (backend.find_fn())(pattern, ptr, size)? };
+    // SAFETY: the match is inside the region the caller guaranteed is readable
+    Some(unsafe { pattern.resolve(scan) })
+}
+
+/// # Safety
+/// address must be valid for up to size reads for as long as the returned iterator is alive
+pub(crate) unsafe fn find_all<P: Borrow<Pattern>>(
+    pattern: P,
+    ptr: *const u8,
+    size: usize,
+) -> Matches<P> {
+    let backend = Backend::select(pattern.borrow());
+    // SAFETY: safe to call as long as the safety conditions were met for this function
+    unsafe { find_all_with(backend, pattern, ptr, size) }
+}
+
+/// # Safety
+/// address must be valid for up to size reads for as long as the returned iterator is alive,
+/// and the backend must be supported
+pub(crate) unsafe fn find_all_with<P: Borrow<Pattern>>(
+    backend: Backend,
+    pattern: P,
+    ptr: *const u8,
+    size: usize,
+) -> Matches<P> {
+    // SAFETY: safe to call as long as the safety conditions were met for this function
+    unsafe { Matches::new(pattern, ptr, size, backend.find_fn()) }
+}
+
+/// An iterator over every occurrence of a pattern in the binary, resolved to the pattern's result marker.
+///
+/// Matches are yielded in ascending address order, and may overlap each other.
+pub(crate) struct Matches<P> {
+    pattern: P,
+    ptr: *const u8,
+    size: usize,
+    // offset from ptr where the next search starts
+    offset: usize,
+    find: FindFn,
+}
+
+unsafe impl<P: Send> Send for Matches<P> {}
+unsafe impl<P: Sync> Sync for Matches<P> {}
+
+impl<P> Matches<P> {
+    /// # Safety
+    ///
+    /// * `ptr` - is valid for `size` reads for as long as the iterator is alive
+    ///
+    /// * `find` - is supported by the currently running CPU
+    unsafe fn new(pattern: P, ptr: *const u8, size: usize, find: FindFn) -> Self {
+        Self {
+            pattern,
+            ptr,
+            size,
+            offset: 0,
+            find,
+        }
+    }
+}
+
+impl<P: Borrow<Pattern>> Iterator for Matches<P> {
+    type Item = Scan;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.offset >= self.size {
+            return None;
+        }
+
+        // SAFETY: the creator of this iterator guaranteed ptr is valid for size reads,
+        //         and offset is always less than size here
+        let scan = unsafe {
+            (self.find)(
+                self.pattern.borrow(),
+                self.ptr.add(self.offset),
+                self.size - self.offset,
+            )
+        };
+
+        let Some(scan) = scan else {
+            self.offset = self.size;
+            return None;
+        };
+
+        // continue one byte past the match, so overlapping matches are found too
+        self.offset = scan.addr as usize - self.ptr as usize + 1;
+
+        // SAFETY: the match is inside the region the creator of this iterator guaranteed is readable
+        Some(unsafe { self.pattern.borrow().resolve(scan) })
+    }
 }
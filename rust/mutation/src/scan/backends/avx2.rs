@@ -1,12 +1,15 @@
 //! AVX2 pattern scanning backend
 
 use std::arch::x86_64::{
-    _mm256_blendv_epi8, _mm256_cmpeq_epi8, _mm256_load_si256, _mm256_loadu_si256,
-    _mm256_movemask_epi8, _mm256_set1_epi8,
+    __m256i, _mm256_and_si256, _mm256_cmpeq_epi8, _mm256_load_si256, _mm256_loadu_si256,
+    _mm256_movemask_epi8,
 };
 
+use super::start_count;
 use crate::scan::{pattern::Pattern, Scan};
 
+const UNIT_SIZE: usize = 32;
+
 /// Find the first occurrence of a pattern in the binary
 /// using AVX2 instructions
 ///
@@ -18,50 +21,66 @@ use crate::scan::{pattern::Pattern, Scan};
 ///
 /// * Currently running CPU supports AVX2
 #[target_feature(enable = "avx2")]
-pub unsafe fn find(pattern_data: &Pattern, ptr: *const u8, size: usize) -> Option<Scan> {
-    const UNIT_SIZE: usize = 32;
-
-    let mut processed_size = 0;
-
-    let data_base = pattern_data.data.as_ptr();
-    let mask_base = pattern_data.mask.as_ptr();
-
-    // SAFETY: this function is only called if the CPU supports AVX2
-
-    let mut pattern = unsafe { _mm256_load_si256(data_base.cast()) };
-    let mut mask = unsafe { _mm256_load_si256(mask_base.cast()) };
-    let all_zeros = unsafe { _mm256_set1_epi8(0x00) };
-
-    let mut chunk = 0;
-    while chunk < size {
-        let chunk_data = unsafe { _mm256_loadu_si256(ptr.add(chunk).cast()) };
-
-        let blend = unsafe { _mm256_blendv_epi8(all_zeros, chunk_data, mask) };
-        let eq = unsafe { _mm256_cmpeq_epi8(pattern, blend) };
-
-        if unsafe { _mm256_movemask_epi8(eq) as u32 == 0xffffffff } {
-            processed_size += UNIT_SIZE;
-
-            if processed_size < pattern_data.unpadded_size {
-                chunk += UNIT_SIZE - 1;
-
-                pattern = unsafe { _mm256_load_si256(data_base.add(processed_size).cast()) };
-                mask = unsafe { _mm256_load_si256(mask_base.add(processed_size).cast()) };
-            } else {
-                let addr = unsafe { ptr.add(chunk).sub(processed_size).add(UNIT_SIZE) };
-
-                let scan = Scan { addr };
-
-                return Some(scan);
+pub unsafe fn find(pattern: &Pattern, ptr: *const u8, size: usize) -> Option<Scan> {
+    let data_base = pattern.data.as_ptr();
+    let mask_base = pattern.mask.as_ptr();
+
+    // units past the end of the pattern are fully masked out, so they're skipped
+    let units = pattern.unpadded_size.div_ceil(UNIT_SIZE);
+
+    'start: for start in 0..start_count(pattern, size) {
+        for unit in 0..units {
+            let offset = start + unit * UNIT_SIZE;
+
+            // SAFETY: every unit begins before the end of the match, which never passes size
+            let chunk_data = unsafe { load(ptr.add(offset), size - offset) };
+
+            // SAFETY: the pattern data and mask are aligned and padded to a multiple of the unit size
+            let (data, mask) = unsafe {
+                (
+                    _mm256_load_si256(data_base.add(unit * UNIT_SIZE).cast()),
+                    _mm256_load_si256(mask_base.add(unit * UNIT_SIZE).cast()),
+                )
+            };
+
+            // pattern data is pre-masked, so masking out the unknown bits is enough to compare
+            let masked = unsafe { _mm256_and_si256(chunk_data, mask) };
+            let eq = unsafe { _mm256_cmpeq_epi8(data, masked) };
+
+            if unsafe { _mm256_movemask_epi8(eq) as u32 != 0xffffffff } {
+                continue 'start;
             }
-        } else {
-            pattern = unsafe { _mm256_load_si256(data_base.cast()) };
-            mask = unsafe { _mm256_load_si256(mask_base.cast()) };
-            processed_size = 0;
         }
 
-        chunk += 1;
+        // SAFETY: start is always inside the binary
+        let addr = unsafe { ptr.add(start) };
+
+        return Some(Scan { addr });
     }
 
     None
 }
+
+/// Load a unit from `ptr` without reading past `available` bytes.
+/// Bytes past the end of the binary are zeroed, they're always masked out by the pattern padding.
+///
+/// # Safety
+///
+/// * `ptr` - is valid for `available` reads
+///
+/// * Currently running CPU supports AVX2
+#[target_feature(enable = "avx2")]
+unsafe fn load(ptr: *const u8, available: usize) -> __m256i {
+    if available >= UNIT_SIZE {
+        // SAFETY: there's at least a whole unit left to read
+        unsafe { _mm256_loadu_si256(ptr.cast()) }
+    } else {
+        let mut buf = [0u8; UNIT_SIZE];
+
+        // SAFETY: the caller guaranteed ptr is valid for available reads, which fits in buf
+        unsafe {
+            ptr.copy_to_nonoverlapping(buf.as_mut_ptr(), available);
+            _mm256_loadu_si256(buf.as_ptr().cast())
+        }
+    }
+}
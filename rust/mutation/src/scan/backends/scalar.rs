@@ -1,5 +1,6 @@
 //! Scalar pattern scanning backend
 
+use super::start_count;
 use crate::scan::{pattern::Pattern, Scan};
 
 /// Find the first occurrence of a pattern in the binary
@@ -11,29 +12,24 @@ use crate::scan::{pattern::Pattern, Scan};
 ///
 /// * `size` - corresponds to a valid size of `binary`
 pub unsafe fn find(pattern: &Pattern, ptr: *const u8, size: usize) -> Option<Scan> {
-    // SAFETY: safe to call as the pointer will be exactly one byte past the end of the binary
-    let binary_end = unsafe { ptr.add(size) };
-
-    for binary_offset in 0..size {
-        let mut found = true;
-
-        for pattern_offset in 0..pattern.data.len() {
-            if pattern.mask[pattern_offset] == 0x00 {
-                continue;
-            }
-
-            // SAFETY: safe to call as further behavior doesn't rely on overflows
-            // further reads from this address are safe because of the min call
-            // ensuring the pointer is always in bounds
-            let checked_addr =
-                unsafe { ptr.add(binary_offset).add(pattern_offset).min(binary_end) };
-
-            // SAFETY: checked addr is always in binary bounds
-            if unsafe { checked_addr.read_volatile() } != pattern.data[pattern_offset] {
-                found = false;
-                break;
-            }
-        }
+    let data = &pattern.data[..pattern.unpadded_size];
+    let mask = &pattern.mask[..pattern.unpadded_size];
+
+    for binary_offset in 0..start_count(pattern, size) {
+        let found = data
+            .iter()
+            .zip(mask)
+            .enumerate()
+            .all(|(pattern_offset, (&data, &mask))| {
+                if mask == 0x00 {
+                    return true;
+                }
+
+                // SAFETY: the pattern always fits in the binary at every start offset
+                // pattern data is pre-masked, so only the known bits need comparing
+                let byte = unsafe { ptr.add(binary_offset + pattern_offset).read_volatile() };
+                byte & mask == data
+            });
 
         if found {
             // SAFETY: safe to call because binary offset never gets out of binary+binary_size space
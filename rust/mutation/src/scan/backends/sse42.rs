@@ -1,12 +1,14 @@
 //! SSE4.2 pattern scanning backend
 
 use std::arch::x86_64::{
-    _mm_blendv_epi8, _mm_cmpeq_epi8, _mm_load_si128, _mm_loadu_si128, _mm_movemask_epi8,
-    _mm_set1_epi8,
+    __m128i, _mm_and_si128, _mm_cmpeq_epi8, _mm_load_si128, _mm_loadu_si128, _mm_movemask_epi8,
 };
 
+use super::start_count;
 use crate::scan::{pattern::Pattern, Scan};
 
+const UNIT_SIZE: usize = 16;
+
 /// Find the first occurrence of a pattern in the binary
 /// using SSE4.2 instructions
 ///
@@ -18,52 +20,66 @@ use crate::scan::{pattern::Pattern, Scan};
 ///
 /// * Currently running CPU supports SSE4.2
 #[target_feature(enable = "sse4.2")]
-pub unsafe fn find(pattern_data: &Pattern, ptr: *const u8, size: usize) -> Option<Scan> {
-    const UNIT_SIZE: usize = 16;
-
-    let mut processed_size = 0;
-
-    // SAFETY: this function is only called if the CPU supports SSE4.2
-
-    let mut pattern = unsafe { _mm_load_si128(pattern_data.data.as_ptr().cast()) };
-    let mut mask = unsafe { _mm_load_si128(pattern_data.mask.as_ptr().cast()) };
-    let all_zeros = unsafe { _mm_set1_epi8(0x00) };
-
-    let mut chunk = 0;
-
-    while chunk < size {
-        let chunk_data = unsafe { _mm_loadu_si128(ptr.add(chunk).cast()) };
-        let blend = unsafe { _mm_blendv_epi8(all_zeros, chunk_data, mask) };
-        let eq = unsafe { _mm_cmpeq_epi8(pattern, blend) };
-
-        if unsafe { _mm_movemask_epi8(eq) == 0xffff } {
-            processed_size += UNIT_SIZE;
-
-            if processed_size < pattern_data.unpadded_size {
-                chunk += UNIT_SIZE - 1;
-
-                pattern = unsafe {
-                    _mm_load_si128(pattern_data.data.as_ptr().add(processed_size).cast())
-                };
-
-                mask = unsafe {
-                    _mm_load_si128(pattern_data.mask.as_ptr().add(processed_size).cast())
-                };
-            } else {
-                let addr = unsafe { ptr.add(chunk).sub(processed_size).add(UNIT_SIZE) };
-
-                let scan = Scan { addr };
-
-                return Some(scan);
+pub unsafe fn find(pattern: &Pattern, ptr: *const u8, size: usize) -> Option<Scan> {
+    let data_base = pattern.data.as_ptr();
+    let mask_base = pattern.mask.as_ptr();
+
+    // units past the end of the pattern are fully masked out, so they're skipped
+    let units = pattern.unpadded_size.div_ceil(UNIT_SIZE);
+
+    'start: for start in 0..start_count(pattern, size) {
+        for unit in 0..units {
+            let offset = start + unit * UNIT_SIZE;
+
+            // SAFETY: every unit begins before the end of the match, which never passes size
+            let chunk_data = unsafe { load(ptr.add(offset), size - offset) };
+
+            // SAFETY: the pattern data and mask are aligned and padded to a multiple of the unit size
+            let (data, mask) = unsafe {
+                (
+                    _mm_load_si128(data_base.add(unit * UNIT_SIZE).cast()),
+                    _mm_load_si128(mask_base.add(unit * UNIT_SIZE).cast()),
+                )
+            };
+
+            // pattern data is pre-masked, so masking out the unknown bits is enough to compare
+            let masked = unsafe { _mm_and_si128(chunk_data, mask) };
+            let eq = unsafe { _mm_cmpeq_epi8(data, masked) };
+
+            if unsafe { _mm_movemask_epi8(eq) != 0xffff } {
+                continue 'start;
             }
-        } else {
-            pattern = unsafe { _mm_load_si128(pattern_data.data.as_ptr().cast()) };
-            mask = unsafe { _mm_load_si128(pattern_data.mask.as_ptr().cast()) };
-            processed_size = 0;
         }
 
-        chunk += 1;
+        // SAFETY: start is always inside the binary
+        let addr = unsafe { ptr.add(start) };
+
+        return Some(Scan { addr });
     }
 
     None
 }
+
+/// Load a unit from `ptr` without reading past `available` bytes.
+/// Bytes past the end of the binary are zeroed, they're always masked out by the pattern padding.
+///
+/// # Safety
+///
+/// * `ptr` - is valid for `available` reads
+///
+/// * Currently running CPU supports SSE4.2
+#[target_feature(enable = "sse4.2")]
+unsafe fn load(ptr: *const u8, available: usize) -> __m128i {
+    if available >= UNIT_SIZE {
+        // SAFETY: there's at least a whole unit left to read
+        unsafe { _mm_loadu_si128(ptr.cast()) }
+    } else {
+        let mut buf = [0u8; UNIT_SIZE];
+
+        // SAFETY: the caller guaranteed ptr is valid for available reads, which fits in buf
+        unsafe {
+            ptr.copy_to_nonoverlapping(buf.as_mut_ptr(), available);
+            _mm_loadu_si128(buf.as_ptr().cast())
+        }
+    }
+}
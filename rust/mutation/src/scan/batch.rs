@@ -0,0 +1,346 @@
+//! Scan a region once for many patterns at the same time
+
+use std::{collections::HashMap, slice};
+
+use aho_corasick::{AhoCorasick, AhoCorasickKind};
+
+use super::{backends::Backend, pattern::Pattern, Scan};
+
+/// The longest run of fully known bytes in the pattern, as (offset, bytes)
+fn literal(pattern: &Pattern) -> Option<(usize, &[u8])> {
+    let len = pattern.unpadded_size;
+    let mut best: Option<(usize, usize)> = None;
+    let mut offset = 0;
+
+    while offset < len {
+        let run = pattern.mask[offset..len]
+            .iter()
+            .take_while(|&&mask| mask == 0xFF)
+            .count();
+
+        if run > best.map_or(0, |(_, best)| best) {
+            best = Some((offset, run));
+        }
+
+        offset += run + 1;
+    }
+
+    best.map(|(offset, run)| (offset, &pattern.data[offset..offset + run]))
+}
+
+/// How many bytes every pattern is scanned for at a time, small enough to stay in the cache
+const BLOCK_SIZE: usize = 256 * 1024;
+
+/// With this many runs or more, searching for all of them at once with Aho-Corasick beats scanning
+/// for each pattern on its own.
+///
+/// Measured with the `batch` group of `benches/scan.rs` on an Intel Xeon virtual machine,
+/// Aho-Corasick walks 16 MiB in about 40ms no matter how many runs there are, while each pattern
+/// scanned on its own with a rare anchor takes about 1.5ms. Patterns which need the simd backends
+/// take much longer each
+const AHO_CORASICK_MIN_RUNS: usize = 24;
+
+/// Walk the binary once, calling `cb` with the pattern index and start of every match.
+/// Matches of the same pattern are reported in ascending order.
+///
+/// A few patterns are each scanned for with their own [`Backend`], a block at a time. With many
+/// patterns, the longest run of fully known bytes of every pattern is searched for at the same time
+/// with Aho-Corasick, and the whole pattern is only compared with simd where its run is found.
+///
+/// `cb` returns whether the pattern should be searched for further. Scanning stops once no patterns are left.
+///
+/// # Safety
+/// `addr` must be valid for `size` reads
+unsafe fn scan(
+    patterns: &[&Pattern],
+    addr: *const u8,
+    size: usize,
+    mut cb: impl FnMut(usize, Scan) -> bool,
+) {
+    if addr.is_null() || size == 0 {
+        return;
+    }
+
+    // SAFETY: caller guarantees addr is valid for size reads
+    let binary = unsafe { slice::from_raw_parts(addr, size) };
+
+    // the same run can be in several patterns. run -> (pattern index, offset of the run in it)
+    let mut runs = HashMap::<&[u8], Vec<(usize, usize)>>::new();
+    // patterns with no fully known byte have to be compared at every offset
+    let mut unanchored = Vec::new();
+
+    for (idx, pattern) in patterns.iter().enumerate() {
+        match literal(pattern) {
+            Some((offset, run)) => runs.entry(run).or_default().push((idx, offset)),
+            None => unanchored.push(idx),
+        }
+    }
+
+    let (runs, owners): (Vec<_>, Vec<_>) = runs.into_iter().unzip();
+
+    let mut done = vec![false; patterns.len()];
+    let mut remaining = patterns.len();
+
+    let mut found = |idx: usize, start: usize, done: &mut [bool], remaining: &mut usize| {
+        // SAFETY: start is within the binary
+        let scan = Scan {
+            addr: unsafe { addr.add(start) },
+        };
+
+        if !cb(idx, scan) {
+            done[idx] = true;
+            *remaining -= 1;
+        }
+    };
+
+    // patterns which are scanned for on their own
+    let separate = if runs.len() >= AHO_CORASICK_MIN_RUNS {
+        // given exactly a pattern's length, this compares the pattern at that one offset
+        let verify = Backend::detect().find_fn();
+
+        // the runs are plain bytes, so building never fails
+        let searcher = AhoCorasick::builder()
+            .kind(Some(AhoCorasickKind::DFA))
+            .build(&runs)
+            .unwrap();
+
+        // runs of the same pattern are found in ascending order, so its matches are too
+        for run in searcher.find_overlapping_iter(binary) {
+            for &(idx, offset) in &owners[run.pattern().as_usize()] {
+                let len = patterns[idx].unpadded_size;
+
+                if done[idx] || run.start() < offset || run.start() - offset + len > size {
+                    continue;
+                }
+
+                let start = run.start() - offset;
+
+                // SAFETY: the pattern fits inside the binary at start, and the detected backend is
+                //         always supported
+                if unsafe { verify(patterns[idx], addr.add(start), len) }.is_some() {
+                    found(idx, start, &mut done, &mut remaining);
+                }
+            }
+
+            if remaining == 0 {
+                return;
+            }
+        }
+
+        unanchored
+    } else {
+        (0..patterns.len()).collect()
+    };
+
+    let finds = separate
+        .iter()
+        .map(|&idx| Backend::select(patterns[idx]).find_fn())
+        .collect::<Vec<_>>();
+
+    // the offset each pattern's next search starts at
+    let mut next = vec![0; patterns.len()];
+
+    // every pattern is scanned for in a block before moving on, so the block stays cached
+    for block_start in (0..size).step_by(BLOCK_SIZE) {
+        let block_end = (block_start + BLOCK_SIZE).min(size);
+
+        for (&idx, find) in separate.iter().zip(&finds) {
+            let pattern = patterns[idx];
+            // matches which start in this block can continue into the next one
+            let end = (block_end + pattern.unpadded_size.saturating_sub(1)).min(size);
+
+            while !done[idx] && next[idx] < block_end {
+                let from = next[idx];
+
+                // SAFETY: from..end is inside the binary, and the selected backend is
+                //         always supported
+                let Some(scan) = (unsafe { find(pattern, addr.add(from), end - from) }) else {
+                    next[idx] = block_end;
+                    break;
+                };
+
+                let start = scan.addr as usize - addr as usize;
+                next[idx] = start + 1;
+
+                found(idx, start, &mut done, &mut remaining);
+            }
+        }
+
+        if remaining == 0 {
+            return;
+        }
+    }
+}
+
+/// Scan a region once for many named patterns, returning the first match of each.
+/// Names which were not found are not in the returned map.
+///
+/// Each match is resolved to the pattern's result marker, the same as [`Pattern::scan`].
+///
+/// # Safety
+///
+/// * `addr` - is a valid pointer
+///
+/// * `size` - corresponds to a valid size of `binary`
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let patterns = [
+///     ("player", "48 8B 05 [?? ?? ?? ??]".parse()?),
+///     ("update", "E8 [?? ?? ?? ??] 84 C0".parse()?),
+/// ];
+///
+/// let found = unsafe { batch_scan(patterns.iter().map(|(n, p)| (*n, p)), module.base, module.size as _) };
+///
+/// println!("{:?}", found.get("player"));
+/// ```
+pub unsafe fn batch_scan<'a>(
+    patterns: impl IntoIterator<Item = (&'a str, &'a Pattern)>,
+    addr: *const u8,
+    size: usize,
+) -> HashMap<String, Scan> {
+    let (names, patterns): (Vec<_>, Vec<_>) = patterns.into_iter().unzip();
+
+    let mut results = HashMap::new();
+
+    // SAFETY: safe to call as long as the safety conditions were met for this function
+    unsafe {
+        scan(&patterns, addr, size, |idx, scan| {
+            // SAFETY: the match is inside the region the caller guaranteed is readable
+            let scan = patterns[idx].resolve(scan);
+            results.insert(names[idx].to_owned(), scan);
+
+            false
+        });
+    }
+
+    results
+}
+
+/// Scan a region once for many named patterns, returning every match of each.
+/// Names which were not found are not in the returned map.
+///
+/// Each match is resolved to the pattern's result marker, the same as [`Pattern::scan_all`].
+///
+/// # Safety
+///
+/// * `addr` - is a valid pointer
+///
+/// * `size` - corresponds to a valid size of `binary`
+pub unsafe fn batch_scan_all<'a>(
+    patterns: impl IntoIterator<Item = (&'a str, &'a Pattern)>,
+    addr: *const u8,
+    size: usize,
+) -> HashMap<String, Vec<Scan>> {
+    let (names, patterns): (Vec<_>, Vec<_>) = patterns.into_iter().unzip();
+
+    let mut results = HashMap::<String, Vec<Scan>>::new();
+
+    // SAFETY: safe to call as long as the safety conditions were met for this function
+    unsafe {
+        scan(&patterns, addr, size, |idx, scan| {
+            // SAFETY: the match is inside the region the caller guaranteed is readable
+            let scan = patterns[idx].resolve(scan);
+            results.entry(names[idx].to_owned()).or_default().push(scan);
+
+            true
+        });
+    }
+
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn longest_literal() {
+        let run = |pattern: &str| {
+            let pattern = pattern.parse::<Pattern>().unwrap();
+            literal(&pattern).map(|(offset, run)| (offset, run.to_vec()))
+        };
+
+        assert_eq!(run("48 ?? 8B 05 ?? 90"), Some((2, vec![0x8B, 0x05])));
+        assert_eq!(run("48 8B ?? 05 90"), Some((0, vec![0x48, 0x8B])));
+        assert_eq!(run("4? ?? [E8]"), Some((2, vec![0xE8])));
+        assert_eq!(run("?? 4? ??"), None);
+    }
+
+    #[test]
+    fn same_as_separate_scans() {
+        let mut binary = vec![0x90; BLOCK_SIZE + 0x400];
+        binary[0x10..0x16].copy_from_slice(&[0x48, 0x8B, 0x05, 0x11, 0x22, 0x33]);
+        // crosses into the next block
+        binary[BLOCK_SIZE - 2..BLOCK_SIZE + 4]
+            .copy_from_slice(&[0x48, 0x8B, 0x05, 0x44, 0x55, 0x66]);
+        binary[BLOCK_SIZE + 0x200..BLOCK_SIZE + 0x205]
+            .copy_from_slice(&[0xE8, 0x40, 0x41, 0x42, 0x43]);
+        let end = binary.len() - 3;
+        binary[end..].copy_from_slice(&[0x48, 0x8B, 0x05]);
+
+        let mut patterns = [
+            ("load", "48 8B 05 ?? ?? ??"),
+            // the same run as load, at another offset
+            ("load tail", "?? 8B 05 [?? ??]"),
+            ("call", "E8 4? 4? 4? 4?"),
+            // no fully known byte
+            ("nibbles", "4? 4? 4?"),
+            // overlaps itself
+            ("nops", "90 90 90"),
+            ("missing", "CC CC"),
+        ]
+        .map(|(name, pattern)| (name.to_owned(), pattern.parse::<Pattern>().unwrap()))
+        .to_vec();
+
+        let check = |patterns: &[(String, Pattern)]| {
+            let (ptr, size) = (binary.as_ptr(), binary.len());
+            let iter = || {
+                patterns
+                    .iter()
+                    .map(|(name, pattern)| (name.as_str(), pattern))
+            };
+
+            // SAFETY: the binary is valid for its whole length
+            let (first, all) = unsafe {
+                (
+                    batch_scan(iter(), ptr, size),
+                    batch_scan_all(iter(), ptr, size),
+                )
+            };
+
+            for (name, pattern) in patterns {
+                // SAFETY: the binary is valid for its whole length
+                let expected = unsafe { pattern.scan_all(ptr, size) }
+                    .map(|scan| scan.addr)
+                    .collect::<Vec<_>>();
+
+                let found = all.get(name).map_or(Vec::new(), |scans| {
+                    scans.iter().map(|scan| scan.addr).collect()
+                });
+
+                assert_eq!(found, expected, "{name}");
+                assert_eq!(
+                    first.get(name).map(|scan| scan.addr),
+                    expected.first().copied(),
+                    "{name}"
+                );
+            }
+
+            assert!(!all.contains_key("missing"));
+            assert_eq!(all["load"].len(), 2);
+        };
+
+        // each pattern on its own
+        check(&patterns);
+
+        // enough runs for Aho-Corasick
+        patterns.extend((0..AHO_CORASICK_MIN_RUNS).map(|i| {
+            let pattern = format!("CC {i:02X} CC");
+            (pattern.clone(), pattern.parse().unwrap())
+        }));
+
+        check(&patterns);
+    }
+}
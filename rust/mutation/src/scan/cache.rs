@@ -0,0 +1,447 @@
+//! A persistent cache of resolved scans, so unchanged modules don't have to be scanned again
+
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{self, Write as _},
+    ops::Range,
+    path::PathBuf,
+    slice,
+};
+
+use pelite::{
+    image::{IMAGE_REL_BASED_DIR64, IMAGE_REL_BASED_HIGHLOW},
+    pe64::{Pe, PeView},
+};
+
+use super::{signature::code_sections, Scan};
+use crate::modules::Module;
+
+/// An error for the [SigCache]
+#[derive(Debug, thiserror::Error)]
+pub enum CacheError {
+    /// the cache file could not be read or written
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// Identifies a specific build of a module
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Fingerprint {
+    /// the PE timestamp
+    pub timestamp: u32,
+    /// the size of the module in memory
+    pub image_size: u32,
+    /// FNV-1a hash of every executable section, with relocated addresses zeroed
+    pub code_hash: u64,
+}
+
+impl Fingerprint {
+    /// Fingerprint a module. The code is hashed as it currently is in memory,
+    /// so this should be done before the code is patched. Addresses which were
+    /// relocated when the module was loaded are skipped, so the load address doesn't matter
+    pub fn new(module: &Module) -> Self {
+        // this base address is crate private, so it is guaranteed
+        let base = module.handle.base;
+
+        // SAFETY: module field is crate private, it cannot be changed
+        //         and we only support 64-bit. Additionally, each module is backed by
+        //         an increased refcount, which keeps them valid for the duration of Module
+        let view = unsafe { PeView::module(base.cast()) };
+
+        // a module without relocations can only be loaded at its preferred base
+        let mut relocs = Vec::new();
+        if let Ok(base_relocs) = view.base_relocs() {
+            base_relocs.for_each(|rva, ty| {
+                let len = match ty {
+                    IMAGE_REL_BASED_DIR64 => 8,
+                    IMAGE_REL_BASED_HIGHLOW => 4,
+                    _ => 2,
+                };
+
+                relocs.push(rva as usize..rva as usize + len);
+            });
+        }
+
+        relocs.sort_unstable_by_key(|reloc| reloc.start);
+
+        let sections = code_sections(module).into_iter().map(|section| {
+            // SAFETY: code sections are mapped for as long as the module is loaded
+            let code = unsafe { slice::from_raw_parts(section.start as *const u8, section.len()) };
+            (section.start - base as usize, code)
+        });
+
+        Self {
+            timestamp: view.file_header().TimeDateStamp,
+            image_size: view.optional_header().SizeOfImage,
+            code_hash: hash_code(sections, &relocs),
+        }
+    }
+}
+
+/// FNV-1a hash of `(rva, code)` sections. Every byte inside of one of the sorted `relocs`
+/// rva ranges is hashed as 0
+fn hash_code<'a>(
+    sections: impl IntoIterator<Item = (usize, &'a [u8])>,
+    relocs: &[Range<usize>],
+) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+
+    for (rva, code) in sections {
+        let first = relocs.partition_point(|reloc| reloc.end <= rva);
+        let mut relocs = relocs[first..].iter().peekable();
+
+        for (i, &byte) in code.iter().enumerate() {
+            let rva = rva + i;
+            while relocs.next_if(|reloc| reloc.end <= rva).is_some() {}
+
+            let byte = match relocs.peek() {
+                Some(reloc) if reloc.contains(&rva) => 0,
+                _ => byte,
+            };
+
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    hash
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:08x}-{:08x}-{:016x}",
+            self.timestamp, self.image_size, self.code_hash
+        )
+    }
+}
+
+/// The cached scans of a single module
+#[derive(Debug, Default)]
+struct Entry {
+    fingerprint: String,
+    // key -> rva of the resolved scan
+    rvas: HashMap<String, usize>,
+}
+
+/// Resolved scans stored on disk as module relative addresses.
+///
+/// Scans are keyed by the module's name and [`Fingerprint`], so cached results are only used
+/// for the exact same build of the module. Once the module changes, its old results are dropped.
+///
+/// New results are kept in memory until [`SigCache::save`], or until the cache is dropped.
+/// Errors from saving on drop are ignored, so call [`SigCache::save`] to handle them.
+#[derive(Debug)]
+pub struct SigCache {
+    path: PathBuf,
+    // module name -> cached scans
+    entries: HashMap<String, Entry>,
+    // modules already fingerprinted, by base address
+    fingerprints: HashMap<usize, Fingerprint>,
+    // whether there are changes which haven't been saved yet
+    dirty: bool,
+}
+
+impl SigCache {
+    /// Load the cache from a file. If the file doesn't exist yet, the cache starts out empty
+    pub fn load<P: Into<PathBuf>>(path: P) -> Result<Self, CacheError> {
+        let path = path.into();
+
+        let data = match fs::read_to_string(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut entries = HashMap::<String, Entry>::new();
+
+        // each line is `module \t fingerprint \t key \t rva`, with tabs, newlines and backslashes
+        // in the fields escaped. anything malformed is skipped
+        for line in data.lines() {
+            let mut parts = line.split('\t');
+            let (Some(module), Some(fingerprint), Some(key), Some(rva), None) = (
+                parts.next(),
+                parts.next(),
+                parts.next(),
+                parts.next(),
+                parts.next(),
+            ) else {
+                continue;
+            };
+
+            let (Some(module), Some(fingerprint), Some(key), Ok(rva)) = (
+                unescape(module),
+                unescape(fingerprint),
+                unescape(key),
+                usize::from_str_radix(rva, 16),
+            ) else {
+                continue;
+            };
+
+            let entry = entries.entry(module).or_default();
+            entry.fingerprint = fingerprint;
+            entry.rvas.insert(key, rva);
+        }
+
+        let cache = Self {
+            path,
+            entries,
+            fingerprints: HashMap::new(),
+            dirty: false,
+        };
+
+        Ok(cache)
+    }
+
+    /// Write the cache to its file, if anything changed since it was loaded or last saved
+    pub fn save(&mut self) -> Result<(), CacheError> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let mut data = Vec::new();
+
+        for (module, entry) in &self.entries {
+            let module = escape(module);
+            let fingerprint = escape(&entry.fingerprint);
+
+            for (key, rva) in &entry.rvas {
+                let key = escape(key);
+                writeln!(data, "{module}\t{fingerprint}\t{key}\t{rva:x}")?;
+            }
+        }
+
+        fs::write(&self.path, data)?;
+        self.dirty = false;
+
+        Ok(())
+    }
+
+    /// Get the fingerprint of a module. Each module is only hashed once
+    pub fn fingerprint(&mut self, module: &Module) -> Fingerprint {
+        *self
+            .fingerprints
+            .entry(module.base as usize)
+            .or_insert_with(|| Fingerprint::new(module))
+    }
+
+    /// Get the cached rva for `key` in the module, if the module hasn't changed since it was cached
+    pub fn get(&mut self, module: &Module, key: &str) -> Option<usize> {
+        let fingerprint = self.fingerprint(module).to_string();
+
+        let entry = self.entries.get(&module.name)?;
+        if entry.fingerprint != fingerprint {
+            return None;
+        }
+
+        entry.rvas.get(key).copied()
+    }
+
+    /// Cache the rva for `key` in the module. This isn't saved to disk until [`SigCache::save`]
+    pub fn insert(&mut self, module: &Module, key: &str, rva: usize) {
+        let fingerprint = self.fingerprint(module).to_string();
+
+        let entry = self.entries.entry(module.name.clone()).or_default();
+
+        // results for an older build of the module are useless now
+        if entry.fingerprint != fingerprint {
+            entry.fingerprint = fingerprint;
+            entry.rvas.clear();
+        }
+
+        entry.rvas.insert(key.to_owned(), rva);
+        self.dirty = true;
+    }
+
+    /// The key of an IDA-style sig scan of `size` bytes from `addr` in the module
+    pub fn sig_key(module: &Module, pattern: &str, addr: *const u8, size: usize) -> String {
+        let offset = (addr as usize).wrapping_sub(module.base as usize);
+        format!("{pattern}@{offset:x}+{size:x}")
+    }
+
+    /// Single result IDA-style pattern scanner, which uses the cached result
+    /// if the module hasn't changed since the pattern was last found.
+    /// A newly found result isn't saved to disk until [`SigCache::save`]
+    ///
+    /// # Safety
+    ///
+    /// * `addr` - is a valid pointer
+    ///
+    /// * `size` - corresponds to a valid size of `binary`
+    pub unsafe fn sig_scan(
+        &mut self,
+        module: &Module,
+        pattern: &str,
+        addr: *const u8,
+        size: usize,
+    ) -> Option<Scan> {
+        let key = Self::sig_key(module, pattern, addr, size);
+        // SAFETY: safe to call as long as the safety conditions were met for this function
+        self.resolve(module, &key, || unsafe {
+            super::sig_scan(pattern, addr, size)
+        })
+    }
+
+    /// Resolve `key` in the module from the cache, or run `scan` if it's not cached yet.
+    /// A newly found scan inside the module is cached, but isn't saved to disk until [`SigCache::save`]
+    ///
+    /// `key` must identify everything the scan depends on, e.g. the pattern and the scanned range.
+    pub fn resolve(
+        &mut self,
+        module: &Module,
+        key: &str,
+        scan: impl FnOnce() -> Option<Scan>,
+    ) -> Option<Scan> {
+        let base = module.base as usize;
+
+        if let Some(rva) = self.get(module, key) {
+            let scan = Scan {
+                addr: (base + rva) as *const u8,
+            };
+
+            return Some(scan);
+        }
+
+        let scan = scan()?;
+
+        // markers can resolve outside of the module, which can't be cached as an rva
+        if (base..module.end as usize).contains(&(scan.addr as usize)) {
+            self.insert(module, key, scan.addr as usize - base);
+        }
+
+        Some(scan)
+    }
+}
+
+impl Drop for SigCache {
+    fn drop(&mut self) {
+        _ = self.save();
+    }
+}
+
+/// Escape the separators of the cache file, so a field can contain anything
+fn escape(field: &str) -> String {
+    let mut escaped = String::with_capacity(field.len());
+
+    for c in field.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Undo [`escape`]. Returns `None` if the field has an invalid escape
+fn unescape(field: &str) -> Option<String> {
+    let mut unescaped = String::with_capacity(field.len());
+    let mut chars = field.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+
+        let c = match chars.next()? {
+            '\\' => '\\',
+            't' => '\t',
+            'n' => '\n',
+            'r' => '\r',
+            _ => return None,
+        };
+
+        unescaped.push(c);
+    }
+
+    Some(unescaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_round_trip() {
+        for field in [
+            "",
+            "48 8B ?? [E8 ?? ?? ?? ??]",
+            "a\tb\nc\rd",
+            "back\\slash\\t",
+            "\\",
+        ] {
+            let escaped = escape(field);
+            assert!(!escaped.contains(['\t', '\n', '\r']));
+            assert_eq!(unescape(&escaped).as_deref(), Some(field));
+        }
+
+        assert_eq!(unescape("trailing\\"), None);
+        assert_eq!(unescape("\\x"), None);
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = std::env::temp_dir().join(format!("sigcache-test-{}", std::process::id()));
+
+        let key = "key\twith\nseparators\\";
+        let mut cache = SigCache::load(&path).unwrap();
+        cache.entries.insert(
+            "game\t.exe".to_owned(),
+            Entry {
+                fingerprint: "fingerprint".to_owned(),
+                rvas: HashMap::from([(key.to_owned(), 0x1234), ("plain".to_owned(), 0x10)]),
+            },
+        );
+        cache.dirty = true;
+        drop(cache);
+
+        let loaded = SigCache::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let entry = &loaded.entries["game\t.exe"];
+        assert_eq!(entry.fingerprint, "fingerprint");
+        assert_eq!(entry.rvas.len(), 2);
+        assert_eq!(entry.rvas[key], 0x1234);
+        assert_eq!(entry.rvas["plain"], 0x10);
+    }
+
+    #[test]
+    fn code_hash_ignores_load_base() {
+        // mov rax, imm64 ; call [rip+0] ; jmp [rip+0x10] ; then an absolute address.
+        // both addresses are relocated
+        let code = |base: u64| {
+            let mut code = vec![0x48, 0xB8];
+            code.extend_from_slice(&(base + 0x2000).to_le_bytes());
+            code.extend_from_slice(&[0xFF, 0x15, 0, 0, 0, 0, 0xFF, 0x25, 0x10, 0, 0, 0]);
+            code.extend_from_slice(&(base + 0x3000).to_le_bytes());
+            code
+        };
+
+        let relocs = [0x1002..0x100A, 0x1016..0x101E];
+        let hash = |code: &[u8]| hash_code([(0x1000, code)], &relocs);
+
+        let default = code(0x1_4000_0000);
+        let moved = code(0x7FF6_1234_0000);
+        assert_ne!(default, moved);
+        assert_eq!(hash(&default), hash(&moved));
+
+        // anything else which changed is still noticed
+        let mut patched = moved.clone();
+        patched[0x0A] = 0xCC;
+        assert_ne!(hash(&patched), hash(&moved));
+
+        // sections are hashed from wherever they start in the relocations
+        let (first, second) = moved.split_at(0x0C);
+        let (d_first, d_second) = default.split_at(0x0C);
+        assert_eq!(
+            hash_code([(0x1000, first), (0x100C, second)], &relocs),
+            hash_code([(0x1000, d_first), (0x100C, d_second)], &relocs)
+        );
+    }
+}
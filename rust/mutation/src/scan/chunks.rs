@@ -0,0 +1,237 @@
+//! Read whole segments of memory in chunks, skipping whatever isn't readable
+
+use std::{marker::PhantomData, mem, ops::ControlFlow, ptr, slice};
+
+use crate::{
+    memory::{self, Alloc, MemError},
+    segments::Segment,
+    Prot,
+};
+
+/// Tests which scan the whole process run one at a time, otherwise they find each other's buffers
+#[cfg(test)]
+pub(crate) static SCAN_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// Memory is read this many bytes at a time
+pub(crate) const CHUNK_SIZE: usize = 1024 * 1024;
+/// Unreadable memory is skipped one page at a time
+pub(crate) const PAGE_SIZE: usize = 0x1000;
+
+/// Read every segment one chunk at a time, calling `f(address, data, len)` for each readable chunk.
+///
+/// Values starting in a chunk can continue into the next one, so `data` continues up to `overlap`
+/// bytes past the chunk's `len` where possible. Only values starting before `len` belong to the chunk.
+///
+/// Memory can be freed or protected at any time, so only the parts which can't be read are skipped.
+/// Reading stops as soon as `f` breaks.
+pub(crate) fn for_each_chunk(
+    segments: impl IntoIterator<Item = Segment>,
+    overlap: usize,
+    mut f: impl FnMut(usize, &[u8], usize) -> ControlFlow<()>,
+) -> Result<(), MemError> {
+    // the buffer is its own allocation, so its segment can be skipped.
+    // otherwise the copies of memory in it would be found as well
+    let buf_len = CHUNK_SIZE + overlap;
+    let alloc = memory::alloc(buf_len, Prot::RW)?;
+    // SAFETY: the allocation is valid for buf_len bytes, and only used through this slice
+    let buf = unsafe { slice::from_raw_parts_mut(alloc.addr(), buf_len) };
+
+    for segment in segments {
+        if segment.base == alloc.addr() as *const () {
+            continue;
+        }
+
+        let end = segment.end as usize;
+
+        let mut start = segment.base as usize;
+        while start < end {
+            let chunk_end = (start + CHUNK_SIZE).min(end);
+            if read_range(start, chunk_end, end, overlap, buf, &mut f).is_break() {
+                return Ok(());
+            }
+
+            start = chunk_end;
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether a segment is the stack of the thread calling this. A scan's locals live there, so
+/// scanning it finds the scan's own copies of what it's looking for
+pub(crate) fn is_current_stack(segment: &Segment) -> bool {
+    let local = 0u8;
+    let addr = ptr::addr_of!(local).cast::<()>();
+
+    (segment.base..segment.end).contains(&addr)
+}
+
+/// Read start..end, and up to `overlap` bytes past it without going past `limit`
+fn read_range(
+    start: usize,
+    end: usize,
+    limit: usize,
+    overlap: usize,
+    buf: &mut [u8],
+    f: &mut impl FnMut(usize, &[u8], usize) -> ControlFlow<()>,
+) -> ControlFlow<()> {
+    let len = (end + overlap).min(limit) - start;
+
+    if memory::read_bytes_checked(start as _, &mut buf[..len]).is_err() {
+        if end - start > PAGE_SIZE {
+            for page in (start..end).step_by(PAGE_SIZE) {
+                let page_end = (page + PAGE_SIZE).min(end);
+                read_range(page, page_end, limit, overlap, buf, f)?;
+            }
+        } else if limit > end {
+            // the page might be fine, and only the next one is unreadable
+            return read_range(start, end, end, overlap, buf, f);
+        }
+
+        return ControlFlow::Continue(());
+    }
+
+    f(start, &buf[..len], end - start)
+}
+
+/// A growable list for what a scan finds while it runs.
+///
+/// A Vec lives in the heap, which is scanned as well, so the scan would find its own results.
+/// This is its own allocation instead, made after the segments were enumerated, so it's never
+/// scanned. Old allocations are released as it grows, so they don't leave copies behind either
+pub(crate) struct ScanVec<T> {
+    alloc: Option<Alloc>,
+    len: usize,
+    cap: usize,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Copy> ScanVec<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            alloc: None,
+            len: 0,
+            cap: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    pub(crate) fn push(&mut self, item: T) -> Result<(), MemError> {
+        self.extend_from_slice(slice::from_ref(&item))
+    }
+
+    pub(crate) fn extend_from_slice(&mut self, items: &[T]) -> Result<(), MemError> {
+        let len = self.len + items.len();
+
+        if len > self.cap {
+            let cap = len.max(self.cap * 2).max(PAGE_SIZE / mem::size_of::<T>());
+            let alloc = memory::alloc(cap * mem::size_of::<T>(), Prot::RW)?;
+
+            // SAFETY: both allocations are page aligned and valid for at least len items
+            unsafe {
+                ptr::copy_nonoverlapping(self.as_slice().as_ptr(), alloc.addr().cast(), self.len);
+            }
+
+            self.alloc = Some(alloc);
+            self.cap = cap;
+        }
+
+        // SAFETY: the allocation is valid for cap items, and cap >= len
+        unsafe {
+            let end = self.as_mut_ptr().add(self.len);
+            ptr::copy_nonoverlapping(items.as_ptr(), end, items.len());
+        }
+
+        self.len = len;
+
+        Ok(())
+    }
+
+    pub(crate) fn as_slice(&self) -> &[T] {
+        match &self.alloc {
+            // SAFETY: the allocation is page aligned, and the first len items were written
+            Some(alloc) => unsafe { slice::from_raw_parts(alloc.addr().cast(), self.len) },
+            None => &[],
+        }
+    }
+
+    fn as_mut_ptr(&mut self) -> *mut T {
+        self.alloc
+            .as_ref()
+            .map_or(ptr::null_mut(), |alloc| alloc.addr().cast())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(data: &[u8]) -> Segment {
+        let range = data.as_ptr_range();
+
+        Segment {
+            base: range.start.cast(),
+            end: range.end.cast(),
+            size: data.len(),
+            prot: Prot::RW,
+        }
+    }
+
+    #[test]
+    fn chunks_overlap() {
+        let data = (0..CHUNK_SIZE * 2 + 100)
+            .map(|i| i as u8)
+            .collect::<Vec<_>>();
+
+        let mut chunks = Vec::new();
+        for_each_chunk([segment(&data)], 3, |start, chunk, len| {
+            let offset = start - data.as_ptr() as usize;
+            assert_eq!(chunk, &data[offset..offset + chunk.len()]);
+
+            chunks.push((offset, chunk.len(), len));
+            ControlFlow::Continue(())
+        })
+        .unwrap();
+
+        assert_eq!(
+            chunks,
+            [
+                (0, CHUNK_SIZE + 3, CHUNK_SIZE),
+                (CHUNK_SIZE, CHUNK_SIZE + 3, CHUNK_SIZE),
+                (CHUNK_SIZE * 2, 100, 100),
+            ]
+        );
+    }
+
+    #[test]
+    fn break_stops_reading() {
+        let data = vec![0u8; CHUNK_SIZE * 3];
+        let segments = [
+            segment(&data[..CHUNK_SIZE * 2]),
+            segment(&data[CHUNK_SIZE * 2..]),
+        ];
+
+        let mut calls = 0;
+        for_each_chunk(segments, 0, |_, _, _| {
+            calls += 1;
+            ControlFlow::Break(())
+        })
+        .unwrap();
+
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn scan_vec_grows() {
+        let mut vec = ScanVec::new();
+        assert_eq!(vec.as_slice(), &[] as &[usize]);
+
+        for i in 0..PAGE_SIZE {
+            vec.push(i).unwrap();
+        }
+        vec.extend_from_slice(&[1, 2, 3]).unwrap();
+
+        let expected = (0..PAGE_SIZE).chain([1, 2, 3]).collect::<Vec<_>>();
+        assert_eq!(vec.as_slice(), expected);
+    }
+}
@@ -0,0 +1,638 @@
+//! Match sequences of instructions by their mnemonics and operands
+//!
+//! A pattern is a list of instructions separated by `;` or newlines, written like disassembly:
+//! `mov r64, [rip+*]; test r64, r64; jz *`. Each operand can be
+//!
+//! * `*` - any operand
+//! * `reg` - any register, or `reg8`, `reg16`, `reg32`, `reg64` (`r16`, `r32`, `r64`) for a size
+//! * a register name, e.g. `rax`
+//! * `imm` - any immediate, or a number, e.g. `0x10`. relative branch targets are the absolute address
+//! * a memory operand, e.g. `[*]` for any, or `qword ptr [rax+rcx*8+*]` where `*` is any displacement
+//!
+//! Wrapping an operand in parentheses, e.g. `([rip+*])`, captures its value. Mnemonics can use `*`
+//! as a wildcard, and an instruction written without operands matches any operands.
+
+use std::{ops::ControlFlow, str::FromStr};
+
+use capstone::arch::{
+    x86::{X86OpMem, X86OperandType},
+    ArchOperand,
+};
+use capstone::prelude::*;
+use capstone::{Insn, RegIdInt};
+
+use super::sweep;
+use crate::asm::{self, AsmError, Inst};
+
+/// An error for instruction patterns
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum InsnPatternError {
+    /// a part of the pattern could not be parsed
+    #[error("invalid instruction pattern at `{0}`")]
+    Pattern(String),
+    /// the pattern has no instructions
+    #[error("instruction pattern is empty")]
+    Empty,
+    /// disassembly failed
+    #[error(transparent)]
+    Asm(#[from] AsmError),
+}
+
+/// The value of a captured operand
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Capture {
+    /// the name of a register
+    Reg(String),
+    /// an immediate. for relative branches, this is the absolute target
+    Imm(u64),
+    /// the address of a memory operand. rip-relative operands are resolved to the absolute address,
+    /// and any other operand is its displacement
+    Mem(u64),
+}
+
+/// A match of an [`InsnPattern`]
+#[derive(Debug, Clone)]
+pub struct InsnMatch {
+    /// the address of the first matched instruction
+    pub addr: *const u8,
+    /// every matched instruction
+    pub insts: Vec<Inst>,
+    /// the captured operands, in the order they're written in the pattern
+    pub captures: Vec<Capture>,
+}
+
+unsafe impl Send for InsnMatch {}
+unsafe impl Sync for InsnMatch {}
+
+/// A pattern of instructions, see the [module docs](self) for the syntax.
+///
+/// Code is disassembled from start to end, and the pattern matches consecutive instructions.
+/// Unlike a byte signature, it still matches when the compiler picks different registers or encodings.
+#[derive(Debug, Clone)]
+pub struct InsnPattern {
+    insts: Vec<InsnPat>,
+}
+
+#[derive(Debug, Clone)]
+struct InsnPat {
+    mnemonic: String,
+    /// None if the operands aren't checked
+    operands: Option<Vec<OperandPat>>,
+}
+
+#[derive(Debug, Clone)]
+struct OperandPat {
+    capture: bool,
+    kind: OperandKind,
+}
+
+#[derive(Debug, Clone)]
+enum OperandKind {
+    Any,
+    Reg(RegPat),
+    /// None for any immediate
+    Imm(Option<u64>),
+    Mem {
+        size: Option<u8>,
+        /// None for any memory operand
+        mem: Option<MemPat>,
+    },
+}
+
+#[derive(Debug, Clone)]
+enum RegPat {
+    Any,
+    Size(u8),
+    Name(String),
+}
+
+#[derive(Debug, Clone)]
+struct MemPat {
+    base: Option<RegPat>,
+    index: Option<(RegPat, i32)>,
+    /// None for any displacement
+    disp: Option<i64>,
+}
+
+/// An instruction's details which patterns are matched against
+struct Decoded {
+    mnemonic: String,
+    /// (size, operand)
+    operands: Vec<(u8, X86OperandType)>,
+    /// the address of the next instruction, which rip-relative operands are relative to
+    next: u64,
+}
+
+/// A partial match of a pattern
+#[derive(Default)]
+struct Partial {
+    insts: Vec<Inst>,
+    captures: Vec<Capture>,
+}
+
+impl FromStr for InsnPattern {
+    type Err = InsnPatternError;
+
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        let insts = pattern
+            .split([';', '\n'])
+            .map(str::trim)
+            .filter(|inst| !inst.is_empty())
+            .map(parse_inst)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if insts.is_empty() {
+            return Err(InsnPatternError::Empty);
+        }
+
+        Ok(Self { insts })
+    }
+}
+
+impl TryFrom<&str> for InsnPattern {
+    type Error = InsnPatternError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl InsnPattern {
+    /// Find the first match of the pattern in the code at `addr`
+    ///
+    /// # Safety
+    ///
+    /// * `addr` - is a valid pointer
+    ///
+    /// * `size` - corresponds to a valid size of the code
+    pub unsafe fn scan(
+        &self,
+        addr: *const u8,
+        size: usize,
+    ) -> Result<Option<InsnMatch>, InsnPatternError> {
+        // SAFETY: safe to call as long as the safety conditions were met for this function
+        let matches = unsafe { self.scan_all(addr, size, 1)? };
+        Ok(matches.into_iter().next())
+    }
+
+    /// Find up to `limit` matches of the pattern in the code at `addr`, in ascending address order.
+    /// Overlapping matches are all returned.
+    ///
+    /// # Safety
+    ///
+    /// * `addr` - is a valid pointer
+    ///
+    /// * `size` - corresponds to a valid size of the code
+    pub unsafe fn scan_all(
+        &self,
+        addr: *const u8,
+        size: usize,
+        limit: usize,
+    ) -> Result<Vec<InsnMatch>, InsnPatternError> {
+        let mut matches = Vec::new();
+        if limit == 0 {
+            return Ok(matches);
+        }
+
+        let cs = asm::capstone()?;
+        let rip = RegId(arch::x86::X86Reg::X86_REG_RIP as RegIdInt);
+
+        let mut partials = Vec::<Partial>::new();
+
+        let visit = |insn: Option<&Insn>| {
+            // a match can't continue past bytes which don't decode
+            let Some(insn) = insn else {
+                partials.clear();
+                return Ok(ControlFlow::Continue(()));
+            };
+
+            let decoded = Decoded::new(&cs, insn)?;
+
+            // every partial match moves forward by one instruction, and a new one starts here
+            let mut next = Vec::new();
+            for mut partial in partials.drain(..).chain([Partial::default()]) {
+                let inst = &self.insts[partial.insts.len()];
+
+                if !inst.matches(&cs, &decoded, rip, &mut partial.captures) {
+                    continue;
+                }
+
+                partial.insts.push(Inst::new(&cs, insn));
+
+                if partial.insts.len() < self.insts.len() {
+                    next.push(partial);
+                    continue;
+                }
+
+                // partials all move at the same pace, so earlier ones always finish first
+                matches.push(InsnMatch {
+                    addr: partial.insts[0].address,
+                    insts: partial.insts,
+                    captures: partial.captures,
+                });
+
+                if matches.len() >= limit {
+                    return Ok(ControlFlow::Break(()));
+                }
+            }
+
+            partials = next;
+
+            Ok(ControlFlow::Continue(()))
+        };
+
+        // SAFETY: safe to call as long as the safety conditions were met for this function
+        unsafe { sweep::sweep(&cs, addr as usize, size, visit)? };
+
+        Ok(matches)
+    }
+}
+
+/// Find the first match of an instruction pattern in the code at `addr`.
+/// See [`InsnPattern`] for the syntax.
+///
+/// # Safety
+///
+/// * `addr` - is a valid pointer
+///
+/// * `size` - corresponds to a valid size of the code
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let pattern = "mov r64, ([rip+*]); test r64, r64; jz *";
+/// let found = unsafe { insn_scan(pattern, module.base, size)? };
+///
+/// if let Some(found) = found {
+///     println!("{:?} reads {:?}", found.addr, found.captures[0]);
+/// }
+/// ```
+pub unsafe fn insn_scan(
+    pattern: &str,
+    addr: *const u8,
+    size: usize,
+) -> Result<Option<InsnMatch>, InsnPatternError> {
+    let pattern = InsnPattern::from_str(pattern)?;
+    // SAFETY: safe to call as long as the safety conditions were met for this function
+    unsafe { pattern.scan(addr, size) }
+}
+
+impl Decoded {
+    fn new(cs: &Capstone, insn: &Insn) -> Result<Self, AsmError> {
+        let detail = cs.insn_detail(insn)?;
+
+        let operands = detail
+            .arch_detail()
+            .operands()
+            .into_iter()
+            .filter_map(|op| match op {
+                ArchOperand::X86Operand(op) => Some((op.size, op.op_type)),
+                _ => None,
+            })
+            .collect();
+
+        let decoded = Self {
+            mnemonic: insn.mnemonic().unwrap_or_default().to_owned(),
+            operands,
+            next: insn.address() + insn.bytes().len() as u64,
+        };
+
+        Ok(decoded)
+    }
+}
+
+impl InsnPat {
+    /// Check if an instruction matches, and push its captured operands
+    fn matches(
+        &self,
+        cs: &Capstone,
+        decoded: &Decoded,
+        rip: RegId,
+        captures: &mut Vec<Capture>,
+    ) -> bool {
+        if !glob(&self.mnemonic, &decoded.mnemonic) {
+            return false;
+        }
+
+        let Some(operands) = &self.operands else {
+            return true;
+        };
+
+        if operands.len() != decoded.operands.len() {
+            return false;
+        }
+
+        for (pat, (size, op)) in operands.iter().zip(&decoded.operands) {
+            if !pat.kind.matches(cs, *size, op) {
+                return false;
+            }
+
+            if pat.capture {
+                let capture = match op {
+                    X86OperandType::Reg(reg) => cs.reg_name(*reg).map(Capture::Reg),
+                    X86OperandType::Imm(imm) => Some(Capture::Imm(*imm as u64)),
+                    X86OperandType::Mem(mem) if mem.base() == rip => {
+                        Some(Capture::Mem(decoded.next.wrapping_add(mem.disp() as u64)))
+                    }
+                    X86OperandType::Mem(mem) => Some(Capture::Mem(mem.disp() as u64)),
+                    _ => None,
+                };
+
+                let Some(capture) = capture else {
+                    return false;
+                };
+
+                captures.push(capture);
+            }
+        }
+
+        true
+    }
+}
+
+impl OperandKind {
+    fn matches(&self, cs: &Capstone, size: u8, op: &X86OperandType) -> bool {
+        match (self, op) {
+            (Self::Any, _) => true,
+
+            (Self::Reg(pat), X86OperandType::Reg(reg)) => {
+                cs.reg_name(*reg).is_some_and(|name| pat.matches(&name))
+            }
+
+            (Self::Imm(value), X86OperandType::Imm(imm)) => {
+                value.map_or(true, |value| value == *imm as u64)
+            }
+
+            (
+                Self::Mem {
+                    size: pat_size,
+                    mem: pat,
+                },
+                X86OperandType::Mem(mem),
+            ) => {
+                pat_size.map_or(true, |pat_size| pat_size == size)
+                    && pat.as_ref().map_or(true, |pat| pat.matches(cs, mem))
+            }
+
+            _ => false,
+        }
+    }
+}
+
+impl RegPat {
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Size(size) => reg_size(name) == Some(*size),
+            Self::Name(pat) => pat == name,
+        }
+    }
+}
+
+impl MemPat {
+    fn matches(&self, cs: &Capstone, mem: &X86OpMem) -> bool {
+        let name = |reg: RegId| if reg.0 == 0 { None } else { cs.reg_name(reg) };
+
+        let base = match (&self.base, name(mem.base())) {
+            (None, None) => true,
+            (Some(pat), Some(base)) => pat.matches(&base),
+            _ => false,
+        };
+
+        let index = match (&self.index, name(mem.index())) {
+            (None, None) => true,
+            (Some((pat, scale)), Some(index)) => pat.matches(&index) && *scale == mem.scale(),
+            _ => false,
+        };
+
+        base && index && self.disp.map_or(true, |disp| disp == mem.disp())
+    }
+}
+
+fn parse_inst(inst: &str) -> Result<InsnPat, InsnPatternError> {
+    let inst = inst.to_ascii_lowercase();
+
+    let (mnemonic, operands) = inst.split_once(char::is_whitespace).unwrap_or((&inst, ""));
+    let operands = operands.trim();
+
+    let operands = if operands.is_empty() {
+        None
+    } else {
+        let operands = operands
+            .split(',')
+            .map(|operand| parse_operand(operand.trim()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Some(operands)
+    };
+
+    let pat = InsnPat {
+        mnemonic: canonical_mnemonic(mnemonic),
+        operands,
+    };
+
+    Ok(pat)
+}
+
+fn parse_operand(operand: &str) -> Result<OperandPat, InsnPatternError> {
+    let err = || InsnPatternError::Pattern(operand.to_owned());
+
+    let (capture, inner) = match operand.strip_prefix('(') {
+        Some(inner) => (true, inner.strip_suffix(')').ok_or_else(err)?.trim()),
+        None => (false, operand),
+    };
+
+    let kind = if inner == "*" {
+        OperandKind::Any
+    } else if inner == "imm" {
+        OperandKind::Imm(None)
+    } else if let Some(value) = number(inner) {
+        OperandKind::Imm(Some(value as u64))
+    } else if let Some((prefix, mem)) = inner.split_once('[') {
+        let mem = mem.strip_suffix(']').ok_or_else(err)?;
+
+        let size = match prefix.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [] => None,
+            [size] | [size, "ptr"] => Some(mem_size(size).ok_or_else(err)?),
+            _ => return Err(err()),
+        };
+
+        OperandKind::Mem {
+            size,
+            mem: parse_mem(mem).ok_or_else(err)?,
+        }
+    } else {
+        OperandKind::Reg(parse_reg(inner).ok_or_else(err)?)
+    };
+
+    Ok(OperandPat { capture, kind })
+}
+
+/// Parse the inside of a memory operand. Returns `Some(None)` for any memory operand
+fn parse_mem(mem: &str) -> Option<Option<MemPat>> {
+    let mem = mem.replace(char::is_whitespace, "");
+    if mem == "*" {
+        return Some(None);
+    }
+
+    let mut pat = MemPat {
+        base: None,
+        index: None,
+        disp: Some(0),
+    };
+
+    let mut any_disp = false;
+
+    for term in mem
+        .replace('-', "+-")
+        .split('+')
+        .filter(|term| !term.is_empty())
+    {
+        if term == "*" || term == "-*" {
+            any_disp = true;
+        } else if let Some(disp) = number(term) {
+            pat.disp = pat.disp.map(|sum| sum.wrapping_add(disp));
+        } else if let Some((index, scale)) = term.split_once('*') {
+            let scale = number(scale).filter(|scale| [1, 2, 4, 8].contains(scale))?;
+            if pat.index.is_some() {
+                return None;
+            }
+
+            pat.index = Some((parse_reg(index)?, scale as i32));
+        } else if pat.base.is_none() {
+            pat.base = Some(parse_reg(term)?);
+        } else if pat.index.is_none() {
+            pat.index = Some((parse_reg(term)?, 1));
+        } else {
+            return None;
+        }
+    }
+
+    if any_disp {
+        pat.disp = None;
+    }
+
+    Some(Some(pat))
+}
+
+fn parse_reg(reg: &str) -> Option<RegPat> {
+    let pat = match reg {
+        "reg" => RegPat::Any,
+        "reg8" => RegPat::Size(1),
+        "reg16" | "r16" => RegPat::Size(2),
+        "reg32" | "r32" => RegPat::Size(4),
+        "reg64" | "r64" => RegPat::Size(8),
+        _ if !reg.is_empty() && reg.chars().all(|c| c.is_ascii_alphanumeric()) => {
+            RegPat::Name(reg.to_owned())
+        }
+        _ => return None,
+    };
+
+    Some(pat)
+}
+
+/// Parse a decimal or `0x` hex number, with an optional `-`
+fn number(num: &str) -> Option<i64> {
+    let (negative, num) = match num.strip_prefix('-') {
+        Some(num) => (true, num),
+        None => (false, num),
+    };
+
+    let value = match num.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => num.parse::<u64>().ok()?,
+    } as i64;
+
+    Some(if negative {
+        value.wrapping_neg()
+    } else {
+        value
+    })
+}
+
+/// The byte size of a memory operand's size keyword
+fn mem_size(size: &str) -> Option<u8> {
+    let size = match size {
+        "byte" => 1,
+        "word" => 2,
+        "dword" => 4,
+        "qword" => 8,
+        "tbyte" => 10,
+        "xmmword" => 16,
+        "ymmword" => 32,
+        "zmmword" => 64,
+        _ => return None,
+    };
+
+    Some(size)
+}
+
+/// The byte size of a general purpose register
+fn reg_size(name: &str) -> Option<u8> {
+    let size = match name {
+        "rax" | "rbx" | "rcx" | "rdx" | "rsi" | "rdi" | "rbp" | "rsp" | "rip" => 8,
+        "eax" | "ebx" | "ecx" | "edx" | "esi" | "edi" | "ebp" | "esp" | "eip" => 4,
+        "ax" | "bx" | "cx" | "dx" | "si" | "di" | "bp" | "sp" => 2,
+        "al" | "bl" | "cl" | "dl" | "ah" | "bh" | "ch" | "dh" | "sil" | "dil" | "bpl" | "spl" => 1,
+
+        // r8 to r15, with a suffix for the smaller sizes
+        _ => {
+            let num = name.strip_prefix('r')?;
+            let (num, size) = match num.as_bytes().last()? {
+                b'd' => (&num[..num.len() - 1], 4),
+                b'w' => (&num[..num.len() - 1], 2),
+                b'b' => (&num[..num.len() - 1], 1),
+                _ => (num, 8),
+            };
+
+            return matches!(num.parse::<u8>(), Ok(8..=15)).then_some(size);
+        }
+    };
+
+    Some(size)
+}
+
+/// The mnemonic capstone uses for a conditional instruction, e.g. `jz` is disassembled as `je`
+fn canonical_mnemonic(mnemonic: &str) -> String {
+    for prefix in ["cmov", "set", "j"] {
+        let Some(condition) = mnemonic.strip_prefix(prefix) else {
+            continue;
+        };
+
+        let condition = match condition {
+            "z" => "e",
+            "nz" => "ne",
+            "c" | "nae" => "b",
+            "nc" | "nb" => "ae",
+            "na" => "be",
+            "nbe" => "a",
+            "pe" => "p",
+            "po" => "np",
+            "nge" => "l",
+            "nl" => "ge",
+            "ng" => "le",
+            "nle" => "g",
+            _ => break,
+        };
+
+        return format!("{prefix}{condition}");
+    }
+
+    mnemonic.to_owned()
+}
+
+/// Match a mnemonic against a pattern where `*` is any amount of characters
+fn glob(pattern: &str, mnemonic: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == mnemonic,
+        Some((prefix, rest)) => {
+            let Some(mnemonic) = mnemonic.strip_prefix(prefix) else {
+                return false;
+            };
+
+            (0..=mnemonic.len())
+                .filter(|&i| mnemonic.is_char_boundary(i))
+                .any(|i| glob(rest, &mnemonic[i..]))
+        }
+    }
+}
@@ -0,0 +1,449 @@
+//! Parsers for the different text notations signatures are shared in
+
+use std::fmt;
+
+use super::pattern::{Pattern, PatternError, Resolve};
+
+/// A text notation of a [`Pattern`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Notation {
+    /// `48 8B ?? ?5 & E8 [?? ?? ?? ??]`. `?` or `??` is a whole byte wildcard,
+    /// and a single nibble can be wildcarded with `?`. This is the only notation with result markers
+    Ida,
+    /// `"\x48\x8B\x00", "xx?"`. Escaped data, followed by an optional mask
+    /// where `x` is a known byte and `?` or `.` is an unknown byte
+    CodeStyle,
+    /// `488B????` or `48 8B ?? ??`. Whitespace is ignored, and every `?` is a nibble wildcard
+    X64dbg,
+    /// `48 8B * *` or `48 8B ?? 4*`. `*` or `?` is a whole byte wildcard on its own,
+    /// or a nibble wildcard next to a hex digit
+    CheatEngine,
+    /// `0x48 0x8b .. 01001...`. Bytes are hex, with an optional `0x` prefix and `.` as a nibble
+    /// wildcard, or 8 binary digits with `.` as a bit wildcard
+    Ghidra,
+}
+
+impl Notation {
+    /// Guess the notation of a pattern.
+    ///
+    /// IDA, x64dbg, and Cheat Engine notation overlap, and patterns which are valid in more than one
+    /// of them are detected as IDA. Ghidra patterns are only detected if they have a `0x` prefix or a
+    /// `.` wildcard somewhere.
+    pub fn detect(pattern: &str) -> Self {
+        let pattern = pattern.trim();
+
+        let ghidra = pattern
+            .split_whitespace()
+            .any(|token| token.starts_with("0x") || token.starts_with("0X") || token.contains('.'));
+
+        if pattern.starts_with('"') || pattern.contains("\\x") {
+            Self::CodeStyle
+        } else if ghidra {
+            Self::Ghidra
+        } else if pattern.contains('*') {
+            Self::CheatEngine
+        } else if !pattern.contains(char::is_whitespace)
+            && !pattern.contains(['&', '[', ']'])
+            && pattern.len() > 2
+        {
+            Self::X64dbg
+        } else {
+            Self::Ida
+        }
+    }
+
+    /// Parse a pattern written in this notation
+    pub(super) fn parse(self, pattern: &str) -> Result<Pattern, PatternError> {
+        match self {
+            Self::Ida => parse_ida(pattern),
+            Self::CodeStyle => parse_code_style(pattern),
+            Self::X64dbg => parse_x64dbg(pattern),
+            Self::CheatEngine => parse_cheat_engine(pattern),
+            Self::Ghidra => parse_ghidra(pattern),
+        }
+    }
+}
+
+impl fmt::Display for Notation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Ida => "IDA",
+            Self::CodeStyle => "code-style",
+            Self::X64dbg => "x64dbg",
+            Self::CheatEngine => "Cheat Engine",
+            Self::Ghidra => "Ghidra",
+        };
+
+        f.write_str(name)
+    }
+}
+
+/// A nibble's (value, mask). `wildcards` are the characters which mean an unknown nibble
+fn nibble(c: char, wildcards: &[char]) -> Option<(u8, u8)> {
+    if wildcards.contains(&c) {
+        Some((0x0, 0x0))
+    } else {
+        c.to_digit(16).map(|n| (n as u8, 0xF))
+    }
+}
+
+/// A byte's (value, mask) from two nibbles
+fn byte(high: char, low: char, wildcards: &[char]) -> Option<(u8, u8)> {
+    let (high, high_mask) = nibble(high, wildcards)?;
+    let (low, low_mask) = nibble(low, wildcards)?;
+
+    Some((high << 4 | low, high_mask << 4 | low_mask))
+}
+
+/// Parse pairs of nibbles into data and mask
+fn push_pairs(
+    chars: &[char],
+    wildcards: &[char],
+    data: &mut Vec<u8>,
+    mask: &mut Vec<u8>,
+) -> Option<()> {
+    if chars.len() % 2 != 0 {
+        return None;
+    }
+
+    for pair in chars.chunks_exact(2) {
+        let (byte, byte_mask) = byte(pair[0], pair[1], wildcards)?;
+        data.push(byte);
+        mask.push(byte_mask);
+    }
+
+    Some(())
+}
+
+fn parse_ida(pattern: &str) -> Result<Pattern, PatternError> {
+    let mut data = Vec::new();
+    let mut mask = Vec::new();
+
+    let mut resolve = Resolve::Start;
+    // start of an open `[`
+    let mut rel_start = None;
+
+    let mut pattern = pattern.chars().peekable();
+
+    while let Some(sym) = pattern.next() {
+        match sym {
+            ' ' => continue,
+
+            '&' if resolve == Resolve::Start && rel_start.is_none() => {
+                resolve = Resolve::Offset(data.len());
+                continue;
+            }
+
+            '[' if resolve == Resolve::Start && rel_start.is_none() => {
+                rel_start = Some(data.len());
+                continue;
+            }
+
+            ']' => {
+                let offset = rel_start.take().ok_or(PatternError::Marker)?;
+                let size = data.len() - offset;
+
+                if ![1, 2, 4].contains(&size) {
+                    return Err(PatternError::Marker);
+                }
+
+                resolve = Resolve::Relative { offset, size };
+                continue;
+            }
+
+            '&' | '[' => return Err(PatternError::Marker),
+
+            _ => (),
+        }
+
+        // a lone `?` is a whole byte wildcard
+        let next_sym = match pattern.peek().copied() {
+            Some(next_sym) if next_sym.is_ascii_hexdigit() || next_sym == '?' => next_sym,
+            _ if sym == '?' => '?',
+            // check if iterator got out of sync, which indicates a partial match
+            _ => return Err(PatternError::Pat),
+        };
+
+        // only hex digits or wildcards are allowed; a-f A-F 0-9 ?
+        let (byte, byte_mask) = byte(sym, next_sym, &['?']).ok_or(PatternError::Pat)?;
+
+        data.push(byte);
+        mask.push(byte_mask);
+
+        pattern.next_if_eq(&next_sym);
+    }
+
+    // an unclosed `[`
+    if rel_start.is_some() {
+        return Err(PatternError::Marker);
+    }
+
+    let mut slf = Pattern::new(data, mask);
+    slf.resolve = resolve;
+
+    Ok(slf)
+}
+
+fn parse_code_style(pattern: &str) -> Result<Pattern, PatternError> {
+    let err = PatternError::Notation(Notation::CodeStyle);
+
+    let mut parts = Vec::new();
+    let mut chars = pattern.chars().peekable();
+
+    // split into the data and mask, which are separated by whitespace or a comma and may be quoted
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() || c == ',' {
+            chars.next();
+            continue;
+        }
+
+        let mut part = String::new();
+
+        if c == '"' {
+            chars.next();
+
+            loop {
+                match chars.next().ok_or(err)? {
+                    '"' => break,
+                    '\\' => {
+                        part.push('\\');
+                        part.push(chars.next().ok_or(err)?);
+                    }
+                    c => part.push(c),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|&c| !c.is_whitespace() && c != ',') {
+                part.push(c);
+            }
+        }
+
+        parts.push(part);
+    }
+
+    let (data, mask) = match parts.as_slice() {
+        [data] => (data, None),
+        [data, mask] => (data, Some(mask)),
+        _ => return Err(err),
+    };
+
+    // unescape the data
+    let mut bytes = Vec::new();
+    let mut chars = data.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        let byte = match c {
+            '\\' => match chars.next().ok_or(err)? {
+                'x' => {
+                    let mut value = 0;
+                    let mut digits = 0;
+
+                    while let Some(digit) = chars
+                        .next_if(|c| c.is_ascii_hexdigit() && digits < 2)
+                        .and_then(|c| c.to_digit(16))
+                    {
+                        value = value << 4 | digit as u8;
+                        digits += 1;
+                    }
+
+                    if digits == 0 {
+                        return Err(err);
+                    }
+
+                    value
+                }
+
+                '0' => 0,
+                'n' => b'\n',
+                'r' => b'\r',
+                't' => b'\t',
+                c @ ('\\' | '"' | '\'') => c as u8,
+                _ => return Err(err),
+            },
+
+            c if c.is_ascii() => c as u8,
+            _ => return Err(err),
+        };
+
+        bytes.push(byte);
+    }
+
+    match mask {
+        Some(mask) => Pattern::from_data_with_mask(&bytes, mask),
+        None => Ok(Pattern::from_data(&bytes)),
+    }
+}
+
+fn parse_x64dbg(pattern: &str) -> Result<Pattern, PatternError> {
+    let chars = pattern
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<Vec<_>>();
+
+    let mut data = Vec::new();
+    let mut mask = Vec::new();
+
+    push_pairs(&chars, &['?'], &mut data, &mut mask)
+        .ok_or(PatternError::Notation(Notation::X64dbg))?;
+
+    Ok(Pattern::new(data, mask))
+}
+
+fn parse_cheat_engine(pattern: &str) -> Result<Pattern, PatternError> {
+    const WILDCARDS: &[char] = &['*', '?'];
+
+    let mut data = Vec::new();
+    let mut mask = Vec::new();
+
+    for token in pattern.split_whitespace() {
+        let chars = token.chars().collect::<Vec<_>>();
+
+        match chars.as_slice() {
+            // a lone wildcard is a whole byte
+            [c] if WILDCARDS.contains(c) => {
+                data.push(0);
+                mask.push(0);
+            }
+
+            chars => push_pairs(chars, WILDCARDS, &mut data, &mut mask)
+                .ok_or(PatternError::Notation(Notation::CheatEngine))?,
+        }
+    }
+
+    Ok(Pattern::new(data, mask))
+}
+
+fn parse_ghidra(pattern: &str) -> Result<Pattern, PatternError> {
+    let err = PatternError::Notation(Notation::Ghidra);
+
+    let mut data = Vec::new();
+    let mut mask = Vec::new();
+
+    for token in pattern.split_whitespace() {
+        let hex = token
+            .strip_prefix("0x")
+            .or_else(|| token.strip_prefix("0X"));
+
+        let is_binary = hex.is_none()
+            && token.len() == 8
+            && token.chars().all(|c| matches!(c, '0' | '1' | '.'));
+
+        if is_binary {
+            let (mut byte, mut byte_mask) = (0, 0);
+
+            for c in token.chars() {
+                byte <<= 1;
+                byte_mask <<= 1;
+
+                if c != '.' {
+                    byte |= (c == '1') as u8;
+                    byte_mask |= 1;
+                }
+            }
+
+            data.push(byte);
+            mask.push(byte_mask);
+        } else {
+            let chars = hex.unwrap_or(token).chars().collect::<Vec<_>>();
+            push_pairs(&chars, &['.', '?'], &mut data, &mut mask).ok_or(err)?;
+        }
+    }
+
+    Ok(Pattern::new(data, mask))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Detect and parse each pattern, and check its canonical IDA form survives a round trip
+    fn check(cases: &[(&str, Notation, &str)]) {
+        for &(pattern, notation, ida) in cases {
+            assert_eq!(Notation::detect(pattern), notation, "{pattern:?}");
+
+            let parsed = pattern.parse::<Pattern>().unwrap();
+            assert_eq!(parsed.to_ida_string(), ida, "{pattern:?}");
+
+            let round_trip = parsed.to_ida_string().parse::<Pattern>().unwrap();
+            assert_eq!(round_trip.to_string(), ida, "{pattern:?}");
+        }
+    }
+
+    #[test]
+    fn ida() {
+        check(&[
+            ("48 8b ? 24", Notation::Ida, "48 8B ?? 24"),
+            ("48 8B ?? ?5 4?", Notation::Ida, "48 8B ?? ?5 4?"),
+            ("48 & 8b", Notation::Ida, "48 & 8B"),
+            ("E8 [?? ?? ?? ??] 48", Notation::Ida, "E8 [?? ?? ?? ??] 48"),
+            ("48", Notation::Ida, "48"),
+        ]);
+    }
+
+    #[test]
+    fn code_style() {
+        check(&[
+            (r#""\x48\x8B\x00", "xx?""#, Notation::CodeStyle, "48 8B ??"),
+            (
+                r#"\x48\x8B\x00\x05 xx.x"#,
+                Notation::CodeStyle,
+                "48 8B ?? 05",
+            ),
+            (r#""\x48\x8b\x05""#, Notation::CodeStyle, "48 8B 05"),
+            (r#""\x48A\\\0", "xxxx""#, Notation::CodeStyle, "48 41 5C 00"),
+        ]);
+
+        for pattern in [
+            r#""\x48\x8B", "x""#,
+            r#""\x48", "xx", "x""#,
+            r#""\x48"#,
+            r#""\xZZ""#,
+            r#""\q""#,
+        ] {
+            assert!(pattern.parse::<Pattern>().is_err(), "{pattern:?}");
+        }
+    }
+
+    #[test]
+    fn x64dbg() {
+        check(&[
+            ("488B????05", Notation::X64dbg, "48 8B ?? ?? 05"),
+            ("488B?5", Notation::X64dbg, "48 8B ?5"),
+        ]);
+
+        // with whitespace it's detected as IDA, but every `?` is still a nibble in x64dbg notation
+        let pattern = Pattern::from_notation("48 8B ? ?", Notation::X64dbg).unwrap();
+        assert_eq!(pattern.to_string(), "48 8B ??");
+
+        assert!(Pattern::from_notation("488B?", Notation::X64dbg).is_err());
+    }
+
+    #[test]
+    fn cheat_engine() {
+        check(&[
+            ("48 8B * * 4*", Notation::CheatEngine, "48 8B ?? ?? 4?"),
+            ("48 8B * ?? *5", Notation::CheatEngine, "48 8B ?? ?? ?5"),
+        ]);
+
+        assert!("48 8B * 4".parse::<Pattern>().is_err());
+        assert!("48 8B * G*".parse::<Pattern>().is_err());
+    }
+
+    #[test]
+    fn ghidra() {
+        check(&[
+            ("0x48 0x8b .. 0100....", Notation::Ghidra, "48 8B ?? 4?"),
+            ("0x48 0x8b", Notation::Ghidra, "48 8B"),
+            ("48 8.", Notation::Ghidra, "48 8?"),
+        ]);
+
+        // bit wildcards can't be written in IDA notation, but are still matched exactly
+        let pattern = "0x48 01001...".parse::<Pattern>().unwrap();
+        assert_eq!(pattern.to_string(), "48 4?");
+        assert_eq!(&pattern.data[..2], [0x48, 0x48]);
+        assert_eq!(&pattern.mask[..2], [0xFF, 0xF8]);
+
+        assert!("0x4 0x8b".parse::<Pattern>().is_err());
+        assert!("0x48 0100.....".parse::<Pattern>().is_err());
+    }
+}
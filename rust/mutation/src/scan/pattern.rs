@@ -1,29 +1,81 @@
-use std::fmt;
-
-use super::aligned_bytes::AlignedBytes;
-
-#[derive(Debug, thiserror::Error)]
-pub(crate) enum PatternError {
-    #[error("pattern is invalid. pattern must be a-f, A-F, 0-9, or ?? or ? for wildcards")]
+use std::{borrow::Cow, fmt, str::FromStr};
+
+use super::{
+    aligned_bytes::AlignedBytes,
+    backends::{self, Backend},
+    notation::Notation,
+    Scan, Scans,
+};
+
+/// An error which happened while parsing a [`Pattern`]
+#[derive(Debug, Copy, Clone, thiserror::Error)]
+pub enum PatternError {
+    /// the IDA-style pattern contains invalid characters
+    #[error("pattern is invalid. pattern must be a-f, A-F, 0-9, or ?? or ? for wildcards, and ? for nibble wildcards")]
     Pat,
-    #[error("mask is invalid. mask must be x or ? for wildcards")]
+    /// the mask contains invalid characters
+    #[error("mask is invalid. mask must be x, or ? or . for wildcards")]
     Mask,
+    /// the mask and the data have different lengths
     #[error("mask is not the same length as the data")]
     MaskLen,
+    /// the pattern is invalid in the notation it was parsed as
+    #[error("pattern is not valid {0} notation")]
+    Notation(Notation),
+    /// the result markers are used incorrectly
+    #[error(
+        "result marker is invalid. use at most one `&`, or one `[..]` around 1, 2, or 4 bytes"
+    )]
+    Marker,
 }
 
-/// An IDA-style binary pattern
-pub(crate) struct Pattern {
+/// What address a match of a [`Pattern`] resolves to
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub(crate) enum Resolve {
+    /// the start of the match
+    #[default]
+    Start,
+    /// the match plus an offset
+    Offset(usize),
+    /// the target of the relative displacement of `size` bytes at `offset`.
+    /// the displacement is relative to the end of itself
+    Relative { offset: usize, size: usize },
+}
+
+/// A pre-compiled binary pattern.
+///
+/// Parsing a pattern has a cost, so if the same pattern is used for many scans,
+/// compile it once and reuse it with [`Pattern::scan`] and [`Pattern::scan_all`].
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let pattern: Pattern = "48 89 5c 24 ?? 48 89 6c".parse()?;
+///
+/// for (addr, size) in regions {
+///     let result = unsafe { pattern.scan(addr, size) };
+///     println!("{:?}", result);
+/// }
+/// ```
+#[derive(Clone)]
+pub struct Pattern {
     pub(crate) data: AlignedBytes,
     pub(crate) mask: AlignedBytes,
     pub(crate) unpadded_size: usize,
+    pub(crate) resolve: Resolve,
+    // offset of the rarest fully known byte, if any
+    pub(crate) anchor: Option<usize>,
 }
 
 impl fmt::Debug for Pattern {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let Pattern { data, .. } = self;
+        let Pattern {
+            data,
+            unpadded_size,
+            ..
+        } = self;
 
-        let data = &**data;
+        let data = &data[..*unpadded_size];
         write!(f, r#"Pattern({data:?})"#)
     }
 }
@@ -31,85 +83,172 @@ impl fmt::Debug for Pattern {
 impl Pattern {
     const ALIGN: usize = 32;
 
-    /// Create a new IDA-style [`Pattern`] instance
+    /// Create a pattern which matches `data` exactly
     ///
     /// # Example
     ///
     /// ```rust,ignore
-    /// Pattern::new("48 89 ?? 24 ?? 48 89 6c");
-    /// Pattern::new("48 89 ? 24 ? 48 89 6c");
+    /// Pattern::from_data(&[0x48, 0x89, 0x5c]);
     /// ```
-    pub(crate) fn from_str(pattern: &str) -> Result<Self, PatternError> {
-        let char_to_byte = |c| match c {
-            c if matches!(c, 'a'..='f') => c as u8 - b'a' + 0xA,
-            c if matches!(c, 'A'..='F') => c as u8 - b'A' + 0xA,
-            c if c.is_ascii_digit() => c as u8 - b'0',
-            _ => unreachable!(),
-        };
+    pub fn from_data(data: &[u8]) -> Self {
+        let mask = vec![0xFF; data.len()];
 
-        let mut data = Vec::new();
-        let mut mask = Vec::new();
+        Self::new(data.to_vec(), mask)
+    }
 
-        let mut pattern = pattern.chars().peekable();
+    /// Create a pattern from data and a mask. Use `x` in the mask for a known byte and `?` or `.` for an unknown byte.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// Pattern::from_data_with_mask(&[0x48, 0x89, 0x00, 0x24], "xx?x");
+    /// Pattern::from_data_with_mask(&[0x48, 0x89, 0x00, 0x24], "xx.x");
+    /// ```
+    pub fn from_data_with_mask(data: &[u8], mask: &str) -> Result<Self, PatternError> {
+        if mask.len() != data.len() {
+            return Err(PatternError::MaskLen);
+        }
 
-        while let Some(sym) = pattern.next() {
-            let next_sym = pattern.peek().copied();
+        let mut mask_ = Vec::with_capacity(data.len());
 
+        for sym in mask.chars() {
             match sym {
-                ' ' => (),
-
-                '?' => {
-                    data.push(0x00);
-                    mask.push(0x00);
+                'x' | 'X' => mask_.push(0xFF),
+                '?' | '.' => mask_.push(0x00),
+                _ => return Err(PatternError::Mask),
+            }
+        }
 
-                    pattern.next_if_eq(&'?');
-                }
+        Ok(Self::new(data.to_vec(), mask_))
+    }
 
-                _ => {
-                    // check if iterator got out of sync, which indicates a partial match
-                    let Some(next_sym) = next_sym else {
-                        return Err(PatternError::Pat);
-                    };
+    /// Create a pattern written in a specific [`Notation`]
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// Pattern::from_notation("488B??05", Notation::X64dbg);
+    /// ```
+    pub fn from_notation(pattern: &str, notation: Notation) -> Result<Self, PatternError> {
+        notation.parse(pattern)
+    }
 
-                    // only hex digits are allowed; a-f A-F 0-9
-                    if !sym.is_ascii_hexdigit() || !next_sym.is_ascii_hexdigit() {
-                        return Err(PatternError::Pat);
-                    }
+    /// The pattern in canonical IDA-style notation, the same as its [`Display`](fmt::Display)
+    pub fn to_ida_string(&self) -> String {
+        self.to_string()
+    }
 
-                    let byte = char_to_byte(sym) << 4 | char_to_byte(next_sym);
+    /// Find the first occurrence of the pattern in the binary.
+    ///
+    /// If the pattern has a result marker, the address the marker resolves to is returned instead
+    /// of the start of the match.
+    ///
+    /// # Safety
+    ///
+    /// * `addr` - is a valid pointer
+    ///
+    /// * `size` - corresponds to a valid size of `binary`
+    pub unsafe fn scan(&self, addr: *const u8, size: usize) -> Option<Scan> {
+        // SAFETY: safe to call as long as the safety conditions were met for this function
+        unsafe { backends::find(self, addr, size) }
+    }
 
-                    data.push(byte);
-                    mask.push(0xFF);
+    /// Find the first occurrence of the pattern in the binary, splitting the work across multiple threads.
+    ///
+    /// This returns the same match as [`Pattern::scan`], but is faster on large regions.
+    ///
+    /// # Safety
+    ///
+    /// * `addr` - is a valid pointer
+    ///
+    /// * `size` - corresponds to a valid size of `binary`
+    pub unsafe fn scan_parallel(&self, addr: *const u8, size: usize) -> Option<Scan> {
+        // SAFETY: safe to call as long as the safety conditions were met for this function
+        unsafe { backends::find_parallel(self, addr, size) }
+    }
 
-                    pattern.next();
-                }
-            }
-        }
+    /// Find every occurrence of the pattern in the binary
+    ///
+    /// # Safety
+    ///
+    /// * `addr` - is a valid pointer for as long as the iterator is alive
+    ///
+    /// * `size` - corresponds to a valid size of `binary`
+    pub unsafe fn scan_all(&self, addr: *const u8, size: usize) -> Scans<'_> {
+        // SAFETY: safe to call as long as the safety conditions were met for this function
+        let matches = unsafe { backends::find_all(Cow::Borrowed(self), addr, size) };
+        Scans(Some(matches))
+    }
 
-        let unpadded_size = data.len();
+    /// Find the first occurrence of the pattern in the binary using a specific [`Backend`]
+    ///
+    /// Every backend returns the same results, so this is only useful to compare or benchmark them.
+    ///
+    /// # Safety
+    ///
+    /// * `addr` - is a valid pointer
+    ///
+    /// * `size` - corresponds to a valid size of `binary`
+    ///
+    /// * `backend` - is supported by the currently running CPU, see [`Backend::is_supported`]
+    pub unsafe fn scan_with(&self, backend: Backend, addr: *const u8, size: usize) -> Option<Scan> {
+        // SAFETY: safe to call as long as the safety conditions were met for this function
+        unsafe { backends::find_with(backend, self, addr, size) }
+    }
 
-        let count = f32::ceil(unpadded_size as f32 / Self::ALIGN as f32) as usize;
-        let padding_size = count * Self::ALIGN - unpadded_size;
+    /// Find every occurrence of the pattern in the binary using a specific [`Backend`]
+    ///
+    /// # Safety
+    ///
+    /// * `addr` - is a valid pointer for as long as the iterator is alive
+    ///
+    /// * `size` - corresponds to a valid size of `binary`
+    ///
+    /// * `backend` - is supported by the currently running CPU, see [`Backend::is_supported`]
+    pub unsafe fn scan_all_with(
+        &self,
+        backend: Backend,
+        addr: *const u8,
+        size: usize,
+    ) -> Scans<'_> {
+        // SAFETY: safe to call as long as the safety conditions were met for this function
+        let matches = unsafe { backends::find_all_with(backend, Cow::Borrowed(self), addr, size) };
+        Scans(Some(matches))
+    }
 
-        data.resize(unpadded_size + padding_size, 0);
-        mask.resize(unpadded_size + padding_size, 0);
+    /// Resolve a match of this pattern to the address the pattern's result marker points at
+    ///
+    /// # Safety
+    /// `scan` must be a match of this pattern, and valid for reads of the whole pattern
+    pub(crate) unsafe fn resolve(&self, scan: Scan) -> Scan {
+        let addr = match self.resolve {
+            Resolve::Start => scan.addr,
+
+            // SAFETY: the offset is inside the pattern, which the caller guaranteed is readable
+            Resolve::Offset(offset) => unsafe { scan.addr.add(offset) },
+
+            Resolve::Relative { offset, size } => {
+                // SAFETY: the displacement is inside the pattern, which the caller guaranteed is readable
+                let disp = unsafe {
+                    let disp = scan.addr.add(offset);
+
+                    match size {
+                        1 => disp.cast::<i8>().read_unaligned() as isize,
+                        2 => disp.cast::<i16>().read_unaligned() as isize,
+                        _ => disp.cast::<i32>().read_unaligned() as isize,
+                    }
+                };
 
-        // SAFETY: our align is a power of 2 above
-        let slf = Self {
-            data: unsafe { AlignedBytes::new(&data, Self::ALIGN).unwrap_unchecked() },
-            mask: unsafe { AlignedBytes::new(&mask, Self::ALIGN).unwrap_unchecked() },
-            unpadded_size,
+                // relative to the end of the displacement
+                scan.addr.wrapping_add(offset + size).wrapping_offset(disp)
+            }
         };
 
-        Ok(slf)
+        Scan { addr }
     }
 
-    pub(crate) fn from_data(data: &[u8]) -> Self {
-        let mut data = data.to_vec();
-
-        let mut mask = Vec::with_capacity(data.len());
-        mask.fill(0xFF);
-
+    /// Pad the data and mask up to the alignment, and copy them to aligned storage
+    pub(crate) fn new(mut data: Vec<u8>, mut mask: Vec<u8>) -> Self {
         let unpadded_size = data.len();
 
         let count = f32::ceil(unpadded_size as f32 / Self::ALIGN as f32) as usize;
@@ -118,46 +257,108 @@ impl Pattern {
         data.resize(unpadded_size + padding_size, 0);
         mask.resize(unpadded_size + padding_size, 0);
 
+        // backends compare `byte & mask` against the data, so unknown bits of the data must be 0
+        for (byte, mask) in data.iter_mut().zip(&mask) {
+            *byte &= mask;
+        }
+
+        let anchor = backends::rarest_byte(&data, &mask);
+
         // SAFETY: our align is a power of 2 above
         Self {
             data: unsafe { AlignedBytes::new(&data, Self::ALIGN).unwrap_unchecked() },
             mask: unsafe { AlignedBytes::new(&mask, Self::ALIGN).unwrap_unchecked() },
             unpadded_size,
+            resolve: Resolve::Start,
+            anchor,
         }
     }
+}
 
-    pub(crate) fn from_data_with_mask(data: &[u8], mask: &str) -> Result<Self, PatternError> {
-        if mask.len() != data.len() {
-            return Err(PatternError::MaskLen);
-        }
+impl FromStr for Pattern {
+    type Err = PatternError;
 
-        let mut data = data.to_vec();
-        let mut mask_ = Vec::with_capacity(data.len());
+    /// Create a new [`Pattern`] instance, detecting the pattern's [`Notation`]
+    ///
+    /// In IDA-style notation, each byte is two hex digits. A whole byte can be wildcarded with `??` or `?`,
+    /// and a single nibble can be wildcarded by replacing one digit with `?`.
+    ///
+    /// An IDA-style pattern may also contain one result marker, which changes the address a match resolves to:
+    ///
+    /// * `&` - resolve to the byte following the marker instead of the start of the match
+    ///
+    /// * `[..]` - around 1, 2, or 4 bytes; resolve to the target of the relative displacement
+    ///   in the brackets. The displacement is relative to the end of the brackets, which is the
+    ///   address of the next instruction as long as no immediate follows the displacement
+    ///
+    /// See [`Notation`] for the other notations. Use [`Pattern::from_notation`] if the notation is known.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// "48 89 ?? 24 ?? 48 89 6c".parse::<Pattern>();
+    /// "48 89 ? 24 ? 48 89 6c".parse::<Pattern>();
+    /// "4? 8B ?5".parse::<Pattern>();
+    /// // resolves to the address of `8B`
+    /// "48 & 8B 05".parse::<Pattern>();
+    /// // resolves to the call target
+    /// "E8 [?? ?? ?? ??] 48 8B".parse::<Pattern>();
+    /// // other notations
+    /// r#""\x48\x89\x00\x24", "xx?x""#.parse::<Pattern>();
+    /// "48 89 * 24".parse::<Pattern>();
+    /// "0x48 0x89 .. 0x24".parse::<Pattern>();
+    /// ```
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        Notation::detect(pattern).parse(pattern)
+    }
+}
 
-        for sym in mask.chars() {
-            match sym {
-                'x' => mask_.push(0xFF),
-                '?' => mask_.push(0x00),
-                _ => return Err(PatternError::Mask),
+impl fmt::Display for Pattern {
+    /// Write the pattern in canonical IDA-style notation.
+    ///
+    /// Nibbles which are only partly known can't be written in IDA-style notation, so they're written as `?`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let size = self.unpadded_size;
+
+        let nibble = |value: u8, mask: u8| {
+            if mask == 0xF {
+                char::from_digit(value as u32, 16)
+                    .unwrap()
+                    .to_ascii_uppercase()
+            } else {
+                '?'
             }
-        }
+        };
 
-        let unpadded_size = data.len();
+        let mut tokens = Vec::with_capacity(size + 1);
+        for (i, (&byte, &mask)) in self.data[..size].iter().zip(&self.mask[..size]).enumerate() {
+            if self.resolve == Resolve::Offset(i) {
+                tokens.push("&".to_owned());
+            }
 
-        let count = f32::ceil(unpadded_size as f32 / Self::ALIGN as f32) as usize;
-        let padding_size = count * Self::ALIGN - unpadded_size;
+            let mut token = match (nibble(byte >> 4, mask >> 4), nibble(byte & 0xF, mask & 0xF)) {
+                ('?', '?') => "??".to_owned(),
+                (high, low) => format!("{high}{low}"),
+            };
 
-        data.resize(unpadded_size + padding_size, 0);
-        mask_.resize(unpadded_size + padding_size, 0);
+            if let Resolve::Relative { offset, size } = self.resolve {
+                if i == offset {
+                    token.insert(0, '[');
+                }
 
-        // SAFETY: our align is a power of 2 above
-        let slf = Self {
-            data: unsafe { AlignedBytes::new(&data, Self::ALIGN).unwrap_unchecked() },
-            mask: unsafe { AlignedBytes::new(&mask_, Self::ALIGN).unwrap_unchecked() },
-            unpadded_size,
-        };
+                if i == offset + size - 1 {
+                    token.push(']');
+                }
+            }
+
+            tokens.push(token);
+        }
+
+        if self.resolve == Resolve::Offset(size) {
+            tokens.push("&".to_owned());
+        }
 
-        Ok(slf)
+        f.write_str(&tokens.join(" "))
     }
 }
 
@@ -171,6 +372,174 @@ impl TryFrom<&str> for Pattern {
     type Error = PatternError;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        Self::from_str(value)
+        value.parse()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    /// Offsets of every match of the pattern in the binary
+    fn offsets(pattern: &Pattern, binary: &[u8]) -> Vec<usize> {
+        // SAFETY: the binary is valid for its whole length
+        unsafe { pattern.scan_all(binary.as_ptr(), binary.len()) }
+            .map(|scan| scan.addr as usize - binary.as_ptr() as usize)
+            .collect()
+    }
+
+    #[test]
+    fn scan_reuses_pattern() {
+        let pattern = "48 89 5C 24 ?? 48".parse::<Pattern>().unwrap();
+
+        let first = [0x00, 0x48, 0x89, 0x5C, 0x24, 0x08, 0x48, 0x00];
+        let second = [
+            0x48, 0x89, 0x5C, 0x24, 0x10, 0x48, 0x48, 0x89, 0x5C, 0x24, 0x18, 0x48,
+        ];
+
+        // SAFETY: the binaries are valid for their whole length
+        let scan = unsafe { pattern.scan(first.as_ptr(), first.len()) }.unwrap();
+        assert_eq!(scan.addr, first[1..].as_ptr());
+
+        assert_eq!(offsets(&pattern, &first), [1]);
+        assert_eq!(offsets(&pattern, &second), [0, 6]);
+        assert_eq!(offsets(&pattern, &[0x48, 0x89, 0x5C]), []);
+    }
+
+    #[test]
+    fn scan_all_overlapping() {
+        let pattern = Pattern::from_data(&[0xAA, 0xAA]);
+        assert_eq!(offsets(&pattern, &[0xAA; 4]), [0, 1, 2]);
+    }
+
+    #[test]
+    fn scan_parallel_same_as_scan() {
+        let pattern = "48 8B 05 ?? ?? ?? ?? C3".parse::<Pattern>().unwrap();
+        let sig = [0x48, 0x8B, 0x05, 0x01, 0x02, 0x03, 0x04, 0xC3];
+
+        let mut binary = vec![0x90u8; 32 * 1024 * 1024];
+        // a late chunk, then across 16 and 8 MiB, which are chunk boundaries with most thread counts
+        for offset in [30 << 20, (16 << 20) - 3, (8 << 20) - 5] {
+            binary[offset..offset + sig.len()].copy_from_slice(&sig);
+
+            thread::scope(|s| {
+                // scans from several threads at once share the workers
+                for _ in 0..4 {
+                    s.spawn(|| {
+                        // SAFETY: the binary is valid for its whole length
+                        let (single, parallel) = unsafe {
+                            (
+                                pattern.scan(binary.as_ptr(), binary.len()),
+                                pattern.scan_parallel(binary.as_ptr(), binary.len()),
+                            )
+                        };
+
+                        assert_eq!(parallel.unwrap().addr, single.unwrap().addr);
+                        assert_eq!(parallel.unwrap().addr, binary[offset..].as_ptr());
+                    });
+                }
+            });
+        }
+
+        // SAFETY: the binary is valid for its whole length
+        let small = unsafe { pattern.scan_parallel(binary.as_ptr(), 1024) };
+        assert!(small.is_none());
+    }
+
+    #[test]
+    fn from_data_with_mask() {
+        let pattern = Pattern::from_data_with_mask(&[0x48, 0xFF, 0x05], "x?x").unwrap();
+        assert_eq!(pattern.to_string(), "48 ?? 05");
+        assert_eq!(offsets(&pattern, &[0x48, 0x12, 0x05, 0x48, 0x05]), [0]);
+
+        assert_eq!(
+            Pattern::from_data_with_mask(&[0x48, 0x8B], "x.")
+                .unwrap()
+                .to_string(),
+            "48 ??"
+        );
+        assert!(matches!(
+            Pattern::from_data_with_mask(&[0x48], "xx"),
+            Err(PatternError::MaskLen)
+        ));
+        assert!(matches!(
+            Pattern::from_data_with_mask(&[0x48], "y"),
+            Err(PatternError::Mask)
+        ));
+    }
+
+    #[test]
+    fn invalid() {
+        for pattern in ["48 8G", "48 8", "48 -8"] {
+            assert!(pattern.parse::<Pattern>().is_err(), "{pattern:?}");
+        }
+    }
+
+    /// Where the first match of the pattern resolves to, as an offset into the binary
+    fn resolved(pattern: &str, binary: &[u8]) -> isize {
+        let pattern = pattern.parse::<Pattern>().unwrap();
+
+        // SAFETY: the binary is valid for its whole length
+        let scan = unsafe { pattern.scan(binary.as_ptr(), binary.len()) }.unwrap();
+        scan.addr as isize - binary.as_ptr() as isize
+    }
+
+    #[test]
+    fn offset_marker() {
+        let binary = [0x90, 0x48, 0x8B, 0x05, 0x90];
+
+        assert_eq!(resolved("48 8B 05", &binary), 1);
+        assert_eq!(resolved("48 & 8B 05", &binary), 2);
+        assert_eq!(resolved("& 48 8B 05", &binary), 1);
+        // right after the match
+        assert_eq!(resolved("48 8B 05 &", &binary), 4);
+    }
+
+    #[test]
+    fn relative_marker() {
+        // call +0x10, the target is relative to the end of the call
+        let call = [0x90, 0xE8, 0x10, 0x00, 0x00, 0x00, 0x48];
+        assert_eq!(resolved("E8 [?? ?? ?? ??] 48", &call), 6 + 0x10);
+
+        // jmp -0x4, rel8
+        let jmp = [0x90, 0x90, 0x90, 0xEB, 0xFC];
+        assert_eq!(resolved("EB [??]", &jmp), 5 - 4);
+
+        // rel16, in the middle of the pattern
+        let rel16 = [0x66, 0x00, 0x01, 0xC3];
+        assert_eq!(resolved("66 [?? ??] C3", &rel16), 3 + 0x100);
+    }
+
+    #[test]
+    fn markers_round_trip() {
+        for pattern in [
+            "48 & 8B 05",
+            "E8 [?? ?? ?? ??] 48 8B",
+            "EB [??]",
+            "48 8B &",
+            "66 [?? ??] C3",
+        ] {
+            assert_eq!(pattern.parse::<Pattern>().unwrap().to_string(), pattern);
+        }
+    }
+
+    #[test]
+    fn invalid_markers() {
+        for pattern in [
+            "48 & 8B &",
+            "E8 [?? ??",
+            "E8 ?? ??]",
+            "E8 [?? ?? ??] 48",
+            "E8 [] 48",
+            "& E8 [??]",
+            "E8 [?? [??]]",
+        ] {
+            assert!(
+                matches!(pattern.parse::<Pattern>(), Err(PatternError::Marker)),
+                "{pattern:?}"
+            );
+        }
     }
 }
@@ -0,0 +1,555 @@
+//! Find chains of pointers from static addresses inside modules to a target address
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    io::{self, Write as _},
+    ops::{ControlFlow, Range},
+    path::Path,
+    str::FromStr,
+};
+
+use super::chunks::{self, ScanVec};
+use crate::{
+    memory::{self, MemError},
+    modules::{self, Module, ModuleError},
+    segments,
+};
+
+/// An error for the pointer scanner
+#[derive(Debug, thiserror::Error)]
+pub enum PointerScanError {
+    /// memory for the scan could not be allocated
+    #[error(transparent)]
+    Mem(#[from] MemError),
+    /// the loaded modules could not be listed
+    #[error(transparent)]
+    Module(#[from] ModuleError),
+    /// a saved scan could not be read or written
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// a pointer chain is not in the format of [`PointerChain`]'s `Display`
+    #[error("invalid pointer chain. expected `module+0xrva -> [0xoffset, ..]`")]
+    Parse,
+}
+
+/// A chain of pointers from a static address inside a module to a target.
+///
+/// Starting at the module's base plus `rva`, each pointer is read and then offset. The result of the
+/// last offset is the target. This is the same as [`memory::deep_pointer`] with the same offsets.
+///
+/// Chains are written as `game.exe+0x1a2b0 -> [0x10, 0x8, 0x3c]`, and can be parsed back from that.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PointerChain {
+    /// the name of the module the chain starts in
+    pub module: String,
+    /// the address the chain starts at, relative to the module's base
+    pub rva: usize,
+    /// the offsets applied after each pointer is read
+    pub offsets: Vec<usize>,
+}
+
+impl PointerChain {
+    /// The static address the chain starts at, or None if the module isn't loaded
+    pub fn base(&self) -> Option<*const *const ()> {
+        let module = modules::find_module(&self.module).ok()??;
+        Some(module.base.wrapping_add(self.rva).cast())
+    }
+
+    /// Follow the chain to the address it currently leads to
+    ///
+    /// # Safety
+    /// Every pointer along the chain must be valid to read, see [`memory::deep_pointer`]
+    pub unsafe fn resolve(&self) -> Result<*const (), MemError> {
+        let base = self.base().ok_or(MemError::BadAddress)?;
+        // SAFETY: safe to call as long as the safety conditions were met for this function
+        unsafe { memory::deep_pointer(base, &self.offsets) }
+    }
+}
+
+impl fmt::Display for PointerChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let offsets = self
+            .offsets
+            .iter()
+            .map(|offset| format!("{offset:#x}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        write!(f, "{}+{:#x} -> [{offsets}]", self.module, self.rva)
+    }
+}
+
+impl FromStr for PointerChain {
+    type Err = PointerScanError;
+
+    fn from_str(chain: &str) -> Result<Self, Self::Err> {
+        let hex = |num: &str| {
+            let num = num.trim();
+            let num = num.strip_prefix("0x").unwrap_or(num);
+            usize::from_str_radix(num, 16).map_err(|_| PointerScanError::Parse)
+        };
+
+        let (base, offsets) = chain
+            .trim()
+            .split_once(" -> ")
+            .ok_or(PointerScanError::Parse)?;
+
+        // module names may contain `+`, but rvas can't
+        let (module, rva) = base.rsplit_once('+').ok_or(PointerScanError::Parse)?;
+
+        let offsets = offsets
+            .strip_prefix('[')
+            .and_then(|offsets| offsets.strip_suffix(']'))
+            .ok_or(PointerScanError::Parse)?;
+
+        let offsets = offsets
+            .split(',')
+            .filter(|offset| !offset.trim().is_empty())
+            .map(hex)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let chain = Self {
+            module: module.to_owned(),
+            rva: hex(rva)?,
+            offsets,
+        };
+
+        Ok(chain)
+    }
+}
+
+/// A location found while searching back from the target
+#[derive(Debug)]
+struct Node {
+    addr: usize,
+    // (index of a node in the previous level, offset from this node's pointer to it)
+    links: Vec<(usize, usize)>,
+}
+
+/// Find chains of pointers which lead from static addresses inside loaded modules to `target`.
+///
+/// Each pointer in a chain points at most `max_offset` bytes before the next address in the chain,
+/// and chains are at most `max_depth` pointers long. Shorter chains are returned first, up to `limit` chains.
+///
+/// Every level of the search keeps at most `max_nodes` locations, which bounds memory use when many
+/// pointers are found. The search stops early once the levels so far already hold `limit` chains.
+///
+/// Pointers are only searched for in writable memory, since pointers to dynamic memory have to be
+/// written at some point. Heap addresses change between runs, but chains from a static address
+/// usually don't. Use [`intersect_chains`] on the results of multiple runs to find the stable ones.
+pub fn pointer_scan(
+    target: *const (),
+    max_depth: usize,
+    max_offset: usize,
+    limit: usize,
+    max_nodes: usize,
+) -> Result<Vec<PointerChain>, PointerScanError> {
+    if limit == 0 {
+        return Ok(Vec::new());
+    }
+
+    // the map is built first, so it doesn't contain the pointers in the module list
+    let pointers = pointer_map()?;
+
+    let mut modules = modules::enum_modules()?;
+    modules.sort_by_key(|module| module.base as usize);
+
+    let module_of = |addr: usize| {
+        let i = modules.partition_point(|module| module.end as usize <= addr);
+        modules.get(i).filter(|module| module.base as usize <= addr)
+    };
+
+    // search back from the target one level at a time. each level has every location which
+    // points near an address in the previous level
+    let mut levels = vec![vec![Node {
+        addr: target as usize,
+        links: Vec::new(),
+    }]];
+
+    // number of chains from each node of the previous level to the target
+    let mut paths = vec![1usize];
+    // number of chains which start at a static location in the levels so far
+    let mut found = 0usize;
+
+    for depth in 1..=max_depth {
+        let prev = levels.last().unwrap();
+
+        let mut level = Vec::<Node>::new();
+        // location -> index in level
+        let mut index = HashMap::new();
+
+        for (i, node) in prev.iter().enumerate() {
+            let low = node.addr.saturating_sub(max_offset);
+            let first = pointers.partition_point(|&(value, _)| value < low);
+
+            let near = pointers[first..]
+                .iter()
+                .take_while(|&&(value, _)| value <= node.addr);
+
+            for &(value, location) in near {
+                // only static locations can start a chain, so the last level doesn't need anything else
+                if depth == max_depth && module_of(location).is_none() {
+                    continue;
+                }
+
+                let idx = match index.get(&location) {
+                    Some(&idx) => idx,
+                    // the level is full, so new locations are dropped
+                    None if level.len() >= max_nodes => continue,
+                    None => {
+                        level.push(Node {
+                            addr: location,
+                            links: Vec::new(),
+                        });
+
+                        index.insert(location, level.len() - 1);
+                        level.len() - 1
+                    }
+                };
+
+                level[idx].links.push((i, node.addr - value));
+            }
+        }
+
+        if level.is_empty() {
+            break;
+        }
+
+        paths = level
+            .iter()
+            .map(|node| {
+                node.links
+                    .iter()
+                    .fold(0usize, |sum, &(i, _)| sum.saturating_add(paths[i]))
+            })
+            .collect();
+
+        for (node, &count) in level.iter().zip(&paths) {
+            if module_of(node.addr).is_some() {
+                found = found.saturating_add(count);
+            }
+        }
+
+        levels.push(level);
+
+        // deeper levels only have longer chains, which would come after these
+        if found >= limit {
+            break;
+        }
+    }
+
+    let mut chains = Vec::new();
+
+    for (depth, level) in levels.iter().enumerate().skip(1) {
+        for (i, node) in level.iter().enumerate() {
+            let Some(module) = module_of(node.addr) else {
+                continue;
+            };
+
+            collect_chains(&levels, module, depth, i, limit, &mut chains);
+
+            if chains.len() >= limit {
+                return Ok(chains);
+            }
+        }
+    }
+
+    Ok(chains)
+}
+
+/// Every aligned pointer in writable memory which points to readable memory, as (value, location)
+/// sorted by value.
+///
+/// The scan's own pointers are skipped: the segment lists, the map itself while it's built, and the
+/// stack of the thread running it
+fn pointer_map() -> Result<Vec<(usize, usize)>, PointerScanError> {
+    let segments = segments::enum_segments();
+
+    let readable = segments
+        .iter()
+        .filter(|segment| segment.prot.is_readable())
+        .map(|segment| segment.base as usize..segment.end as usize)
+        .collect::<Vec<Range<usize>>>();
+
+    let is_readable = |addr: usize| {
+        let i = readable.partition_point(|range| range.end <= addr);
+        readable.get(i).is_some_and(|range| range.contains(&addr))
+    };
+
+    // both lists are full of pointers to readable memory
+    let lists = [range_of(segments.as_slice()), range_of(readable.as_slice())];
+
+    let is_own = |addr: usize| lists.iter().any(|list| list.contains(&addr));
+
+    let writable = segments.iter().copied().filter(|segment| {
+        segment.prot.is_readable()
+            && segment.prot.is_writable()
+            && !chunks::is_current_stack(segment)
+    });
+
+    let mut pointers = ScanVec::new();
+    let mut error = None;
+
+    // segments are page aligned, so aligned pointers never cross into the next chunk
+    chunks::for_each_chunk(writable, 0, |start, data, _| {
+        let first = start.next_multiple_of(8) - start;
+
+        for offset in (first..data.len().saturating_sub(7)).step_by(8) {
+            let value = usize::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+            let location = start + offset;
+
+            if !is_readable(value) || is_own(location) {
+                continue;
+            }
+
+            if let Err(e) = pointers.push((value, location)) {
+                error = Some(e);
+                return ControlFlow::Break(());
+            }
+        }
+
+        ControlFlow::Continue(())
+    })?;
+
+    if let Some(e) = error {
+        return Err(e.into());
+    }
+
+    let mut pointers = pointers.as_slice().to_vec();
+    pointers.sort_unstable();
+
+    Ok(pointers)
+}
+
+/// The addresses a slice's items are stored at
+fn range_of<T>(slice: &[T]) -> Range<usize> {
+    let range = slice.as_ptr_range();
+    range.start as usize..range.end as usize
+}
+
+/// Walk every path from a static node down to the target
+fn collect_chains(
+    levels: &[Vec<Node>],
+    module: &Module,
+    depth: usize,
+    index: usize,
+    limit: usize,
+    chains: &mut Vec<PointerChain>,
+) {
+    let rva = levels[depth][index].addr - module.base as usize;
+
+    let mut stack = vec![(depth, index, Vec::new())];
+
+    while let Some((depth, index, offsets)) = stack.pop() {
+        if depth == 0 {
+            chains.push(PointerChain {
+                module: module.name.clone(),
+                rva,
+                offsets,
+            });
+
+            if chains.len() >= limit {
+                return;
+            }
+
+            continue;
+        }
+
+        for &(next, offset) in &levels[depth][index].links {
+            let mut offsets = offsets.clone();
+            offsets.push(offset);
+
+            stack.push((depth - 1, next, offsets));
+        }
+    }
+}
+
+/// Keep only the chains which are in both `a` and `b`.
+///
+/// A chain which was found again after the game restarted is likely to keep working.
+pub fn intersect_chains(a: &[PointerChain], b: &[PointerChain]) -> Vec<PointerChain> {
+    let b = b.iter().collect::<HashSet<_>>();
+    a.iter()
+        .filter(|chain| b.contains(chain))
+        .cloned()
+        .collect()
+}
+
+/// Save chains to a file, one per line
+pub fn save_chains<P: AsRef<Path>>(
+    path: P,
+    chains: &[PointerChain],
+) -> Result<(), PointerScanError> {
+    let mut data = Vec::new();
+    for chain in chains {
+        writeln!(data, "{chain}")?;
+    }
+
+    fs::write(path, data)?;
+
+    Ok(())
+}
+
+/// Load chains saved with [`save_chains`]
+pub fn load_chains<P: AsRef<Path>>(path: P) -> Result<Vec<PointerChain>, PointerScanError> {
+    fs::read_to_string(path)?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::hint;
+    use std::ptr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    fn chain(module: &str, rva: usize, offsets: &[usize]) -> PointerChain {
+        PointerChain {
+            module: module.to_owned(),
+            rva,
+            offsets: offsets.to_vec(),
+        }
+    }
+
+    #[test]
+    fn parse() {
+        let parse = |s: &str| s.parse::<PointerChain>().unwrap();
+
+        assert_eq!(
+            parse("game.exe+0x1a2b0 -> [0x10, 0x8, 0x3c]"),
+            chain("game.exe", 0x1A2B0, &[0x10, 0x8, 0x3C])
+        );
+
+        // no prefix, no spaces, and surrounding whitespace
+        assert_eq!(
+            parse("  game.exe+1A2B0 -> [10,8]\n"),
+            chain("game.exe", 0x1A2B0, &[0x10, 0x8])
+        );
+
+        // the module name contains `+`
+        assert_eq!(parse("c++.dll+0x20 -> [0x0]"), chain("c++.dll", 0x20, &[0]));
+
+        assert_eq!(parse("game.exe+0x20 -> []"), chain("game.exe", 0x20, &[]));
+    }
+
+    #[test]
+    fn parse_invalid() {
+        for chain in [
+            "",
+            "game.exe+0x20",
+            "game.exe -> [0x10]",
+            "game.exe+0x20 -> 0x10",
+            "game.exe+0x20 -> [0x10",
+            "game.exe+0xzz -> [0x10]",
+            "game.exe+0x20 -> [0x10, nope]",
+        ] {
+            assert!(
+                matches!(chain.parse::<PointerChain>(), Err(PointerScanError::Parse)),
+                "{chain:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn display_round_trip() {
+        for chain in [
+            chain("game.exe", 0x1A2B0, &[0x10, 0x8, 0x3C]),
+            chain("c++.dll", 0, &[0]),
+            chain("game.exe", 0x20, &[]),
+        ] {
+            let text = chain.to_string();
+            assert_eq!(text.parse::<PointerChain>().unwrap(), chain, "{text}");
+        }
+
+        assert_eq!(
+            chain("game.exe", 0x1A2B0, &[0x10, 0x3C]).to_string(),
+            "game.exe+0x1a2b0 -> [0x10, 0x3c]"
+        );
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = std::env::temp_dir().join(format!("chains-test-{}", std::process::id()));
+
+        let chains = [
+            chain("game.exe", 0x1A2B0, &[0x10, 0x8, 0x3C]),
+            chain("engine.dll", 0x400, &[0x28]),
+        ];
+
+        save_chains(&path, &chains).unwrap();
+        let loaded = load_chains(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded, chains);
+    }
+
+    #[test]
+    fn intersect() {
+        let a = [
+            chain("game.exe", 0x10, &[0x8]),
+            chain("game.exe", 0x20, &[0x8]),
+        ];
+        let b = [
+            chain("game.exe", 0x20, &[0x8]),
+            chain("game.exe", 0x30, &[0x8]),
+        ];
+
+        assert_eq!(intersect_chains(&a, &b), [chain("game.exe", 0x20, &[0x8])]);
+    }
+
+    #[test]
+    fn pointer_map_skips_own_pointers() {
+        let _lock = chunks::SCAN_LOCK.lock().unwrap();
+
+        // heap addresses are also in the allocator's own bookkeeping, static ones aren't
+        static TARGET: u64 = 0;
+
+        let target = ptr::addr_of!(TARGET) as usize;
+        let holder = Box::new(target);
+        let holder = ptr::addr_of!(*holder) as usize;
+
+        let pointers = pointer_map().unwrap();
+        let found = pointers
+            .iter()
+            .filter(|&&(value, _)| value == target)
+            .collect::<Vec<_>>();
+
+        assert_eq!(found, [&(target, holder)]);
+    }
+
+    #[test]
+    fn pointer_scan_caps_nodes() {
+        let _lock = chunks::SCAN_LOCK.lock().unwrap();
+
+        static TARGET: u64 = 0;
+        // statics in a module are where chains start. non-zero so they're in writable data
+        static HOLDERS: [AtomicUsize; 4] = [
+            AtomicUsize::new(1),
+            AtomicUsize::new(1),
+            AtomicUsize::new(1),
+            AtomicUsize::new(1),
+        ];
+
+        let target = ptr::addr_of!(TARGET);
+        for holder in &HOLDERS {
+            holder.store(target as usize, Ordering::Relaxed);
+        }
+        // nothing reads them, so they'd otherwise be optimized away
+        hint::black_box(&HOLDERS);
+
+        assert_eq!(
+            pointer_scan(target.cast(), 1, 0, 100, 100).unwrap().len(),
+            4
+        );
+        assert_eq!(pointer_scan(target.cast(), 1, 0, 100, 2).unwrap().len(), 2);
+        assert_eq!(pointer_scan(target.cast(), 3, 0, 3, 100).unwrap().len(), 3);
+        assert!(pointer_scan(target.cast(), 3, 0, 0, 100)
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,224 @@
+//! Scan every readable segment of the process
+
+use std::ops::{ControlFlow, Range};
+
+use super::{
+    chunks,
+    pattern::{Pattern, PatternError, Resolve},
+    region::{self, RegionScan},
+    Scan,
+};
+use crate::{
+    memory::MemError,
+    modules::{self, ModuleError},
+    segments::{self, Segment},
+};
+
+/// An error for [`sig_scan_process`]
+#[derive(Debug, thiserror::Error)]
+pub enum ProcessScanError {
+    /// the pattern failed to parse
+    #[error(transparent)]
+    Pattern(#[from] PatternError),
+    /// the loaded modules couldn't be listed
+    #[error(transparent)]
+    Module(#[from] ModuleError),
+    /// the scan buffer couldn't be allocated
+    #[error(transparent)]
+    Mem(#[from] MemError),
+    /// no module with this name is loaded
+    #[error("module `{0}` is not loaded")]
+    ModuleNotFound(String),
+}
+
+/// Which modules' memory [`ProcessFilter`] keeps
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum ModuleFilter {
+    /// all memory, whether it's in a module or not
+    #[default]
+    Any,
+    /// only memory inside of any loaded module
+    Only,
+    /// only memory outside of every loaded module, e.g. the heap and stacks
+    Exclude,
+    /// only memory inside of the module with this name
+    Named(String),
+}
+
+/// Which memory [`sig_scan_process`] searches.
+///
+/// Guard pages, no-access pages, and reserved memory are never searched.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProcessFilter {
+    /// skip segments which can be written to
+    pub skip_writable: bool,
+    /// only search executable segments if `true`, or non-executable segments if `false`
+    pub executable: Option<bool>,
+    /// which modules' memory to search
+    pub modules: ModuleFilter,
+    /// only search memory inside of this address range
+    pub range: Option<Range<usize>>,
+}
+
+impl ProcessFilter {
+    /// The parts of the process' segments which pass the filter, in ascending address order
+    fn segments(&self) -> Result<Vec<Segment>, ProcessScanError> {
+        let modules = match &self.modules {
+            ModuleFilter::Any => Vec::new(),
+            ModuleFilter::Only | ModuleFilter::Exclude => modules::enum_modules()?,
+            ModuleFilter::Named(name) => {
+                let module = modules::find_module(name)?
+                    .ok_or_else(|| ProcessScanError::ModuleNotFound(name.clone()))?;
+
+                vec![module]
+            }
+        };
+
+        let in_module = |segment: &Segment| {
+            modules.iter().any(|module| {
+                segment.base < module.end.cast_const().cast()
+                    && segment.end > module.base.cast_const().cast()
+            })
+        };
+
+        let segments = segments::enum_segments()
+            .into_iter()
+            .filter(|segment| {
+                // guard pages and reserved memory have no readable protection either
+                let prot = segment.prot;
+
+                prot.is_readable()
+                    && !(self.skip_writable && prot.is_writable())
+                    && self
+                        .executable
+                        .map_or(true, |executable| prot.is_executable() == executable)
+            })
+            .filter(|segment| match self.modules {
+                ModuleFilter::Any => true,
+                ModuleFilter::Only | ModuleFilter::Named(_) => in_module(segment),
+                ModuleFilter::Exclude => !in_module(segment),
+            })
+            .filter_map(|segment| {
+                let Some(range) = &self.range else {
+                    return Some(segment);
+                };
+
+                let base = (segment.base as usize).max(range.start);
+                let end = (segment.end as usize).min(range.end);
+
+                (base < end).then(|| Segment {
+                    base: base as _,
+                    end: end as _,
+                    size: end - base,
+                    ..segment
+                })
+            })
+            .collect();
+
+        Ok(segments)
+    }
+}
+
+/// Find the first occurrence of an IDA-style pattern anywhere in the process.
+///
+/// Every committed, readable segment which passes `filter` is searched in ascending address order.
+/// Memory is copied before it's searched, so pages which are freed or protected during the scan
+/// are skipped instead of crashing. This makes it safe to search the heap for data structures.
+pub fn sig_scan_process(
+    pattern: &str,
+    filter: &ProcessFilter,
+) -> Result<Option<RegionScan>, ProcessScanError> {
+    let scans = sig_scan_process_all(pattern, filter, 1)?;
+    Ok(scans.into_iter().next())
+}
+
+/// Find up to `limit` occurrences of an IDA-style pattern anywhere in the process.
+///
+/// Same as [`sig_scan_process`], but returns every match in ascending address order.
+/// Overlapping matches are included.
+pub fn sig_scan_process_all(
+    pattern: &str,
+    filter: &ProcessFilter,
+    limit: usize,
+) -> Result<Vec<RegionScan>, ProcessScanError> {
+    let pattern = Pattern::try_from(pattern)?;
+    if limit == 0 {
+        return Ok(Vec::new());
+    }
+
+    let segments = filter.segments()?;
+
+    // matches are searched for in a copy of the memory, so only resolve them once they're found
+    let unresolved = Pattern {
+        resolve: Resolve::Start,
+        ..pattern.clone()
+    };
+
+    let size = pattern.unpadded_size;
+    let mut scans = Vec::new();
+
+    chunks::for_each_chunk(segments, size.saturating_sub(1), |start, data, len| {
+        if data.len() < size {
+            return ControlFlow::Continue(());
+        }
+
+        // SAFETY: data is a valid slice
+        let found = unsafe { unresolved.scan_all(data.as_ptr(), data.len()) };
+
+        let found = found
+            .map(|scan| scan.addr as usize - data.as_ptr() as usize)
+            // matches starting in the overlap belong to the next chunk
+            .take_while(|&offset| offset < len)
+            .take(limit - scans.len())
+            .map(|offset| {
+                // SAFETY: the whole match is inside of data
+                let resolved = unsafe {
+                    pattern.resolve(Scan {
+                        addr: &data[offset],
+                    })
+                };
+                let addr = (resolved.addr as usize)
+                    .wrapping_sub(data.as_ptr() as usize)
+                    .wrapping_add(start);
+
+                Scan { addr: addr as _ }
+            })
+            .collect::<Vec<_>>();
+
+        scans.extend(found);
+
+        if scans.len() >= limit {
+            ControlFlow::Break(())
+        } else {
+            ControlFlow::Continue(())
+        }
+    })?;
+
+    if scans.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    Ok(region::owners(scans))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn range_clips_segments() {
+        let buffer = vec![0u8; 0x4000];
+        let range = buffer.as_ptr() as usize + 0x1000..buffer.as_ptr() as usize + 0x2000;
+
+        // every other segment of the process is outside of the range, and has to be dropped
+        let filter = ProcessFilter {
+            range: Some(range.clone()),
+            ..Default::default()
+        };
+
+        let segments = filter.segments().unwrap();
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].base as usize..segments[0].end as usize, range);
+        assert_eq!(segments[0].size, 0x1000);
+    }
+}
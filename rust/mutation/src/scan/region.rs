@@ -0,0 +1,165 @@
+//! Scan modules, their sections, and segments instead of raw address ranges
+
+use pelite::pe64::{Pe, PeView};
+
+use super::{Pattern, Scan};
+use crate::{
+    modules::{self, Module},
+    segments::Segment,
+};
+
+/// An error for [`Region`]
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum RegionError {
+    /// the module has no section with this name
+    #[error("module has no section named `{0}`")]
+    Section(String),
+}
+
+/// A range of memory to scan
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Region {
+    /// the start of the region
+    pub base: *const u8,
+    /// the size of the region
+    pub size: usize,
+}
+
+unsafe impl Send for Region {}
+unsafe impl Sync for Region {}
+
+impl From<&Module> for Region {
+    /// The module's whole image, including its headers and data
+    fn from(module: &Module) -> Self {
+        Self {
+            base: module.base,
+            size: module.end as usize - module.base as usize,
+        }
+    }
+}
+
+impl From<Segment> for Region {
+    fn from(segment: Segment) -> Self {
+        Self {
+            base: segment.base.cast(),
+            size: segment.size,
+        }
+    }
+}
+
+impl From<&Segment> for Region {
+    fn from(segment: &Segment) -> Self {
+        (*segment).into()
+    }
+}
+
+impl Region {
+    /// The module's PE sections with the given names, e.g. `.text`, in the order of `names`
+    pub fn sections<S: AsRef<str>>(module: &Module, names: &[S]) -> Result<Vec<Self>, RegionError> {
+        // this base address is crate private, so it is guaranteed
+        let base = module.handle.base;
+
+        // SAFETY: module field is crate private, it cannot be changed
+        //         and we only support 64-bit. Additionally, each module is backed by
+        //         an increased refcount, which keeps them valid for the duration of Module
+        let view = unsafe { PeView::module(base.cast()) };
+
+        names
+            .iter()
+            .map(|name| {
+                let name = name.as_ref();
+
+                let section = view
+                    .section_headers()
+                    .by_name(name)
+                    .ok_or_else(|| RegionError::Section(name.to_owned()))?;
+
+                let region = Self {
+                    base: base.wrapping_add(section.VirtualAddress as usize),
+                    size: section.VirtualSize as usize,
+                };
+
+                Ok(region)
+            })
+            .collect()
+    }
+}
+
+/// A match found in a [`Region`], along with the module it's in
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegionScan {
+    /// the address of a found match
+    pub addr: *const u8,
+    /// the name of the module the match is in, if any
+    pub module: Option<String>,
+    /// the address of the match relative to the module's base, if it's in a module
+    pub rva: Option<usize>,
+}
+
+unsafe impl Send for RegionScan {}
+unsafe impl Sync for RegionScan {}
+
+impl From<Scan> for RegionScan {
+    /// Find the module the match is in
+    fn from(scan: Scan) -> Self {
+        owners([scan]).pop().unwrap()
+    }
+}
+
+/// Find the module each match is in, listing the loaded modules only once
+pub(super) fn owners(scans: impl IntoIterator<Item = Scan>) -> Vec<RegionScan> {
+    let modules = modules::enum_modules().unwrap_or_default();
+
+    scans
+        .into_iter()
+        .map(|scan| {
+            let module = modules.iter().find(|module| {
+                (module.base.cast_const()..module.end.cast_const()).contains(&scan.addr)
+            });
+
+            RegionScan {
+                addr: scan.addr,
+                module: module.map(|module| module.name.clone()),
+                rva: module.map(|module| scan.addr as usize - module.base as usize),
+            }
+        })
+        .collect()
+}
+
+impl Pattern {
+    /// Find the first occurrence of the pattern in the regions, searching them in order
+    ///
+    /// # Safety
+    ///
+    /// * `regions` - are each valid for reads of their whole size
+    pub unsafe fn scan_regions(&self, regions: &[Region]) -> Option<RegionScan> {
+        let scan = regions.iter().find_map(|region| {
+            // SAFETY: safe to call as long as the safety conditions were met for this function
+            unsafe { self.scan(region.base, region.size) }
+        });
+
+        scan.map(Into::into)
+    }
+
+    /// Find up to `limit` occurrences of the pattern in the regions, searching them in order
+    ///
+    /// # Safety
+    ///
+    /// * `regions` - are each valid for reads of their whole size
+    pub unsafe fn scan_regions_all(&self, regions: &[Region], limit: usize) -> Vec<RegionScan> {
+        let scans = regions
+            .iter()
+            .flat_map(|region| {
+                // SAFETY: safe to call as long as the safety conditions were met for this function
+                unsafe { self.scan_all(region.base, region.size) }
+            })
+            .take(limit)
+            .collect::<Vec<_>>();
+
+        if scans.is_empty() {
+            return Vec::new();
+        }
+
+        owners(scans)
+    }
+}
@@ -0,0 +1,200 @@
+//! Generate unique signatures for an address
+
+use std::{ops::Range, slice};
+
+use pelite::{
+    image::IMAGE_SCN_MEM_EXECUTE,
+    pe64::{Pe, PeView},
+};
+
+use super::Pattern;
+use crate::{
+    asm::{self, FixupKind},
+    modules::Module,
+};
+
+/// Signatures are never grown past this many bytes
+const MAX_LEN: usize = 128;
+/// How far before the address to look for an earlier start, if the address has no unique signature
+const MAX_BACKTRACK: usize = 64;
+/// Matches are only tracked once there are at most this many of them, before that the code is rescanned
+const MAX_CANDIDATES: usize = 4096;
+
+/// An error for [make_signature]
+#[derive(Copy, Clone, Debug, thiserror::Error)]
+pub enum SignatureError {
+    /// the address is not inside one of the module's code sections
+    #[error("address is not in the module's code")]
+    NotCode,
+    /// no signature up to the max length was unique in the module
+    #[error("failed to make a unique signature")]
+    NotUnique,
+}
+
+/// A generated signature
+#[derive(Debug, Clone)]
+pub struct Signature {
+    /// the IDA-style signature
+    pub sig: String,
+    /// the offset of the address from the start of a match
+    pub offset: usize,
+}
+
+/// Make an IDA-style signature which uniquely finds `address` in the module's code.
+///
+/// Starting at `address`, instructions are added until the signature only matches once. Bytes
+/// which change between builds are wildcarded: relative branch displacements, rip-relative
+/// displacements, and immediates or displacements which point inside the module.
+///
+/// If no unique signature starts at `address`, an earlier instruction is used as the start instead.
+/// [`Signature::offset`] is how far `address` is from the start of a match.
+pub fn make_signature(module: &Module, address: *const u8) -> Result<Signature, SignatureError> {
+    let sections = code_sections(module);
+
+    let address = address as usize;
+    let section = sections
+        .iter()
+        .find(|section| section.contains(&address))
+        .ok_or(SignatureError::NotCode)?;
+
+    let module_range = module.base as usize..module.end as usize;
+
+    for start in starts(address, section) {
+        if let Some(sig) = unique_sig(start, section, &sections, &module_range) {
+            let signature = Signature {
+                sig,
+                offset: address - start,
+            };
+
+            return Ok(signature);
+        }
+    }
+
+    Err(SignatureError::NotUnique)
+}
+
+/// Address ranges of every executable section in the module
+pub(super) fn code_sections(module: &Module) -> Vec<Range<usize>> {
+    // this base address is crate private, so it is guaranteed
+    let base = module.handle.base;
+
+    // SAFETY: module field is crate private, it cannot be changed
+    //         and we only support 64-bit. Additionally, each module is backed by
+    //         an increased refcount, which keeps them valid for the duration of Module
+    let view = unsafe { PeView::module(base.cast()) };
+
+    view.section_headers()
+        .iter()
+        .filter(|section| section.Characteristics & IMAGE_SCN_MEM_EXECUTE != 0)
+        .map(|section| {
+            let start = base as usize + section.VirtualAddress as usize;
+            start..start + section.VirtualSize as usize
+        })
+        .collect()
+}
+
+/// Every address a signature for `address` can start at, best first.
+/// That's the address itself, then earlier instruction boundaries which lead up to it
+fn starts(address: usize, section: &Range<usize>) -> impl Iterator<Item = usize> + '_ {
+    let earlier = (1..=MAX_BACKTRACK)
+        .map(move |back| address.wrapping_sub(back))
+        .take_while(|&start| section.contains(&start))
+        .filter(move |&start| {
+            // SAFETY: start..address is inside of the code section
+            let code = unsafe { slice::from_raw_parts(start as *const u8, address - start) };
+
+            // disassembly stops at anything invalid, so this only adds up if every byte
+            // decodes and the last instruction ends exactly at the address
+            asm::decoded_len(code, start).is_ok_and(|len| len == address - start)
+        });
+
+    std::iter::once(address).chain(earlier)
+}
+
+/// Grow a signature from `start` one instruction at a time, until it only matches once
+fn unique_sig(
+    start: usize,
+    section: &Range<usize>,
+    sections: &[Range<usize>],
+    module_range: &Range<usize>,
+) -> Option<String> {
+    let mut data = Vec::new();
+    let mut mask = Vec::new();
+
+    // every match of the signature so far, along with the end of the section it's in
+    let mut candidates: Option<Vec<(usize, usize)>> = None;
+
+    let mut cursor = start;
+    while cursor < section.end && data.len() < MAX_LEN {
+        // SAFETY: cursor is inside of the code section, and an instruction is at most 16 bytes
+        let code =
+            unsafe { slice::from_raw_parts(cursor as *const u8, (section.end - cursor).min(16)) };
+
+        let Ok((size, fixups)) = asm::disassemble_fixups(code, cursor) else {
+            break;
+        };
+
+        let mut inst_mask = vec![0xFF; size];
+        for fixup in fixups {
+            let volatile = match fixup.kind {
+                FixupKind::Branch | FixupKind::RipRelative => true,
+                FixupKind::Immediate | FixupKind::Displacement => {
+                    module_range.contains(&(fixup.value as usize))
+                }
+            };
+
+            if volatile {
+                inst_mask[fixup.offset..fixup.offset + fixup.size].fill(0);
+            }
+        }
+
+        data.extend_from_slice(&code[..size]);
+        mask.extend_from_slice(&inst_mask);
+        cursor += size;
+
+        // trailing wildcards can't make a signature any more unique
+        if mask.last() != Some(&0xFF) {
+            continue;
+        }
+
+        match &mut candidates {
+            Some(candidates) => candidates.retain(|&(candidate, end)| {
+                candidate + data.len() <= end && {
+                    // SAFETY: the candidate is inside of a code section, and the signature fits in it
+                    let code = unsafe { slice::from_raw_parts(candidate as *const u8, data.len()) };
+
+                    code.iter()
+                        .zip(&data)
+                        .zip(&mask)
+                        .all(|((&byte, &data), &mask)| byte & mask == data & mask)
+                }
+            }),
+
+            None => {
+                let pattern = Pattern::new(data.clone(), mask.clone());
+
+                let found = sections
+                    .iter()
+                    .flat_map(|section| {
+                        // SAFETY: code sections are mapped for as long as the module is loaded
+                        let scans =
+                            unsafe { pattern.scan_all(section.start as *const u8, section.len()) };
+
+                        scans.map(|scan| (scan.addr as usize, section.end))
+                    })
+                    .take(MAX_CANDIDATES + 1)
+                    .collect::<Vec<_>>();
+
+                if found.len() <= MAX_CANDIDATES {
+                    candidates = Some(found);
+                }
+            }
+        }
+
+        if candidates.as_deref() == Some(&[(start, section.end)][..]) {
+            return Some(Pattern::new(data, mask).to_string());
+        }
+    }
+
+    None
+}
@@ -0,0 +1,60 @@
+//! Disassemble a region of code from start to end
+
+use std::{ops::ControlFlow, slice};
+
+use capstone::prelude::*;
+use capstone::Insn;
+
+use crate::asm::AsmError;
+
+/// How much code is disassembled at a time
+const WINDOW: usize = 0x10000;
+
+/// Disassemble `size` bytes at `addr` from start to end, calling `f` with each instruction.
+///
+/// Bytes which don't decode are skipped one at a time, and `f` is called with `None` for each of
+/// them. Disassembly stops early if `f` breaks.
+///
+/// # Safety
+/// `addr` must be valid for `size` bytes of reads
+pub(crate) unsafe fn sweep(
+    cs: &Capstone,
+    addr: usize,
+    size: usize,
+    mut f: impl FnMut(Option<&Insn>) -> Result<ControlFlow<()>, AsmError>,
+) -> Result<(), AsmError> {
+    let end = addr + size;
+    let mut cursor = addr;
+
+    while cursor < end {
+        let len = (end - cursor).min(WINDOW);
+        // SAFETY: cursor..cursor + len is inside of the region, which the caller guarantees is valid
+        let code = unsafe { slice::from_raw_parts(cursor as *const u8, len) };
+
+        // disassembly stops at the first invalid instruction, or one cut off by the window
+        let insts = cs.disasm_all(code, cursor as u64)?;
+
+        let mut decoded = 0;
+        for insn in insts.as_ref() {
+            decoded += insn.bytes().len();
+
+            if f(Some(insn))?.is_break() {
+                return Ok(());
+            }
+        }
+
+        // the next window starts at whatever stopped disassembly. if that's an invalid
+        // instruction it decodes to nothing, so skip over its first byte
+        if decoded == 0 {
+            if f(None)?.is_break() {
+                return Ok(());
+            }
+
+            decoded = 1;
+        }
+
+        cursor += decoded;
+    }
+
+    Ok(())
+}
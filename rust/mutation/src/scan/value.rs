@@ -0,0 +1,633 @@
+//! Search memory for typed values, and narrow the results down over multiple scans
+
+use std::{
+    fmt,
+    ops::{ControlFlow, Range},
+    slice,
+};
+
+use memchr::memmem;
+
+use super::chunks::{self, ScanVec, CHUNK_SIZE, PAGE_SIZE};
+use crate::{
+    memory::{self, MemError},
+    segments, Prot,
+};
+
+/// An error for [`ValueScan`]
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum ValueScanError {
+    /// a value's type is not the type being scanned for
+    #[error("value type does not match the scan's value type")]
+    TypeMismatch,
+    /// the filter compares against the previous results, but this is the first scan
+    #[error("filter needs the results of a previous scan")]
+    NoPrevious,
+    /// the filter orders values, which only works for numbers
+    #[error("filter is only supported for numbers")]
+    NotNumeric,
+    /// the stride is 0
+    #[error("stride must be non-zero")]
+    Stride,
+    /// an empty string was searched for
+    #[error("string must not be empty")]
+    Empty,
+    /// memory for the scan could not be allocated
+    #[error(transparent)]
+    Mem(#[from] MemError),
+}
+
+/// The type of a scanned value
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ValueType {
+    /// i8
+    I8,
+    /// i16
+    I16,
+    /// i32
+    I32,
+    /// i64
+    I64,
+    /// f32
+    F32,
+    /// f64
+    F64,
+    /// UTF-8 string
+    Utf8,
+    /// UTF-16 string
+    Utf16,
+}
+
+impl ValueType {
+    /// The byte size of the type. Strings have no fixed size, they're as long as the string searched for
+    pub fn size(self) -> Option<usize> {
+        let size = match self {
+            Self::I8 => 1,
+            Self::I16 => 2,
+            Self::I32 | Self::F32 => 4,
+            Self::I64 | Self::F64 => 8,
+            Self::Utf8 | Self::Utf16 => return None,
+        };
+
+        Some(size)
+    }
+
+    /// The natural alignment of the type, which is the default stride
+    pub fn align(self) -> usize {
+        match self {
+            Self::Utf8 => 1,
+            Self::Utf16 => 2,
+            _ => self.size().unwrap_or(1),
+        }
+    }
+
+    fn is_float(self) -> bool {
+        matches!(self, Self::F32 | Self::F64)
+    }
+
+    fn is_string(self) -> bool {
+        matches!(self, Self::Utf8 | Self::Utf16)
+    }
+
+    /// Read a number of this type from little endian bytes
+    fn number(self, bytes: &[u8]) -> Number {
+        let mut buf = [0; 8];
+        buf[..bytes.len()].copy_from_slice(bytes);
+
+        match self {
+            Self::I8 => Number::Int(bytes[0] as i8 as i64),
+            Self::I16 => Number::Int(i16::from_le_bytes([buf[0], buf[1]]) as i64),
+            Self::I32 => Number::Int(i32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as i64),
+            Self::F32 => Number::Float(f32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as f64),
+            Self::F64 => Number::Float(f64::from_le_bytes(buf)),
+            _ => Number::Int(i64::from_le_bytes(buf)),
+        }
+    }
+}
+
+/// A typed value
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    /// i8
+    I8(i8),
+    /// i16
+    I16(i16),
+    /// i32
+    I32(i32),
+    /// i64
+    I64(i64),
+    /// f32
+    F32(f32),
+    /// f64
+    F64(f64),
+    /// UTF-8 string
+    Utf8(String),
+    /// UTF-16 string
+    Utf16(String),
+}
+
+impl Value {
+    /// The type of the value
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            Self::I8(_) => ValueType::I8,
+            Self::I16(_) => ValueType::I16,
+            Self::I32(_) => ValueType::I32,
+            Self::I64(_) => ValueType::I64,
+            Self::F32(_) => ValueType::F32,
+            Self::F64(_) => ValueType::F64,
+            Self::Utf8(_) => ValueType::Utf8,
+            Self::Utf16(_) => ValueType::Utf16,
+        }
+    }
+
+    /// The value as it's stored in memory
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Self::I8(v) => v.to_le_bytes().to_vec(),
+            Self::I16(v) => v.to_le_bytes().to_vec(),
+            Self::I32(v) => v.to_le_bytes().to_vec(),
+            Self::I64(v) => v.to_le_bytes().to_vec(),
+            Self::F32(v) => v.to_le_bytes().to_vec(),
+            Self::F64(v) => v.to_le_bytes().to_vec(),
+            Self::Utf8(v) => v.as_bytes().to_vec(),
+            Self::Utf16(v) => v.encode_utf16().flat_map(u16::to_le_bytes).collect(),
+        }
+    }
+
+    /// Read a value of a type from the bytes it's stored as in memory.
+    /// Invalid strings are converted lossily
+    pub fn from_bytes(value_type: ValueType, bytes: &[u8]) -> Self {
+        match value_type {
+            ValueType::Utf8 => Self::Utf8(String::from_utf8_lossy(bytes).into_owned()),
+
+            ValueType::Utf16 => {
+                let units = bytes
+                    .chunks_exact(2)
+                    .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+                    .collect::<Vec<_>>();
+
+                Self::Utf16(String::from_utf16_lossy(&units))
+            }
+
+            ty => match (ty, ty.number(bytes)) {
+                (ValueType::I8, Number::Int(v)) => Self::I8(v as i8),
+                (ValueType::I16, Number::Int(v)) => Self::I16(v as i16),
+                (ValueType::I32, Number::Int(v)) => Self::I32(v as i32),
+                (ValueType::F32, Number::Float(v)) => Self::F32(v as f32),
+                (ValueType::F64, Number::Float(v)) => Self::F64(v),
+                (_, Number::Int(v)) => Self::I64(v),
+                (_, Number::Float(v)) => Self::F64(v),
+            },
+        }
+    }
+}
+
+/// A number widened to compare it
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
+enum Number {
+    Int(i64),
+    Float(f64),
+}
+
+/// Which values a scan keeps
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    /// values equal to this. floats may differ by the scan's tolerance
+    Exact(Value),
+    /// numbers in this inclusive range
+    Between(Value, Value),
+    /// values which changed since the previous scan
+    Changed,
+    /// values which didn't change since the previous scan
+    Unchanged,
+    /// numbers which are bigger than in the previous scan
+    Increased,
+    /// numbers which are smaller than in the previous scan
+    Decreased,
+}
+
+/// Compares values against a filter
+struct Matcher<'a> {
+    value_type: ValueType,
+    tolerance: f64,
+    filter: &'a Filter,
+    // the filter's values as bytes and numbers
+    exact: Vec<u8>,
+    bounds: Option<(Number, Number)>,
+}
+
+impl<'a> Matcher<'a> {
+    fn new(value_type: ValueType, tolerance: f64, filter: &'a Filter) -> Self {
+        let (exact, bounds) = match filter {
+            Filter::Exact(value) => {
+                let exact = value.to_bytes();
+                let bound = value_type.number(&exact[..exact.len().min(8)]);
+
+                (exact, Some((bound, bound)))
+            }
+
+            Filter::Between(low, high) => {
+                let low = value_type.number(&low.to_bytes());
+                let high = value_type.number(&high.to_bytes());
+
+                (Vec::new(), Some((low, high)))
+            }
+
+            _ => (Vec::new(), None),
+        };
+
+        Self {
+            value_type,
+            tolerance,
+            filter,
+            exact,
+            bounds,
+        }
+    }
+
+    /// Whether `range` overlaps the filter's values, or the matcher's copy of them
+    fn is_own(&self, range: Range<usize>) -> bool {
+        let overlaps = |bytes: &[u8]| {
+            let own = bytes.as_ptr_range();
+            !bytes.is_empty() && range.start < own.end as usize && (own.start as usize) < range.end
+        };
+
+        let string = match self.filter {
+            Filter::Exact(Value::Utf8(string) | Value::Utf16(string)) => string.as_bytes(),
+            _ => &[],
+        };
+
+        overlaps(&self.exact) || overlaps(string)
+    }
+
+    /// Whether an exact match can be found by comparing bytes
+    fn is_bytewise(&self) -> bool {
+        matches!(self.filter, Filter::Exact(_)) && !self.value_type.is_float()
+    }
+
+    /// Whether two floats are equal within the tolerance
+    fn close(&self, a: Number, b: Number) -> bool {
+        match (a, b) {
+            (Number::Float(a), Number::Float(b)) => (a - b).abs() <= self.tolerance,
+            (a, b) => a == b,
+        }
+    }
+
+    fn is_match(&self, current: &[u8], previous: &[u8]) -> bool {
+        let ty = self.value_type;
+        let number = |bytes: &[u8]| ty.number(bytes);
+
+        match self.filter {
+            Filter::Exact(_) if !ty.is_float() => current == self.exact,
+            Filter::Exact(_) => self
+                .bounds
+                .is_some_and(|(exact, _)| self.close(number(current), exact)),
+
+            Filter::Between(..) => self.bounds.is_some_and(|(low, high)| {
+                let current = number(current);
+                low <= current && current <= high
+            }),
+
+            Filter::Changed => !self.unchanged(current, previous),
+            Filter::Unchanged => self.unchanged(current, previous),
+            Filter::Increased => number(current) > number(previous),
+            Filter::Decreased => number(current) < number(previous),
+        }
+    }
+
+    fn unchanged(&self, current: &[u8], previous: &[u8]) -> bool {
+        current == previous
+            || self.value_type.is_float()
+                && self.close(
+                    self.value_type.number(current),
+                    self.value_type.number(previous),
+                )
+    }
+}
+
+/// A Cheat Engine style value scan.
+///
+/// The first scan searches every readable segment for a value, and keeps every address it was found at.
+/// Each scan after that narrows those addresses down by comparing them against a value, or against
+/// their value in the previous scan.
+///
+/// # Example
+///
+/// ```rust,ignore
+/// let mut scan = ValueScan::new(ValueType::I32);
+/// scan.scan(Filter::Exact(Value::I32(100)))?;
+///
+/// // take some damage in game
+/// scan.scan(Filter::Decreased)?;
+///
+/// for (addr, value) in scan.results() {
+///     println!("{addr:?} = {value:?}");
+/// }
+/// ```
+pub struct ValueScan {
+    value_type: ValueType,
+    /// only addresses which are a multiple of this are searched. defaults to the type's alignment
+    pub stride: usize,
+    /// how far apart floats can be while still being equal. defaults to 0
+    pub tolerance: f64,
+    /// only search writable memory, which is where game state lives. defaults to true
+    pub writable_only: bool,
+    // byte size of each value, known once the first scan is done
+    size: usize,
+    addrs: Vec<usize>,
+    // the value of each address as of the last scan, `size` bytes each
+    values: Vec<u8>,
+    scans: usize,
+}
+
+impl fmt::Debug for ValueScan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ValueScan")
+            .field("value_type", &self.value_type)
+            .field("stride", &self.stride)
+            .field("tolerance", &self.tolerance)
+            .field("writable_only", &self.writable_only)
+            .field("results", &self.addrs.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl ValueScan {
+    /// Create a new scan for a type of value
+    pub fn new(value_type: ValueType) -> Self {
+        Self {
+            value_type,
+            stride: value_type.align(),
+            tolerance: 0.0,
+            writable_only: true,
+            size: 0,
+            addrs: Vec::new(),
+            values: Vec::new(),
+            scans: 0,
+        }
+    }
+
+    /// The type of value being scanned for
+    pub fn value_type(&self) -> ValueType {
+        self.value_type
+    }
+
+    /// How many scans were done
+    pub fn scans(&self) -> usize {
+        self.scans
+    }
+
+    /// How many addresses are left
+    pub fn len(&self) -> usize {
+        self.addrs.len()
+    }
+
+    /// Whether no addresses are left
+    pub fn is_empty(&self) -> bool {
+        self.addrs.is_empty()
+    }
+
+    /// Every address left, with its value as of the last scan
+    pub fn results(&self) -> impl Iterator<Item = (*const u8, Value)> + '_ {
+        self.addrs
+            .iter()
+            .zip(self.values.chunks_exact(self.size.max(1)))
+            .map(|(&addr, bytes)| (addr as *const u8, Value::from_bytes(self.value_type, bytes)))
+    }
+
+    /// Forget every result, so the next scan is a first scan again
+    pub fn reset(&mut self) {
+        self.size = 0;
+        self.addrs = Vec::new();
+        self.values = Vec::new();
+        self.scans = 0;
+    }
+
+    /// Run a scan, returning how many addresses are left.
+    ///
+    /// The first scan can only be [`Filter::Exact`] or [`Filter::Between`]. Strings can only be
+    /// compared with [`Filter::Exact`], [`Filter::Changed`], and [`Filter::Unchanged`].
+    pub fn scan(&mut self, filter: Filter) -> Result<usize, ValueScanError> {
+        if self.stride == 0 {
+            return Err(ValueScanError::Stride);
+        }
+
+        match &filter {
+            Filter::Exact(value) if value.value_type() != self.value_type => {
+                return Err(ValueScanError::TypeMismatch)
+            }
+
+            Filter::Exact(value) if value.to_bytes().is_empty() => {
+                return Err(ValueScanError::Empty)
+            }
+
+            Filter::Between(low, high)
+                if low.value_type() != self.value_type || high.value_type() != self.value_type =>
+            {
+                return Err(ValueScanError::TypeMismatch)
+            }
+
+            Filter::Between(..) | Filter::Increased | Filter::Decreased
+                if self.value_type.is_string() =>
+            {
+                return Err(ValueScanError::NotNumeric)
+            }
+
+            Filter::Changed | Filter::Unchanged | Filter::Increased | Filter::Decreased
+                if self.scans == 0 =>
+            {
+                return Err(ValueScanError::NoPrevious)
+            }
+
+            _ => (),
+        }
+
+        let matcher = Matcher::new(self.value_type, self.tolerance, &filter);
+
+        if self.scans == 0 {
+            self.size = match &filter {
+                Filter::Exact(value) if self.value_type.is_string() => value.to_bytes().len(),
+                _ => self.value_type.size().unwrap_or(0),
+            };
+
+            self.first_scan(&matcher)?;
+        } else {
+            self.next_scan(&matcher)?;
+        }
+
+        self.scans += 1;
+
+        Ok(self.addrs.len())
+    }
+
+    /// Search every readable segment.
+    ///
+    /// The scan's own copies of the value are skipped: the results it found so far, the bytes it
+    /// searches for, and the stack of the thread running it. Copies made by the caller, e.g. the
+    /// value a script passed in, can still be found
+    fn first_scan(&mut self, matcher: &Matcher) -> Result<(), ValueScanError> {
+        let segments = segments::enum_segments().into_iter().filter(|segment| {
+            segment.prot.is_readable()
+                && (!self.writable_only || segment.prot.is_writable())
+                && !chunks::is_current_stack(segment)
+        });
+
+        let size = self.size;
+        let stride = self.stride;
+        let mut addrs = ScanVec::new();
+        let mut values = ScanVec::new();
+        let mut error = None;
+
+        chunks::for_each_chunk(segments, size - 1, |start, data, _| {
+            if data.len() < size {
+                return ControlFlow::Continue(());
+            }
+
+            let mut push = |offset: usize| {
+                let addr = start + offset;
+                if matcher.is_own(addr..addr + size) {
+                    return ControlFlow::Continue(());
+                }
+
+                let pushed = addrs
+                    .push(addr)
+                    .and_then(|_| values.extend_from_slice(&data[offset..offset + size]));
+
+                match pushed {
+                    Ok(()) => ControlFlow::Continue(()),
+                    Err(e) => {
+                        error = Some(e);
+                        ControlFlow::Break(())
+                    }
+                }
+            };
+
+            if matcher.is_bytewise() {
+                for offset in memmem::find_iter(data, &matcher.exact) {
+                    if (start + offset) % stride == 0 {
+                        push(offset)?;
+                    }
+                }
+            } else {
+                let first = start.next_multiple_of(stride) - start;
+
+                for offset in (first..=data.len() - size).step_by(stride) {
+                    if matcher.is_match(&data[offset..offset + size], &[]) {
+                        push(offset)?;
+                    }
+                }
+            }
+
+            ControlFlow::Continue(())
+        })?;
+
+        if let Some(e) = error {
+            return Err(e.into());
+        }
+
+        self.addrs = addrs.as_slice().to_vec();
+        self.values = values.as_slice().to_vec();
+
+        Ok(())
+    }
+
+    /// Compare every address left against the filter, and update their values
+    fn next_scan(&mut self, matcher: &Matcher) -> Result<(), ValueScanError> {
+        let size = self.size;
+
+        let alloc = memory::alloc(CHUNK_SIZE, Prot::RW)?;
+        // SAFETY: the allocation is valid for CHUNK_SIZE bytes, and only used through this slice
+        let buf = unsafe { slice::from_raw_parts_mut(alloc.addr(), CHUNK_SIZE) };
+        // the memory currently in buf. addresses are sorted, so each read covers many of them
+        let mut window = 0..0;
+
+        let mut kept = 0;
+        for i in 0..self.addrs.len() {
+            let addr = self.addrs[i];
+
+            if addr < window.start || addr + size > window.end {
+                window = read_window(addr, size, buf);
+            }
+
+            // the memory isn't readable anymore
+            if window.is_empty() {
+                continue;
+            }
+
+            let offset = addr - window.start;
+            let current = &buf[offset..offset + size];
+
+            if matcher.is_match(current, &self.values[i * size..(i + 1) * size]) {
+                self.addrs[kept] = addr;
+                self.values[kept * size..(kept + 1) * size].copy_from_slice(current);
+                kept += 1;
+            }
+        }
+
+        self.addrs.truncate(kept);
+        self.values.truncate(kept * size);
+
+        Ok(())
+    }
+}
+
+/// Read as much memory from `addr` into buf as possible, returning the range that was read.
+/// The range is empty if the value at `addr` couldn't be read
+fn read_window(addr: usize, size: usize, buf: &mut [u8]) -> Range<usize> {
+    if memory::read_bytes_checked(addr as _, buf).is_ok() {
+        return addr..addr + buf.len();
+    }
+
+    // the rest of the page might still be readable
+    let end = (addr + size).next_multiple_of(PAGE_SIZE);
+    if memory::read_bytes_checked(addr as _, &mut buf[..end - addr]).is_ok() {
+        return addr..end;
+    }
+
+    0..0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr;
+
+    use super::*;
+
+    #[test]
+    fn matcher_owns_its_copies() {
+        let filter = Filter::Exact(Value::Utf16("health".to_owned()));
+        let matcher = Matcher::new(ValueType::Utf16, 0.0, &filter);
+
+        let Filter::Exact(Value::Utf16(string)) = &filter else {
+            unreachable!()
+        };
+
+        for bytes in [matcher.exact.as_slice(), string.as_bytes()] {
+            let range = bytes.as_ptr_range();
+            let (start, end) = (range.start as usize, range.end as usize);
+
+            assert!(matcher.is_own(start..end));
+            // a match which only partly overlaps is still a copy
+            assert!(matcher.is_own(start - 1..start + 1));
+            assert!(matcher.is_own(end - 1..end + 1));
+            assert!(!matcher.is_own(end..end + 2));
+        }
+    }
+
+    #[test]
+    fn first_scan_finds_live_value() {
+        let _lock = chunks::SCAN_LOCK.lock().unwrap();
+
+        let unique = 0x5EED_1234_5678_9ABC;
+        let value = Box::new(unique);
+
+        let mut scan = ValueScan::new(ValueType::I64);
+        scan.scan(Filter::Exact(Value::I64(unique))).unwrap();
+
+        // freed temporaries can still hold the value, so only the live copy is checked for.
+        // the scan's own copy being skipped is checked by matcher_owns_its_copies
+        let found = scan.results().collect::<Vec<_>>();
+        assert!(found.contains(&(ptr::addr_of!(*value).cast(), Value::I64(unique))));
+    }
+}
@@ -0,0 +1,162 @@
+//! Find the instructions which reference an address
+
+use std::ops::ControlFlow;
+
+use capstone::arch::{x86::X86OperandType, ArchOperand};
+use capstone::prelude::*;
+use capstone::{Insn, InsnGroupId, InsnGroupType, RegIdInt};
+
+use super::{signature::code_sections, sweep, Pattern};
+use crate::{
+    asm::{self, AsmError, Inst},
+    modules::Module,
+};
+
+/// How an instruction references an address
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum XrefKind {
+    /// a rip-relative memory operand, e.g. `lea rcx, [rip + 0x1234]`
+    RipRelative,
+    /// a relative call or jump
+    Branch,
+    /// an immediate or memory operand holding the absolute address, e.g. `mov rcx, 0x140001234`
+    Absolute,
+}
+
+/// An instruction which references an address
+#[derive(Debug, Clone)]
+pub struct Xref {
+    /// the address of the instruction
+    pub addr: *const u8,
+    /// how the instruction references the address
+    pub kind: XrefKind,
+    /// the instruction
+    pub inst: Inst,
+}
+
+unsafe impl Send for Xref {}
+unsafe impl Sync for Xref {}
+
+/// Find every instruction in the module's code which references `target`.
+///
+/// The module's code sections are disassembled from start to end, and rip-relative memory
+/// operands, relative calls and jumps, and absolute immediates or displacements are compared against
+/// `target`. Bytes which don't decode are skipped one at a time, so data inside of code sections
+/// may rarely produce a false xref.
+pub fn find_xrefs(module: &Module, target: *const u8) -> Result<Vec<Xref>, AsmError> {
+    xrefs_to(module, &[target as u64])
+}
+
+/// Find every instruction in the module which references a string.
+///
+/// Every nul terminated copy of `string` in the module is found, both as ASCII and as UTF-16,
+/// and then the xrefs to all of them are returned, in ascending address order.
+pub fn find_string_xrefs(module: &Module, string: &str) -> Result<Vec<Xref>, AsmError> {
+    let ascii = string.bytes().chain([0]).collect::<Vec<_>>();
+    let utf16 = string
+        .encode_utf16()
+        .chain([0])
+        .flat_map(u16::to_le_bytes)
+        .collect::<Vec<_>>();
+
+    let size = module.end as usize - module.base as usize;
+
+    let mut targets = Vec::new();
+    for data in [ascii, utf16] {
+        let pattern = Pattern::from_data(&data);
+
+        // SAFETY: the module's image is mapped for as long as the module is loaded
+        let scans = unsafe { pattern.scan_all(module.base, size) };
+        targets.extend(scans.map(|scan| scan.addr as u64));
+    }
+
+    if targets.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    xrefs_to(module, &targets)
+}
+
+/// Find every instruction in the module's code which references any of `targets`
+fn xrefs_to(module: &Module, targets: &[u64]) -> Result<Vec<Xref>, AsmError> {
+    let mut targets = targets.to_vec();
+    targets.sort_unstable();
+
+    let cs = asm::capstone()?;
+    let rip = RegId(arch::x86::X86Reg::X86_REG_RIP as RegIdInt);
+
+    let mut xrefs = Vec::new();
+
+    for section in code_sections(module) {
+        let visit = |insn: Option<&Insn>| {
+            if let Some(insn) = insn {
+                if let Some(kind) = reference(&cs, insn, rip, &targets)? {
+                    let xref = Xref {
+                        addr: insn.address() as _,
+                        kind,
+                        inst: Inst::new(&cs, insn),
+                    };
+
+                    xrefs.push(xref);
+                }
+            }
+
+            Ok(ControlFlow::Continue(()))
+        };
+
+        // SAFETY: code sections are mapped for as long as the module is loaded
+        unsafe { sweep::sweep(&cs, section.start, section.len(), visit)? };
+    }
+
+    Ok(xrefs)
+}
+
+/// How the instruction references one of `targets`, if it does
+fn reference(
+    cs: &Capstone,
+    insn: &Insn,
+    rip: RegId,
+    targets: &[u64],
+) -> Result<Option<XrefKind>, AsmError> {
+    let detail = cs.insn_detail(insn)?;
+    let next = insn.address() + insn.bytes().len() as u64;
+
+    for op in detail.arch_detail().operands() {
+        let ArchOperand::X86Operand(op) = op else {
+            continue;
+        };
+
+        let (value, kind) = match op.op_type {
+            X86OperandType::Mem(mem) if mem.base() == rip => {
+                (next.wrapping_add(mem.disp() as u64), XrefKind::RipRelative)
+            }
+
+            X86OperandType::Mem(mem) if mem.base().0 == 0 && mem.index().0 == 0 => {
+                (mem.disp() as u64, XrefKind::Absolute)
+            }
+
+            // capstone reports relative branches as the absolute target
+            X86OperandType::Imm(imm) => {
+                let relative = detail.groups().iter().any(|group| {
+                    *group == InsnGroupId(InsnGroupType::CS_GRP_BRANCH_RELATIVE as u8)
+                });
+
+                let kind = if relative {
+                    XrefKind::Branch
+                } else {
+                    XrefKind::Absolute
+                };
+
+                (imm as u64, kind)
+            }
+
+            _ => continue,
+        };
+
+        if targets.binary_search(&value).is_ok() {
+            return Ok(Some(kind));
+        }
+    }
+
+    Ok(None)
+}
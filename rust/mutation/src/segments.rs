@@ -23,15 +23,11 @@ unsafe impl Send for Segment {}
 unsafe impl Sync for Segment {}
 
 fn enum_segments_cb(mut cb: impl FnMut(Segment) -> bool) {
-    let mut address = 0;
+    let mut address = 0usize;
     let mut mem_info = MEMORY_BASIC_INFORMATION::default();
 
-    let mut written = 0;
-    while written > 0 {
-        // conditions at top
-        address += mem_info.RegionSize;
-
-        written = unsafe {
+    loop {
+        let written = unsafe {
             VirtualQuery(
                 Some(address as _),
                 &mut mem_info,
@@ -39,15 +35,22 @@ fn enum_segments_cb(mut cb: impl FnMut(Segment) -> bool) {
             )
         };
 
+        // past the highest address
+        if written == 0 {
+            break;
+        }
+
+        address = mem_info.BaseAddress as usize + mem_info.RegionSize;
+
         if mem_info.State == MEM_FREE {
             continue;
         }
 
         let segment = Segment {
             base: mem_info.BaseAddress.cast(),
-            end: unsafe { mem_info.BaseAddress.add(mem_info.RegionSize).cast() },
+            end: address as _,
             size: mem_info.RegionSize,
-            prot: mem_info.AllocationProtect.into(),
+            prot: mem_info.Protect.into(),
         };
 
         if cb(segment) {
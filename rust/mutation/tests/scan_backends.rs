@@ -0,0 +1,132 @@
+//! Differential tests making sure every scan backend returns the same results
+
+use mutation::scan::{Backend, Pattern};
+
+/// Small deterministic xorshift rng, so failures are reproducible
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, max: usize) -> usize {
+        (self.next() % max as u64) as usize
+    }
+
+    /// A byte from a small alphabet, so random patterns actually match
+    fn byte(&mut self) -> u8 {
+        const ALPHABET: [u8; 4] = [0x00, 0x0F, 0xF0, 0xFF];
+        ALPHABET[self.below(ALPHABET.len())]
+    }
+}
+
+/// Make a random IDA-style sig, with some bytes and nibbles wildcarded
+fn random_sig(rng: &mut Rng, bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| match rng.below(8) {
+            0 => "??".to_owned(),
+            1 => format!("{:X}?", byte >> 4),
+            2 => format!("?{:X}", byte & 0xF),
+            _ => format!("{byte:02X}"),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Reference implementation, every offset where the sig fully fits in the binary and matches
+fn reference(sig: &str, binary: &[u8]) -> Vec<usize> {
+    let (data, mask): (Vec<u8>, Vec<u8>) = sig
+        .split(' ')
+        .filter(|byte| !byte.is_empty())
+        .map(|byte| {
+            let nibble = |c: char| c.to_digit(16).map_or((0, 0), |n| (n as u8, 0xF));
+            let (hi, hi_mask) = nibble(byte.chars().next().unwrap());
+            let (lo, lo_mask) = nibble(byte.chars().nth(1).unwrap());
+            ((hi << 4) | lo, (hi_mask << 4) | lo_mask)
+        })
+        .unzip();
+
+    (0..binary.len())
+        .filter(|&start| {
+            binary.get(start..start + data.len()).is_some_and(|window| {
+                window
+                    .iter()
+                    .zip(&data)
+                    .zip(&mask)
+                    .all(|((&byte, &data), &mask)| byte & mask == data & mask)
+            })
+        })
+        .collect()
+}
+
+#[test]
+fn backends_match_reference() {
+    let mut rng = Rng(0x2545_F491_4F6C_DD1D);
+
+    let backends = Backend::ALL
+        .into_iter()
+        .filter(|backend| backend.is_supported())
+        .collect::<Vec<_>>();
+
+    for _ in 0..5000 {
+        // exactly sized, so a read past the end is caught by tools like miri or asan
+        let binary = (0..rng.below(300)).map(|_| rng.byte()).collect::<Vec<_>>();
+
+        // long enough to span multiple simd units
+        let len = rng.below(80);
+        let bytes = if !binary.is_empty() && rng.below(2) == 0 {
+            // take the pattern from the binary itself so it's guaranteed to match
+            let start = rng.below(binary.len());
+            binary[start..(start + len).min(binary.len())].to_vec()
+        } else {
+            (0..len).map(|_| rng.byte()).collect()
+        };
+
+        let sig = random_sig(&mut rng, &bytes);
+        let pattern = sig.parse::<Pattern>().unwrap();
+
+        let expected = reference(&sig, &binary);
+
+        for &backend in &backends {
+            let base = binary.as_ptr() as usize;
+
+            let first = unsafe { pattern.scan_with(backend, binary.as_ptr(), binary.len()) };
+            let first = first.map(|scan| scan.addr as usize - base);
+            assert_eq!(
+                first,
+                expected.first().copied(),
+                "{backend:?} first match of `{sig}` in {binary:02X?}"
+            );
+
+            let all = unsafe { pattern.scan_all_with(backend, binary.as_ptr(), binary.len()) };
+            let all = all
+                .map(|scan| scan.addr as usize - base)
+                .collect::<Vec<_>>();
+            assert_eq!(
+                all, expected,
+                "{backend:?} matches of `{sig}` in {binary:02X?}"
+            );
+        }
+    }
+}
+
+#[test]
+fn match_at_end_of_region() {
+    let binary = [0x11, 0x22, 0x33, 0x44];
+    let pattern = "33 44".parse::<Pattern>().unwrap();
+    let overhanging = "44 55".parse::<Pattern>().unwrap();
+
+    for backend in Backend::ALL.into_iter().filter(|b| b.is_supported()) {
+        let scan = unsafe { pattern.scan_with(backend, binary.as_ptr(), binary.len()) };
+        assert_eq!(scan.map(|s| s.addr), Some(binary[2..].as_ptr()));
+
+        // a match can't continue past the end of the region
+        let scan = unsafe { overhanging.scan_with(backend, binary.as_ptr(), binary.len()) };
+        assert!(scan.is_none(), "{backend:?}");
+    }
+}
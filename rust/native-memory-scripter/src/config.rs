@@ -8,6 +8,8 @@ use serde::{Deserialize, Serialize};
 pub struct Config {
     pub dev: Dev,
     pub log: Log,
+    #[serde(default)]
+    pub scan: Scan,
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
@@ -26,6 +28,18 @@ pub struct Log {
     pub targets: bool,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Scan {
+    /// allow scripts to cache resolved sigs on disk
+    pub cache: bool,
+}
+
+impl Default for Scan {
+    fn default() -> Self {
+        Self { cache: true }
+    }
+}
+
 impl Config {
     /// Load a config file
     /// If path doesn't exist, creates and saves default config
@@ -45,6 +59,7 @@ impl Config {
                     level: "info".to_owned(),
                     targets: false,
                 },
+                scan: Scan { cache: true },
             };
 
             let serialized = toml::to_string_pretty(&config)?;
@@ -242,6 +242,8 @@ fn run_interpreter<R>(settings: Settings, enter: impl FnOnce(&VirtualMachine) ->
             error!("Interpreter enter error:\n{data}");
         });
     }
+
+    crate::modules::scan::scan::save_sig_cache();
 }
 
 #[derive(Copy, Clone)]
@@ -26,6 +26,7 @@ declare_plugin! {
 }
 
 static MODULE_HANDLE: OnceLock<HINSTANCE> = OnceLock::new();
+static CONFIG: OnceLock<Config> = OnceLock::new();
 
 // Dll entry point
 #[no_mangle]
@@ -81,6 +82,8 @@ fn pre_init(module: HINSTANCE) -> Result<()> {
     // set up our actual log file handling
     setup_logging(module, &config).context("failed to setup logging")?;
 
+    _ = CONFIG.set(config);
+
     Ok(())
 }
 
@@ -2,13 +2,27 @@ use rustpython_vm::pymodule;
 
 #[pymodule]
 pub mod asm {
-    use mutation::asm::{self, Inst};
+    use std::{collections::HashMap, sync::Mutex};
+
+    use mutation::{
+        asm::{
+            self, BasicBlock, Cave, DisassembleIter, EdgeKind, Function, Inst, MemOperand,
+            ModuleResolver, Operand, SymbolResolver,
+        },
+        patch::Patch,
+    };
     use rustpython_vm::{
-        builtins::PyByteArray, convert::ToPyObject, function::FuncArgs, pyclass, PyObjectRef,
-        PyPayload, PyRef, PyResult, VirtualMachine,
+        builtins::{PyByteArray, PyDict},
+        convert::ToPyObject,
+        function::{FuncArgs, OptionalArg},
+        protocol::PyIterReturn,
+        pyclass,
+        types::{IterNext, Iterable, SelfIter, Unconstructible},
+        Py, PyObjectRef, PyPayload, PyRef, PyResult, VirtualMachine,
     };
+    use tracing::{trace, trace_span};
 
-    use crate::modules::Address;
+    use crate::modules::{modules::modules::PyModule, Address};
 
     /// Assemble instructions
     ///
@@ -17,12 +31,23 @@ pub mod asm {
     /// Assemble a single instruction to machine code
     ///
     /// String (code), Address (runtime address) -> [Inst]
-    /// Assembles multiple instructions to machine code
+    /// Assembles a multi-line block with labels to machine code. Names like `user32.MessageBoxA`
+    /// or `game.exe+0x1234` resolve to addresses, and so do names in the optional `symbols` kwarg dict
     ///
     #[pyfunction]
-    fn assemble(args: FuncArgs, vm: &VirtualMachine) -> PyResult<PyObjectRef> {
+    fn assemble(mut args: FuncArgs, vm: &VirtualMachine) -> PyResult<PyObjectRef> {
         // String
-        // String, Address
+        // String, Address, symbols=dict
+
+        let symbols = args
+            .kwargs
+            .shift_remove("symbols")
+            .map(|symbols| symbol_args(symbols, vm))
+            .transpose()?;
+
+        if let Some(name) = args.kwargs.keys().next() {
+            return Err(vm.new_type_error(format!("unexpected keyword argument '{name}'")));
+        }
 
         let code = args
             .args
@@ -32,6 +57,12 @@ pub mod asm {
             .ok_or_else(|| vm.new_runtime_error("code argument not found".to_owned()))?;
 
         let obj = match args.args.len() {
+            1 if symbols.is_some() => {
+                return Err(
+                    vm.new_type_error("symbols can only be used with a runtime address".to_owned())
+                )
+            }
+
             1 => asm::assemble(&code)
                 .map(PyInst)
                 .map_err(|e| vm.new_runtime_error(format!("{e}")))?
@@ -45,7 +76,14 @@ pub mod asm {
                     .transpose()?
                     .unwrap();
 
-                asm::assemble_ex(&code, addr)
+                let modules =
+                    ModuleResolver::new().map_err(|e| vm.new_runtime_error(format!("{e}")))?;
+
+                let symbols = symbols.unwrap_or_default();
+                let resolver =
+                    |name: &str| symbols.get(name).copied().or_else(|| modules.resolve(name));
+
+                asm::assemble_block(&code, addr, &resolver)
                     .map(|v| v.into_iter().map(|i| PyInst(i).to_pyobject(vm)))
                     .map_err(|e| vm.new_runtime_error(format!("{e}")))?
                     .collect::<Vec<_>>()
@@ -61,6 +99,21 @@ pub mod asm {
         Ok(obj)
     }
 
+    /// Convert a dict of name -> address
+    fn symbol_args(
+        symbols: PyObjectRef,
+        vm: &VirtualMachine,
+    ) -> PyResult<HashMap<String, Address>> {
+        let symbols = symbols.downcast::<PyDict>().map_err(|_| {
+            vm.new_type_error("symbols must be a dict of name -> address".to_owned())
+        })?;
+
+        symbols
+            .into_iter()
+            .map(|(name, addr)| Ok((name.try_to_value(vm)?, addr.try_to_value(vm)?)))
+            .collect()
+    }
+
     /// Get the code length of an instruction(s) starting at address, with a minimum length
     ///
     /// unsafe fn
@@ -70,6 +123,38 @@ pub mod asm {
         res.map_err(|e| vm.new_runtime_error(format!("{e}")))
     }
 
+    /// Disassemble count instructions at address into a readable multi-line listing
+    /// Addresses are shown as module+rva or module!export+offset, and calls, jumps, and rip-relative
+    /// operands are annotated with what they point at, including string previews
+    ///
+    /// unsafe fn
+    #[pyfunction]
+    fn listing(address: Address, count: usize, vm: &VirtualMachine) -> PyResult<String> {
+        let listing = unsafe { asm::listing(address as _, count) };
+        listing
+            .map(|listing| listing.to_string())
+            .map_err(|e| vm.new_runtime_error(format!("{e}")))
+    }
+
+    /// Find code caves of at least size bytes in the module's executable sections
+    /// These are runs of CC, 90, or 00 padding which no code reaches
+    #[pyfunction]
+    fn find_caves(
+        module: PyRef<PyModule>,
+        size: usize,
+        vm: &VirtualMachine,
+    ) -> PyResult<Vec<PyObjectRef>> {
+        let caves =
+            asm::find_caves(&module, size).map_err(|e| vm.new_runtime_error(format!("{e}")))?;
+
+        let caves = caves
+            .into_iter()
+            .map(|cave| PyCave(cave).into_pyobject(vm))
+            .collect();
+
+        Ok(caves)
+    }
+
     /// Disassemble a single instruction at target address
     /// Address must be valid for a 16 byte read
     ///
@@ -263,10 +348,313 @@ pub mod asm {
         }
     }
 
+    /// Lazily disassemble instructions one at a time starting at address, without needing a size
+    /// runtime_addr is the address of the first instruction, and defaults to address
+    /// The iterator stops at the first instruction which can't be disassembled
+    ///
+    /// unsafe fn
+    #[pyfunction]
+    fn disassemble_iter(
+        address: Address,
+        runtime_addr: OptionalArg<Address>,
+        vm: &VirtualMachine,
+    ) -> PyObjectRef {
+        let runtime_addr = runtime_addr.into_option().unwrap_or(address);
+        let iter = unsafe { asm::disassemble_iter(address as _, runtime_addr) };
+
+        PyDisassembleIter(Mutex::new(iter)).into_pyobject(vm)
+    }
+
+    #[pyattr]
+    #[pyclass(name = "DisassembleIter")]
+    #[derive(Debug, PyPayload)]
+    pub struct PyDisassembleIter(Mutex<DisassembleIter>);
+
+    impl Unconstructible for PyDisassembleIter {}
+
+    #[pyclass(with(Unconstructible, IterNext, Iterable))]
+    impl PyDisassembleIter {}
+
+    impl SelfIter for PyDisassembleIter {}
+
+    impl IterNext for PyDisassembleIter {
+        fn next(zelf: &Py<Self>, vm: &VirtualMachine) -> PyResult<PyIterReturn> {
+            let inst = zelf.0.lock().unwrap().next();
+
+            let res = match inst {
+                Some(inst) => PyIterReturn::Return(PyInst(inst).to_pyobject(vm)),
+                None => PyIterReturn::StopIteration(None),
+            };
+
+            Ok(res)
+        }
+    }
+
+    /// Analyze the function starting at address, following its branches to find its basic blocks
+    /// and the control flow between them. Bounds are confirmed with the module's exception directory
+    ///
+    /// unsafe fn
+    #[pyfunction]
+    fn function(address: Address, vm: &VirtualMachine) -> PyResult<PyFunction> {
+        let function = unsafe { asm::analyze_function(address as _) };
+        function
+            .map(PyFunction)
+            .map_err(|e| vm.new_runtime_error(format!("{e}")))
+    }
+
+    /// Patch the code at address with either asm text, which is assembled at address, or bytes
+    /// The patch is padded with nops to the next instruction boundary, and reverted when the Patch is dropped
+    ///
+    /// unsafe fn
+    #[pyfunction]
+    fn patch(address: Address, code: PyObjectRef, vm: &VirtualMachine) -> PyResult<PyPatch> {
+        let patch = match code.try_to_value::<String>(vm) {
+            Ok(code) => unsafe { Patch::asm(address as _, &code) },
+            Err(_) => {
+                let bytes = code.try_into_value::<Vec<u8>>(vm)?;
+                unsafe { Patch::new(address as _, &bytes) }
+            }
+        };
+
+        patch
+            .map(PyPatch)
+            .map_err(|e| vm.new_runtime_error(format!("{e}")))
+    }
+
+    /// A patch written over code. It's reverted when this is dropped
+    #[pyattr]
+    #[pyclass(name = "Patch")]
+    #[derive(Debug, PyPayload)]
+    pub struct PyPatch(Patch);
+
+    impl Drop for PyPatch {
+        fn drop(&mut self) {
+            let span = trace_span!("drop");
+            let _guard = span.enter();
+            trace!(address = ?self.0.address, "dropping Patch");
+        }
+    }
+
+    #[pyclass]
+    impl PyPatch {
+        /// The patched address
+        #[pygetset]
+        fn address(&self) -> Address {
+            self.0.address as _
+        }
+
+        /// Size of the patch, including the nop padding
+        #[pygetset]
+        fn size(&self) -> usize {
+            self.0.bytes().len()
+        }
+
+        #[pygetset]
+        fn bytes(&self, vm: &VirtualMachine) -> PyRef<PyByteArray> {
+            PyByteArray::new_ref(self.0.bytes().to_vec(), &vm.ctx)
+        }
+
+        #[pygetset]
+        fn original(&self, vm: &VirtualMachine) -> PyRef<PyByteArray> {
+            PyByteArray::new_ref(self.0.original().to_vec(), &vm.ctx)
+        }
+
+        #[pygetset]
+        fn enabled(&self) -> bool {
+            self.0.is_enabled()
+        }
+
+        /// Write the patch
+        ///
+        /// unsafe fn
+        #[pymethod]
+        fn enable(&self, vm: &VirtualMachine) -> PyResult<()> {
+            let res = unsafe { self.0.enable() };
+            res.map_err(|e| vm.new_runtime_error(format!("{e}")))
+        }
+
+        /// Restore the original bytes. If this is not called, they're restored when the instance is dropped
+        ///
+        /// unsafe fn
+        #[pymethod]
+        fn disable(&self, vm: &VirtualMachine) -> PyResult<()> {
+            let res = unsafe { self.0.disable() };
+            res.map_err(|e| vm.new_runtime_error(format!("{e}")))
+        }
+
+        #[pymethod(magic)]
+        fn repr(&self) -> String {
+            self.0.to_string()
+        }
+
+        #[pymethod(magic)]
+        fn str(&self) -> String {
+            self.0.to_string()
+        }
+    }
+
+    #[pyattr]
+    #[pyclass(name = "Cave")]
+    #[derive(Debug, PyPayload)]
+    pub struct PyCave(Cave);
+
+    #[pyclass]
+    impl PyCave {
+        #[pygetset]
+        fn address(&self) -> Address {
+            self.0.base as _
+        }
+
+        #[pygetset]
+        fn size(&self) -> usize {
+            self.0.size
+        }
+
+        #[pygetset]
+        fn section(&self) -> String {
+            self.0.section.clone()
+        }
+
+        #[pymethod(magic)]
+        fn repr(&self) -> String {
+            format!("{:?}", self.0)
+        }
+
+        #[pymethod(magic)]
+        fn str(&self) -> String {
+            format!("{:?}", self.0)
+        }
+    }
+
+    #[pyattr]
+    #[pyclass(name = "Function")]
+    #[derive(Debug, PyPayload)]
+    pub struct PyFunction(Function);
+
+    #[pyclass]
+    impl PyFunction {
+        #[pygetset]
+        fn start(&self) -> Address {
+            self.0.start as _
+        }
+
+        #[pygetset]
+        fn end(&self) -> Address {
+            self.0.end as _
+        }
+
+        #[pygetset]
+        fn blocks(&self, vm: &VirtualMachine) -> Vec<PyObjectRef> {
+            self.0
+                .blocks
+                .iter()
+                .map(|block| PyBlock(block.clone()).into_pyobject(vm))
+                .collect()
+        }
+
+        /// (from, to, kind) tuples, where kind is "jump", "taken", "not_taken", or "fallthrough"
+        #[pygetset]
+        fn edges(&self) -> Vec<(Address, Address, &'static str)> {
+            self.0
+                .edges
+                .iter()
+                .map(|edge| {
+                    let kind = match edge.kind {
+                        EdgeKind::Jump => "jump",
+                        EdgeKind::Taken => "taken",
+                        EdgeKind::NotTaken => "not_taken",
+                        EdgeKind::Fallthrough => "fallthrough",
+                    };
+
+                    (edge.from as _, edge.to as _, kind)
+                })
+                .collect()
+        }
+
+        #[pygetset]
+        fn tail_calls(&self) -> Vec<Address> {
+            self.0.tail_calls.iter().map(|&addr| addr as _).collect()
+        }
+
+        #[pygetset]
+        fn has_unwind_info(&self) -> bool {
+            self.0.unwind_bounds.is_some()
+        }
+
+        #[pymethod(magic)]
+        fn repr(&self) -> String {
+            format!(
+                "Function {{ start: {:?}, end: {:?}, blocks: {}, edges: {} }}",
+                self.0.start,
+                self.0.end,
+                self.0.blocks.len(),
+                self.0.edges.len()
+            )
+        }
+
+        #[pymethod(magic)]
+        fn str(&self) -> String {
+            format!(
+                "Function {{ start: {:?}, end: {:?}, blocks: {}, edges: {} }}",
+                self.0.start,
+                self.0.end,
+                self.0.blocks.len(),
+                self.0.edges.len()
+            )
+        }
+    }
+
+    #[pyattr]
+    #[pyclass(name = "Block")]
+    #[derive(Debug, PyPayload)]
+    pub struct PyBlock(BasicBlock);
+
+    #[pyclass]
+    impl PyBlock {
+        #[pygetset]
+        fn start(&self) -> Address {
+            self.0.start as _
+        }
+
+        #[pygetset]
+        fn end(&self) -> Address {
+            self.0.end as _
+        }
+
+        #[pygetset]
+        fn insts(&self, vm: &VirtualMachine) -> Vec<PyObjectRef> {
+            self.0
+                .insts
+                .iter()
+                .map(|inst| PyInst(inst.clone()).into_pyobject(vm))
+                .collect()
+        }
+
+        #[pymethod(magic)]
+        fn repr(&self) -> String {
+            format!(
+                "Block {{ start: {:?}, end: {:?}, insts: {} }}",
+                self.0.start,
+                self.0.end,
+                self.0.insts.len()
+            )
+        }
+
+        #[pymethod(magic)]
+        fn str(&self) -> String {
+            format!(
+                "Block {{ start: {:?}, end: {:?}, insts: {} }}",
+                self.0.start,
+                self.0.end,
+                self.0.insts.len()
+            )
+        }
+    }
+
     #[pyattr]
     #[pyclass(name = "Inst")]
     #[derive(Debug, Clone, PyPayload)]
-    struct PyInst(Inst);
+    pub struct PyInst(pub Inst);
 
     #[pyclass]
     impl PyInst {
@@ -290,6 +678,55 @@ pub mod asm {
             self.0.op_str.clone()
         }
 
+        #[pygetset]
+        fn operands(&self, vm: &VirtualMachine) -> Vec<PyObjectRef> {
+            self.0
+                .operands
+                .iter()
+                .map(|op| PyOperand(op.clone()).into_pyobject(vm))
+                .collect()
+        }
+
+        #[pygetset]
+        fn regs_read(&self) -> Vec<String> {
+            self.0.regs_read.clone()
+        }
+
+        #[pygetset]
+        fn regs_written(&self) -> Vec<String> {
+            self.0.regs_written.clone()
+        }
+
+        #[pygetset]
+        fn groups(&self) -> Vec<String> {
+            self.0.groups.clone()
+        }
+
+        #[pygetset]
+        fn is_jump(&self) -> bool {
+            self.0.is_jump()
+        }
+
+        #[pygetset]
+        fn is_call(&self) -> bool {
+            self.0.is_call()
+        }
+
+        #[pygetset]
+        fn is_ret(&self) -> bool {
+            self.0.is_ret()
+        }
+
+        #[pygetset]
+        fn is_relative_branch(&self) -> bool {
+            self.0.is_relative_branch()
+        }
+
+        #[pygetset]
+        fn target(&self) -> Option<Address> {
+            self.0.target().map(|target| target as _)
+        }
+
         #[pymethod(magic)]
         fn repr(&self) -> String {
             self.0.to_string()
@@ -300,4 +737,94 @@ pub mod asm {
             self.0.to_string()
         }
     }
+
+    #[pyattr]
+    #[pyclass(name = "Operand")]
+    #[derive(Debug, Clone, PyPayload)]
+    pub struct PyOperand(Operand);
+
+    impl PyOperand {
+        fn mem(&self) -> Option<&MemOperand> {
+            match &self.0 {
+                Operand::Mem(mem) => Some(mem),
+                _ => None,
+            }
+        }
+    }
+
+    #[pyclass]
+    impl PyOperand {
+        /// "reg", "imm", or "mem"
+        #[pygetset(name = "type")]
+        fn ty(&self) -> &'static str {
+            match self.0 {
+                Operand::Reg(_) => "reg",
+                Operand::Imm(_) => "imm",
+                Operand::Mem(_) => "mem",
+            }
+        }
+
+        #[pygetset]
+        fn reg(&self) -> Option<String> {
+            match &self.0 {
+                Operand::Reg(reg) => Some(reg.clone()),
+                _ => None,
+            }
+        }
+
+        #[pygetset]
+        fn imm(&self) -> Option<i64> {
+            match self.0 {
+                Operand::Imm(imm) => Some(imm),
+                _ => None,
+            }
+        }
+
+        #[pygetset]
+        fn segment(&self) -> Option<String> {
+            self.mem().and_then(|mem| mem.segment.clone())
+        }
+
+        #[pygetset]
+        fn base(&self) -> Option<String> {
+            self.mem().and_then(|mem| mem.base.clone())
+        }
+
+        #[pygetset]
+        fn index(&self) -> Option<String> {
+            self.mem().and_then(|mem| mem.index.clone())
+        }
+
+        #[pygetset]
+        fn scale(&self) -> Option<i32> {
+            self.mem().map(|mem| mem.scale)
+        }
+
+        #[pygetset]
+        fn disp(&self) -> Option<i64> {
+            self.mem().map(|mem| mem.disp)
+        }
+
+        #[pygetset]
+        fn size(&self) -> Option<u8> {
+            self.mem().map(|mem| mem.size)
+        }
+
+        #[pygetset]
+        fn target(&self) -> Option<Address> {
+            self.mem()
+                .and_then(|mem| mem.target)
+                .map(|target| target as _)
+        }
+
+        #[pymethod(magic)]
+        fn repr(&self) -> String {
+            format!("Operand({:?})", self.0)
+        }
+
+        #[pymethod(magic)]
+        fn str(&self) -> String {
+            format!("Operand({:?})", self.0)
+        }
+    }
 }
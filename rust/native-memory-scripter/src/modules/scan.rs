@@ -2,41 +2,1266 @@ use rustpython_vm::pymodule;
 
 #[pymodule]
 pub mod scan {
-    use mutation::scan;
+    use std::{
+        array,
+        sync::{Mutex, OnceLock},
+    };
 
-    use crate::modules::Address;
+    use mutation::{
+        modules::{self, Module},
+        scan::{
+            self, Capture, Filter, InsnMatch, InsnPattern, ModuleFilter, Pattern, PointerChain,
+            ProcessFilter, Region, RegionScan, Scan, SigCache, Value, ValueScan, ValueType,
+        },
+    };
+    use rustpython_vm::{
+        builtins::{PyDictRef, PyInt, PyTypeRef},
+        convert::ToPyObject,
+        function::{FuncArgs, OptionalArg},
+        pyclass,
+        types::Constructor,
+        PyObjectRef, PyPayload, PyRef, PyResult, TryFromObject, VirtualMachine,
+    };
+    use tracing::warn;
+
+    use crate::{
+        modules::{
+            asm::asm::PyInst, modules::modules::PyModule, segments::segments::PySegment, Address,
+        },
+        paths::get_dll_dir_filepath,
+        CONFIG, MODULE_HANDLE,
+    };
 
     /// Search for data starting at address
+    /// address can also be a Module, Segment, or a list of them, which returns a ScanResult
+    ///
+    /// unsafe fn
+    #[pyfunction]
+    fn data(
+        data: Vec<u8>,
+        address: PyObjectRef,
+        mut args: FuncArgs,
+        vm: &VirtualMachine,
+    ) -> PyResult<Option<PyObjectRef>> {
+        let target = scan_target(address, &mut args, vm)?;
+        optional_args::<bool, 0>(args, [], vm)?;
+
+        let res = match target {
+            Target::Address(address, scan_size) => {
+                let scan = unsafe { scan::data_scan(&data, address as *const _, scan_size) };
+                scan.map(|s| (s.addr as Address).to_pyobject(vm))
+            }
+
+            Target::Regions(regions) => {
+                let pattern = Pattern::from(data.as_slice());
+                let scan = unsafe { pattern.scan_regions(&regions) };
+                scan.map(|s| PyScanResult(s).into_pyobject(vm))
+            }
+        };
+
+        Ok(res)
+    }
+
+    /// Search for every occurrence of data starting at address, up to an optional limit of results
+    /// address can also be a Module, Segment, or a list of them, which returns a list of ScanResult
     ///
     /// unsafe fn
     #[pyfunction]
-    fn data(data: Vec<u8>, address: Address, scan_size: usize) -> Option<Address> {
-        let scan = unsafe { scan::data_scan(&data, address as *const _, scan_size) };
-        scan.map(|s| s.addr as _)
+    fn data_all(
+        data: Vec<u8>,
+        address: PyObjectRef,
+        mut args: FuncArgs,
+        vm: &VirtualMachine,
+    ) -> PyResult<Vec<PyObjectRef>> {
+        let target = scan_target(address, &mut args, vm)?;
+        let [limit] = optional_args(args, ["limit"], vm)?;
+        let limit = limit.unwrap_or(usize::MAX);
+
+        let res = match target {
+            Target::Address(address, scan_size) => {
+                let scans = unsafe { scan::data_scan_all(&data, address as *const _, scan_size) };
+                scans
+                    .take(limit)
+                    .map(|s| (s.addr as Address).to_pyobject(vm))
+                    .collect()
+            }
+
+            Target::Regions(regions) => {
+                let pattern = Pattern::from(data.as_slice());
+                let scans = unsafe { pattern.scan_regions_all(&regions, limit) };
+                scan_results(scans, vm)
+            }
+        };
+
+        Ok(res)
     }
 
     /// Search for a pattern with data and a mask starting at address
     /// Mask should be in the format `xxx??xx` where `x` is a known byte and `?` is an unknown byte
+    /// address can also be a Module, Segment, or a list of them, which returns a ScanResult
     ///
     /// unsafe fn
     #[pyfunction]
     fn pattern(
         pattern: Vec<u8>,
         mask: String,
+        address: PyObjectRef,
+        mut args: FuncArgs,
+        vm: &VirtualMachine,
+    ) -> PyResult<Option<PyObjectRef>> {
+        let target = scan_target(address, &mut args, vm)?;
+        optional_args::<bool, 0>(args, [], vm)?;
+
+        let res = match target {
+            Target::Address(address, scan_size) => {
+                let scan = unsafe { scan::pattern_scan(&pattern, &mask, address as _, scan_size) };
+                scan.map(|s| (s.addr as Address).to_pyobject(vm))
+            }
+
+            Target::Regions(regions) => {
+                let pattern = Pattern::from_data_with_mask(&pattern, &mask).ok();
+                let scan = pattern.and_then(|pattern| unsafe { pattern.scan_regions(&regions) });
+                scan.map(|s| PyScanResult(s).into_pyobject(vm))
+            }
+        };
+
+        Ok(res)
+    }
+
+    /// Search for every occurrence of a pattern with data and a mask starting at address,
+    /// up to an optional limit of results
+    /// Mask should be in the format `xxx??xx` where `x` is a known byte and `?` is an unknown byte
+    /// address can also be a Module, Segment, or a list of them, which returns a list of ScanResult
+    ///
+    /// unsafe fn
+    #[pyfunction]
+    fn pattern_all(
+        pattern: Vec<u8>,
+        mask: String,
+        address: PyObjectRef,
+        mut args: FuncArgs,
+        vm: &VirtualMachine,
+    ) -> PyResult<Vec<PyObjectRef>> {
+        let target = scan_target(address, &mut args, vm)?;
+        let [limit] = optional_args(args, ["limit"], vm)?;
+        let limit = limit.unwrap_or(usize::MAX);
+
+        let res = match target {
+            Target::Address(address, scan_size) => {
+                let scans =
+                    unsafe { scan::pattern_scan_all(&pattern, &mask, address as _, scan_size) };
+                scans
+                    .take(limit)
+                    .map(|s| (s.addr as Address).to_pyobject(vm))
+                    .collect()
+            }
+
+            Target::Regions(regions) => match Pattern::from_data_with_mask(&pattern, &mask) {
+                Ok(pattern) => {
+                    let scans = unsafe { pattern.scan_regions_all(&regions, limit) };
+                    scan_results(scans, vm)
+                }
+
+                Err(_) => Vec::new(),
+            },
+        };
+
+        Ok(res)
+    }
+
+    /// Search for a pattern with an IDA-style binary pattern
+    /// Sig should be in the format of `11 22 33 ?? 44 ?? 55 ?? ??`, where hex is a known byte and `??` is an unknown byte
+    /// A single nibble may also be unknown, e.g. `4? 8B ?5`
+    /// `&` returns the address after the marker, and `[?? ?? ?? ??]` returns the target of the rel32 inside it
+    /// Code-style, x64dbg, Cheat Engine, and Ghidra sigs are detected automatically
+    /// Pass `parallel=True` to split the scan across multiple threads, which is faster on large regions
+    /// Pass `cache=True` to reuse the result from a previous run if the module hasn't changed since
+    /// address can also be a Module, Segment, or a list of them, which returns a ScanResult
+    ///
+    /// unsafe fn
+    #[pyfunction]
+    fn sig(
+        sig: String,
+        address: PyObjectRef,
+        mut args: FuncArgs,
+        vm: &VirtualMachine,
+    ) -> PyResult<Option<PyObjectRef>> {
+        let target = scan_target(address, &mut args, vm)?;
+        let [parallel, cache] = flag_args(args, ["parallel", "cache"], vm)?;
+
+        let scan_range = |address: Address, scan_size: usize| {
+            let scan = || {
+                if parallel {
+                    unsafe { scan::sig_scan_parallel(&sig, address as _, scan_size) }
+                } else {
+                    unsafe { scan::sig_scan(&sig, address as _, scan_size) }
+                }
+            };
+
+            if cache {
+                cached_scan(&sig, address, scan_size, scan)
+            } else {
+                scan()
+            }
+        };
+
+        let res = match target {
+            Target::Address(address, scan_size) => {
+                scan_range(address, scan_size).map(|s| (s.addr as Address).to_pyobject(vm))
+            }
+
+            Target::Regions(regions) => regions
+                .iter()
+                .find_map(|region| scan_range(region.base as _, region.size))
+                .map(|s| PyScanResult(s.into()).into_pyobject(vm)),
+        };
+
+        Ok(res)
+    }
+
+    /// Where to scan
+    enum Target {
+        /// an address and scan size
+        Address(Address, usize),
+        /// the regions of Module and Segment objects
+        Regions(Vec<Region>),
+    }
+
+    /// Get the scan target from the address arg
+    /// An int address takes the scan size from the next arg or the `scan_size` kwarg
+    /// A Module, Segment, or a list of them become regions, and modules can be restricted to
+    /// their sections with the `sections` kwarg. Segments which can't be read are skipped
+    fn scan_target(
+        address: PyObjectRef,
+        args: &mut FuncArgs,
+        vm: &VirtualMachine,
+    ) -> PyResult<Target> {
+        if address.downcast_ref::<PyInt>().is_some() {
+            let scan_size = match args.kwargs.shift_remove("scan_size") {
+                Some(scan_size) => scan_size,
+                None if !args.args.is_empty() => args.args.remove(0),
+                None => {
+                    return Err(vm.new_type_error(
+                        "missing argument 'scan_size', which is required for an int address"
+                            .to_owned(),
+                    ))
+                }
+            };
+
+            let address = address.try_to_value::<Address>(vm)?;
+            let scan_size = scan_size.try_to_value::<usize>(vm)?;
+
+            return Ok(Target::Address(address, scan_size));
+        }
+
+        let sections = args
+            .kwargs
+            .shift_remove("sections")
+            .map(|sections| sections.try_to_value::<Vec<String>>(vm))
+            .transpose()?;
+
+        let objects = if address.downcast_ref::<PyModule>().is_some()
+            || address.downcast_ref::<PySegment>().is_some()
+        {
+            vec![address]
+        } else {
+            address.try_to_value::<Vec<PyObjectRef>>(vm).map_err(|_| {
+                vm.new_type_error(
+                    "expected an int address, a Module, a Segment, or a list of them".to_owned(),
+                )
+            })?
+        };
+
+        let mut regions = Vec::new();
+
+        for object in objects {
+            if let Some(module) = object.downcast_ref::<PyModule>() {
+                match &sections {
+                    Some(sections) => regions.extend(
+                        Region::sections(module, &sections[..])
+                            .map_err(|e| vm.new_runtime_error(format!("{e}")))?,
+                    ),
+
+                    None => regions.push(Region::from(&***module)),
+                }
+            } else if let Some(segment) = object.downcast_ref::<PySegment>() {
+                if segment.prot.is_readable() {
+                    regions.push(Region::from(&***segment));
+                }
+            } else {
+                return Err(vm.new_type_error(format!(
+                    "expected a Module or a Segment, found {}",
+                    object.class().name()
+                )));
+            }
+        }
+
+        Ok(Target::Regions(regions))
+    }
+
+    /// Get optional args, either as trailing args in order or as kwargs
+    fn optional_args<T: TryFromObject, const N: usize>(
+        args: FuncArgs,
+        names: [&str; N],
+        vm: &VirtualMachine,
+    ) -> PyResult<[Option<T>; N]> {
+        if args.args.len() > N {
+            return Err(vm.new_runtime_error("incorrect number of args".to_owned()));
+        }
+
+        if let Some(name) = args.kwargs.keys().find(|k| !names.contains(&k.as_str())) {
+            return Err(vm.new_type_error(format!("unexpected keyword argument '{name}'")));
+        }
+
+        let mut values = array::from_fn(|_| None);
+        for (i, (value, name)) in values.iter_mut().zip(names).enumerate() {
+            let arg = match (args.args.get(i), args.kwargs.get(name)) {
+                (Some(_), Some(_)) => {
+                    return Err(vm.new_type_error(format!("multiple values for argument '{name}'")))
+                }
+
+                (arg, kwarg) => arg.or(kwarg),
+            };
+
+            if let Some(arg) = arg {
+                *value = Some(arg.clone().try_into_value::<T>(vm)?);
+            }
+        }
+
+        Ok(values)
+    }
+
+    /// Get optional bool flags, either as trailing args in order or as kwargs
+    fn flag_args<const N: usize>(
+        args: FuncArgs,
+        names: [&str; N],
+        vm: &VirtualMachine,
+    ) -> PyResult<[bool; N]> {
+        let flags = optional_args::<bool, N>(args, names, vm)?;
+        Ok(flags.map(|flag| flag.unwrap_or(false)))
+    }
+
+    /// The sig cache, and the modules which were scanned through it
+    struct CacheState {
+        cache: SigCache,
+        // each of these keeps its module loaded, so the address ranges stay valid
+        modules: Vec<Module>,
+    }
+
+    /// The sig cache, or None if it's disabled in the config or failed to load
+    fn sig_cache() -> Option<&'static Mutex<CacheState>> {
+        static SIG_CACHE: OnceLock<Option<Mutex<CacheState>>> = OnceLock::new();
+
+        SIG_CACHE
+            .get_or_init(|| {
+                if !CONFIG.get().is_some_and(|config| config.scan.cache) {
+                    return None;
+                }
+
+                let module = *MODULE_HANDLE.get()?;
+                let path = get_dll_dir_filepath(module, "native-memory-scripter.sigcache").ok()?;
+
+                match SigCache::load(path) {
+                    Ok(cache) => Some(Mutex::new(CacheState {
+                        cache,
+                        modules: Vec::new(),
+                    })),
+                    Err(e) => {
+                        warn!("failed to load sig cache: {e}");
+                        None
+                    }
+                }
+            })
+            .as_ref()
+    }
+
+    /// Write newly cached sigs to disk. New results are only kept in memory until this is called,
+    /// which happens each time a script finishes running
+    pub fn save_sig_cache() {
+        let Some(state) = sig_cache() else {
+            return;
+        };
+
+        if let Err(e) = state.lock().unwrap().cache.save() {
+            warn!("failed to save sig cache: {e}");
+        }
+    }
+
+    /// Resolve a sig through the sig cache. Falls back to a normal scan if the cache is disabled,
+    /// or if address isn't inside of a module
+    fn cached_scan(
+        sig: &str,
         address: Address,
         scan_size: usize,
-    ) -> Option<Address> {
-        let scan = unsafe { scan::pattern_scan(&pattern, &mask, address as _, scan_size) };
-        scan.map(|s| s.addr as _)
+        scan: impl FnOnce() -> Option<Scan>,
+    ) -> Option<Scan> {
+        let Some(state) = sig_cache() else {
+            return scan();
+        };
+
+        let mut state = state.lock().unwrap();
+        let CacheState { cache, modules } = &mut *state;
+
+        let contains = |m: &Module| (m.base as Address..m.end as Address).contains(&address);
+
+        // modules are only listed again when the address isn't in one which was already scanned
+        let index = match modules.iter().position(contains) {
+            Some(index) => index,
+            None => {
+                let module = modules::enum_modules()
+                    .ok()
+                    .and_then(|all| all.into_iter().find(contains));
+
+                let Some(module) = module else {
+                    return scan();
+                };
+
+                modules.push(module);
+                modules.len() - 1
+            }
+        };
+
+        let module = &modules[index];
+        let key = SigCache::sig_key(module, sig, address as _, scan_size);
+        cache.resolve(module, &key, scan)
     }
 
-    /// Search for a pattern with an IDA-style binary pattern
+    /// Search for every occurrence of an IDA-style binary pattern, up to an optional limit of results
     /// Sig should be in the format of `11 22 33 ?? 44 ?? 55 ?? ??`, where hex is a known byte and `??` is an unknown byte
+    /// A single nibble may also be unknown, e.g. `4? 8B ?5`
+    /// address can also be a Module, Segment, or a list of them, which returns a list of ScanResult
+    ///
+    /// unsafe fn
+    #[pyfunction]
+    fn sig_all(
+        sig: String,
+        address: PyObjectRef,
+        mut args: FuncArgs,
+        vm: &VirtualMachine,
+    ) -> PyResult<Vec<PyObjectRef>> {
+        let target = scan_target(address, &mut args, vm)?;
+        let [limit] = optional_args(args, ["limit"], vm)?;
+        let limit = limit.unwrap_or(usize::MAX);
+
+        let res = match target {
+            Target::Address(address, scan_size) => {
+                let scans = unsafe { scan::sig_scan_all(&sig, address as _, scan_size) };
+                scans
+                    .take(limit)
+                    .map(|s| (s.addr as Address).to_pyobject(vm))
+                    .collect()
+            }
+
+            Target::Regions(regions) => match Pattern::try_from(sig.as_str()) {
+                Ok(pattern) => {
+                    let scans = unsafe { pattern.scan_regions_all(&regions, limit) };
+                    scan_results(scans, vm)
+                }
+
+                Err(_) => Vec::new(),
+            },
+        };
+
+        Ok(res)
+    }
+
+    /// Search every readable segment of the process for an IDA-style binary pattern
+    /// Guard pages and no-access pages are skipped, as are pages which become unreadable during the scan
+    /// Filter with writable, executable, in_module, module (a module name), start, and end kwargs
+    /// Returns a ScanResult, or None if not found
+    #[pyfunction]
+    fn sig_process(
+        sig: String,
+        args: FuncArgs,
+        vm: &VirtualMachine,
+    ) -> PyResult<Option<PyObjectRef>> {
+        let filter = process_filter(args, vm)?;
+
+        let scan = scan::sig_scan_process(&sig, &filter)
+            .map_err(|e| vm.new_runtime_error(format!("{e}")))?;
+
+        Ok(scan.map(|scan| PyScanResult(scan).into_pyobject(vm)))
+    }
+
+    /// Search every readable segment of the process for every occurrence of an IDA-style binary pattern,
+    /// up to an optional limit of results
+    /// Takes the same filter kwargs as sig_process
+    /// Returns a list of ScanResult
+    #[pyfunction]
+    fn sig_process_all(
+        sig: String,
+        mut args: FuncArgs,
+        vm: &VirtualMachine,
+    ) -> PyResult<Vec<PyObjectRef>> {
+        let limit = match args.kwargs.shift_remove("limit") {
+            Some(limit) => Some(limit),
+            None if !args.args.is_empty() => Some(args.args.remove(0)),
+            None => None,
+        };
+
+        let limit = limit
+            .map(|limit| limit.try_to_value::<usize>(vm))
+            .transpose()?
+            .unwrap_or(usize::MAX);
+
+        let filter = process_filter(args, vm)?;
+
+        let scans = scan::sig_scan_process_all(&sig, &filter, limit)
+            .map_err(|e| vm.new_runtime_error(format!("{e}")))?;
+
+        Ok(scan_results(scans, vm))
+    }
+
+    /// Get the process scan filter from the kwargs
+    fn process_filter(args: FuncArgs, vm: &VirtualMachine) -> PyResult<ProcessFilter> {
+        let [writable, executable, in_module, module, start, end] = optional_args::<PyObjectRef, 6>(
+            args,
+            [
+                "writable",
+                "executable",
+                "in_module",
+                "module",
+                "start",
+                "end",
+            ],
+            vm,
+        )?;
+
+        // None is the same as not passing the arg
+        let value = |arg: Option<PyObjectRef>| arg.filter(|arg| !vm.is_none(arg));
+
+        let writable = value(writable)
+            .map(|writable| writable.try_to_value::<bool>(vm))
+            .transpose()?
+            .unwrap_or(true);
+
+        let executable = value(executable)
+            .map(|executable| executable.try_to_value::<bool>(vm))
+            .transpose()?;
+
+        let in_module = value(in_module)
+            .map(|in_module| in_module.try_to_value::<bool>(vm))
+            .transpose()?;
+
+        let module = value(module)
+            .map(|module| match module.downcast_ref::<PyModule>() {
+                Some(module) => Ok(module.name.clone()),
+                None => module.try_to_value::<String>(vm),
+            })
+            .transpose()?;
+
+        let modules = match (module, in_module) {
+            (Some(_), Some(false)) => {
+                return Err(vm.new_value_error(
+                    "in_module=False can't be used together with module".to_owned(),
+                ))
+            }
+
+            (Some(name), _) => ModuleFilter::Named(name),
+            (None, Some(true)) => ModuleFilter::Only,
+            (None, Some(false)) => ModuleFilter::Exclude,
+            (None, None) => ModuleFilter::Any,
+        };
+
+        let start = value(start)
+            .map(|start| start.try_to_value::<Address>(vm))
+            .transpose()?;
+
+        let end = value(end)
+            .map(|end| end.try_to_value::<Address>(vm))
+            .transpose()?;
+
+        let range = match (start, end) {
+            (None, None) => None,
+            (start, end) => Some(start.unwrap_or(0)..end.unwrap_or(Address::MAX)),
+        };
+
+        Ok(ProcessFilter {
+            skip_writable: !writable,
+            executable,
+            modules,
+            range,
+        })
+    }
+
+    /// Convert region scans to a list of ScanResult
+    fn scan_results(scans: Vec<RegionScan>, vm: &VirtualMachine) -> Vec<PyObjectRef> {
+        scans
+            .into_iter()
+            .map(|scan| PyScanResult(scan).into_pyobject(vm))
+            .collect()
+    }
+
+    /// Search for many sigs at once, walking the memory only one time
+    /// Takes a dict of name -> sig (str or Pattern), and returns a dict of name -> address, or None if not found
+    ///
+    /// unsafe fn
+    #[pyfunction]
+    fn batch(
+        sigs: PyDictRef,
+        address: Address,
+        scan_size: usize,
+        vm: &VirtualMachine,
+    ) -> PyResult<PyDictRef> {
+        let patterns = batch_patterns(sigs, vm)?;
+
+        let found = unsafe {
+            scan::batch_scan(
+                patterns
+                    .iter()
+                    .map(|(name, pattern)| (name.as_str(), pattern)),
+                address as _,
+                scan_size,
+            )
+        };
+
+        let dict = vm.ctx.new_dict();
+        for (name, _) in &patterns {
+            let address = found.get(name).map(|s| s.addr as Address);
+            dict.set_item(name.as_str(), address.to_pyobject(vm), vm)?;
+        }
+
+        Ok(dict)
+    }
+
+    /// Search for every occurrence of many sigs at once, walking the memory only one time
+    /// Takes a dict of name -> sig (str or Pattern), and returns a dict of name -> list of addresses
+    ///
+    /// unsafe fn
+    #[pyfunction]
+    fn batch_all(
+        sigs: PyDictRef,
+        address: Address,
+        scan_size: usize,
+        vm: &VirtualMachine,
+    ) -> PyResult<PyDictRef> {
+        let patterns = batch_patterns(sigs, vm)?;
+
+        let mut found = unsafe {
+            scan::batch_scan_all(
+                patterns
+                    .iter()
+                    .map(|(name, pattern)| (name.as_str(), pattern)),
+                address as _,
+                scan_size,
+            )
+        };
+
+        let dict = vm.ctx.new_dict();
+        for (name, _) in &patterns {
+            let addresses = found
+                .remove(name)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|s| s.addr as Address)
+                .collect::<Vec<_>>();
+
+            dict.set_item(name.as_str(), addresses.to_pyobject(vm), vm)?;
+        }
+
+        Ok(dict)
+    }
+
+    /// Make an IDA-style sig which uniquely finds address in the module's code
+    /// Returns the sig, and the offset of address from the start of a match
+    #[pyfunction]
+    fn make_sig(
+        addr: Address,
+        module: PyRef<PyModule>,
+        vm: &VirtualMachine,
+    ) -> PyResult<(String, usize)> {
+        let signature = scan::make_signature(&module, addr as _)
+            .map_err(|e| vm.new_runtime_error(format!("{e}")))?;
+
+        Ok((signature.sig, signature.offset))
+    }
+
+    /// Search for a sequence of instructions matching an instruction pattern, starting at address
+    /// e.g. `mov r64, ([rip+*]); test r64, r64; jz *`
+    /// Returns the address of the match and a list of the captured operands
+    ///
+    /// unsafe fn
+    #[pyfunction]
+    fn insn(
+        pattern: String,
+        address: Address,
+        scan_size: usize,
+        vm: &VirtualMachine,
+    ) -> PyResult<Option<(Address, Vec<PyObjectRef>)>> {
+        let pattern = pattern
+            .parse::<InsnPattern>()
+            .map_err(|e| vm.new_runtime_error(format!("{e}")))?;
+
+        let found = unsafe { pattern.scan(address as _, scan_size) };
+        let found = found.map_err(|e| vm.new_runtime_error(format!("{e}")))?;
+
+        Ok(found.map(|found| insn_match(found, vm)))
+    }
+
+    /// Search for every sequence of instructions matching an instruction pattern, starting at address,
+    /// up to an optional limit of results
+    /// Returns a list of the address of each match and a list of its captured operands
     ///
     /// unsafe fn
     #[pyfunction]
-    fn sig(sig: String, address: Address, scan_size: usize) -> Option<Address> {
-        let res = unsafe { scan::sig_scan(&sig, address as _, scan_size) };
-        res.map(|s| s.addr as _)
+    fn insn_all(
+        pattern: String,
+        address: Address,
+        scan_size: usize,
+        limit: OptionalArg<usize>,
+        vm: &VirtualMachine,
+    ) -> PyResult<Vec<(Address, Vec<PyObjectRef>)>> {
+        let pattern = pattern
+            .parse::<InsnPattern>()
+            .map_err(|e| vm.new_runtime_error(format!("{e}")))?;
+
+        let limit = limit.into_option().unwrap_or(usize::MAX);
+
+        let found = unsafe { pattern.scan_all(address as _, scan_size, limit) };
+        let found = found.map_err(|e| vm.new_runtime_error(format!("{e}")))?;
+
+        Ok(found
+            .into_iter()
+            .map(|found| insn_match(found, vm))
+            .collect())
+    }
+
+    /// Convert a match to its address and captures. registers are str, and anything else is int
+    fn insn_match(found: InsnMatch, vm: &VirtualMachine) -> (Address, Vec<PyObjectRef>) {
+        let captures = found
+            .captures
+            .into_iter()
+            .map(|capture| match capture {
+                Capture::Reg(reg) => reg.to_pyobject(vm),
+                Capture::Imm(value) | Capture::Mem(value) => value.to_pyobject(vm),
+            })
+            .collect();
+
+        (found.addr as _, captures)
+    }
+
+    /// Find every instruction in the module's code which references target
+    /// Returns a list of (address, Inst)
+    #[pyfunction]
+    fn xrefs(
+        target: Address,
+        module: PyRef<PyModule>,
+        vm: &VirtualMachine,
+    ) -> PyResult<Vec<(Address, PyObjectRef)>> {
+        let xrefs = scan::find_xrefs(&module, target as _)
+            .map_err(|e| vm.new_runtime_error(format!("{e}")))?;
+
+        Ok(xrefs
+            .into_iter()
+            .map(|xref| (xref.addr as _, PyInst(xref.inst).to_pyobject(vm)))
+            .collect())
+    }
+
+    /// Find every instruction in the module's code which references an ASCII or UTF-16 copy of string
+    /// Returns a list of (address, Inst)
+    #[pyfunction]
+    fn string_xrefs(
+        string: String,
+        module: PyRef<PyModule>,
+        vm: &VirtualMachine,
+    ) -> PyResult<Vec<(Address, PyObjectRef)>> {
+        let xrefs = scan::find_string_xrefs(&module, &string)
+            .map_err(|e| vm.new_runtime_error(format!("{e}")))?;
+
+        Ok(xrefs
+            .into_iter()
+            .map(|xref| (xref.addr as _, PyInst(xref.inst).to_pyobject(vm)))
+            .collect())
+    }
+
+    /// Find chains of pointers from static addresses inside modules to address
+    /// Each pointer points at most max_offset bytes before the next address, and chains are at most max_depth pointers long
+    /// Returns a list of PointerChain, shortest first, up to an optional limit of results (10000 by default)
+    /// Each level of the search keeps at most an optional max_nodes addresses (100000 by default)
+    #[pyfunction]
+    fn pointer_scan(
+        address: Address,
+        max_depth: usize,
+        max_offset: usize,
+        limit: OptionalArg<usize>,
+        max_nodes: OptionalArg<usize>,
+        vm: &VirtualMachine,
+    ) -> PyResult<Vec<PyObjectRef>> {
+        let chains = scan::pointer_scan(
+            address as _,
+            max_depth,
+            max_offset,
+            limit.into_option().unwrap_or(10000),
+            max_nodes.into_option().unwrap_or(100000),
+        )
+        .map_err(|e| vm.new_runtime_error(format!("{e}")))?;
+
+        Ok(chains
+            .into_iter()
+            .map(|chain| PyPointerChain(chain).into_pyobject(vm))
+            .collect())
+    }
+
+    /// Save a list of PointerChain to a file
+    #[pyfunction]
+    fn save_pointers(
+        path: String,
+        chains: Vec<PyRef<PyPointerChain>>,
+        vm: &VirtualMachine,
+    ) -> PyResult<()> {
+        let chains = chains
+            .iter()
+            .map(|chain| chain.0.clone())
+            .collect::<Vec<_>>();
+
+        scan::save_chains(path, &chains).map_err(|e| vm.new_runtime_error(format!("{e}")))
+    }
+
+    /// Load a list of PointerChain saved with save_pointers
+    #[pyfunction]
+    fn load_pointers(path: String, vm: &VirtualMachine) -> PyResult<Vec<PyObjectRef>> {
+        let chains = scan::load_chains(path).map_err(|e| vm.new_runtime_error(format!("{e}")))?;
+
+        Ok(chains
+            .into_iter()
+            .map(|chain| PyPointerChain(chain).into_pyobject(vm))
+            .collect())
+    }
+
+    /// Keep only the PointerChains which are in both lists
+    #[pyfunction]
+    fn intersect_pointers(
+        a: Vec<PyRef<PyPointerChain>>,
+        b: Vec<PyRef<PyPointerChain>>,
+        vm: &VirtualMachine,
+    ) -> Vec<PyObjectRef> {
+        let a = a.iter().map(|chain| chain.0.clone()).collect::<Vec<_>>();
+        let b = b.iter().map(|chain| chain.0.clone()).collect::<Vec<_>>();
+
+        scan::intersect_chains(&a, &b)
+            .into_iter()
+            .map(|chain| PyPointerChain(chain).into_pyobject(vm))
+            .collect()
+    }
+
+    /// Compile a dict of name -> sig (str or Pattern)
+    fn batch_patterns(sigs: PyDictRef, vm: &VirtualMachine) -> PyResult<Vec<(String, Pattern)>> {
+        let mut patterns = Vec::new();
+
+        for (name, sig) in sigs {
+            let name = name.try_to_value::<String>(vm)?;
+
+            let pattern = if let Some(pattern) = sig.downcast_ref::<PyPattern>() {
+                pattern.0.clone()
+            } else {
+                let sig = sig.try_to_value::<String>(vm)?;
+                sig.parse::<Pattern>()
+                    .map_err(|e| vm.new_runtime_error(format!("{name}: {e}")))?
+            };
+
+            patterns.push((name, pattern));
+        }
+
+        Ok(patterns)
+    }
+
+    /// A pre-compiled pattern. Compile a pattern once, then reuse it for many scans
+    #[pyattr]
+    #[pyclass(name = "Pattern")]
+    #[derive(Debug, Clone, PyPayload)]
+    pub struct PyPattern(Pattern);
+
+    impl Constructor for PyPattern {
+        type Args = FuncArgs;
+
+        fn py_new(_cls: PyTypeRef, args: Self::Args, vm: &VirtualMachine) -> PyResult<PyObjectRef> {
+            // String (IDA-style sig)
+            // Bytes (data)
+            // Bytes (data), String (mask)
+
+            let pattern = match args.args.len() {
+                1 => {
+                    if let Ok(sig) = args.args[0].try_to_value::<String>(vm) {
+                        sig.parse::<Pattern>()
+                            .map_err(|e| vm.new_runtime_error(format!("{e}")))?
+                    } else {
+                        let data = args.args[0].try_to_value::<Vec<u8>>(vm)?;
+                        Pattern::from_data(&data)
+                    }
+                }
+
+                2 => {
+                    let data = args.args[0].try_to_value::<Vec<u8>>(vm)?;
+                    let mask = args.args[1].try_to_value::<String>(vm)?;
+
+                    Pattern::from_data_with_mask(&data, &mask)
+                        .map_err(|e| vm.new_runtime_error(format!("{e}")))?
+                }
+
+                _ => {
+                    return Err(vm.new_runtime_error(format!(
+                        "expected 1 or 2 args, found {}",
+                        args.args.len()
+                    )))
+                }
+            };
+
+            Ok(Self(pattern).into_pyobject(vm))
+        }
+    }
+
+    #[pyclass(with(Constructor))]
+    impl PyPattern {
+        /// Search for the first occurrence of the pattern starting at address
+        /// Pass `parallel=True` to split the scan across multiple threads
+        ///
+        /// unsafe fn
+        #[pymethod]
+        fn scan(
+            &self,
+            address: Address,
+            scan_size: usize,
+            args: FuncArgs,
+            vm: &VirtualMachine,
+        ) -> PyResult<Option<Address>> {
+            let [parallel] = flag_args(args, ["parallel"], vm)?;
+
+            let res = if parallel {
+                unsafe { self.0.scan_parallel(address as _, scan_size) }
+            } else {
+                unsafe { self.0.scan(address as _, scan_size) }
+            };
+
+            Ok(res.map(|s| s.addr as _))
+        }
+
+        /// Search for every occurrence of the pattern starting at address, up to an optional limit of results
+        ///
+        /// unsafe fn
+        #[pymethod]
+        fn scan_all(
+            &self,
+            address: Address,
+            scan_size: usize,
+            limit: OptionalArg<usize>,
+        ) -> Vec<Address> {
+            let scans = unsafe { self.0.scan_all(address as _, scan_size) };
+            scans
+                .take(limit.into_option().unwrap_or(usize::MAX))
+                .map(|s| s.addr as _)
+                .collect()
+        }
+
+        #[pymethod(magic)]
+        fn repr(&self) -> String {
+            format!("{:?}", self.0)
+        }
+
+        /// The pattern as an IDA-style sig
+        #[pymethod(magic)]
+        fn str(&self) -> String {
+            self.0.to_ida_string()
+        }
+    }
+
+    /// A Cheat Engine style value scan. The first scan searches the whole process for a value,
+    /// and every scan after that narrows those results down
+    #[pyattr]
+    #[pyclass(name = "ValueScan")]
+    #[derive(Debug, PyPayload)]
+    pub struct PyValueScan(Mutex<ValueScan>);
+
+    impl Constructor for PyValueScan {
+        type Args = FuncArgs;
+
+        fn py_new(_cls: PyTypeRef, args: Self::Args, vm: &VirtualMachine) -> PyResult<PyObjectRef> {
+            // String (type), optional kwargs stride, tolerance, writable_only
+
+            let [value_type] = args.args.as_slice() else {
+                return Err(
+                    vm.new_runtime_error(format!("expected 1 arg, found {}", args.args.len()))
+                );
+            };
+
+            let value_type = value_type.try_to_value::<String>(vm)?;
+            let value_type = match value_type.as_str() {
+                "i8" => ValueType::I8,
+                "i16" => ValueType::I16,
+                "i32" => ValueType::I32,
+                "i64" => ValueType::I64,
+                "f32" => ValueType::F32,
+                "f64" => ValueType::F64,
+                "utf8" => ValueType::Utf8,
+                "utf16" => ValueType::Utf16,
+                _ => {
+                    return Err(vm.new_runtime_error(format!(
+                        "unknown type {value_type}, expected i8, i16, i32, i64, f32, f64, utf8, or utf16"
+                    )))
+                }
+            };
+
+            let mut scan = ValueScan::new(value_type);
+
+            for (name, value) in args.kwargs {
+                match name.as_str() {
+                    "stride" => scan.stride = value.try_to_value::<usize>(vm)?,
+                    "tolerance" => scan.tolerance = value.try_float(vm)?.to_f64(),
+                    "writable_only" => scan.writable_only = value.try_to_value::<bool>(vm)?,
+                    _ => {
+                        return Err(
+                            vm.new_type_error(format!("unexpected keyword argument '{name}'"))
+                        )
+                    }
+                }
+            }
+
+            Ok(Self(Mutex::new(scan)).into_pyobject(vm))
+        }
+    }
+
+    impl PyValueScan {
+        /// Run a scan, returning how many results are left
+        fn run(&self, filter: Filter, vm: &VirtualMachine) -> PyResult<usize> {
+            self.0
+                .lock()
+                .unwrap()
+                .scan(filter)
+                .map_err(|e| vm.new_runtime_error(format!("{e}")))
+        }
+
+        /// Convert a python object to a value of the scanned type
+        fn value(&self, value: PyObjectRef, vm: &VirtualMachine) -> PyResult<Value> {
+            let value_type = self.0.lock().unwrap().value_type();
+
+            let value = match value_type {
+                ValueType::I8 => Value::I8(value.try_to_value(vm)?),
+                ValueType::I16 => Value::I16(value.try_to_value(vm)?),
+                ValueType::I32 => Value::I32(value.try_to_value(vm)?),
+                ValueType::I64 => Value::I64(value.try_to_value(vm)?),
+                ValueType::F32 => Value::F32(value.try_float(vm)?.to_f64() as f32),
+                ValueType::F64 => Value::F64(value.try_float(vm)?.to_f64()),
+                ValueType::Utf8 => Value::Utf8(value.try_to_value(vm)?),
+                ValueType::Utf16 => Value::Utf16(value.try_to_value(vm)?),
+            };
+
+            Ok(value)
+        }
+    }
+
+    #[pyclass(with(Constructor))]
+    impl PyValueScan {
+        /// Keep values equal to value. Floats may differ by the tolerance
+        #[pymethod]
+        fn exact(&self, value: PyObjectRef, vm: &VirtualMachine) -> PyResult<usize> {
+            let value = self.value(value, vm)?;
+            self.run(Filter::Exact(value), vm)
+        }
+
+        /// Keep numbers in the inclusive range low..=high
+        #[pymethod]
+        fn between(
+            &self,
+            low: PyObjectRef,
+            high: PyObjectRef,
+            vm: &VirtualMachine,
+        ) -> PyResult<usize> {
+            let low = self.value(low, vm)?;
+            let high = self.value(high, vm)?;
+            self.run(Filter::Between(low, high), vm)
+        }
+
+        /// Keep values which changed since the previous scan
+        #[pymethod]
+        fn changed(&self, vm: &VirtualMachine) -> PyResult<usize> {
+            self.run(Filter::Changed, vm)
+        }
+
+        /// Keep values which didn't change since the previous scan
+        #[pymethod]
+        fn unchanged(&self, vm: &VirtualMachine) -> PyResult<usize> {
+            self.run(Filter::Unchanged, vm)
+        }
+
+        /// Keep numbers which are bigger than in the previous scan
+        #[pymethod]
+        fn increased(&self, vm: &VirtualMachine) -> PyResult<usize> {
+            self.run(Filter::Increased, vm)
+        }
+
+        /// Keep numbers which are smaller than in the previous scan
+        #[pymethod]
+        fn decreased(&self, vm: &VirtualMachine) -> PyResult<usize> {
+            self.run(Filter::Decreased, vm)
+        }
+
+        /// Every result left as a list of (address, value) as of the last scan,
+        /// up to an optional limit of results
+        #[pymethod]
+        fn results(
+            &self,
+            limit: OptionalArg<usize>,
+            vm: &VirtualMachine,
+        ) -> Vec<(Address, PyObjectRef)> {
+            let scan = self.0.lock().unwrap();
+
+            scan.results()
+                .take(limit.into_option().unwrap_or(usize::MAX))
+                .map(|(addr, value)| {
+                    let value = match value {
+                        Value::I8(v) => v.to_pyobject(vm),
+                        Value::I16(v) => v.to_pyobject(vm),
+                        Value::I32(v) => v.to_pyobject(vm),
+                        Value::I64(v) => v.to_pyobject(vm),
+                        Value::F32(v) => (v as f64).to_pyobject(vm),
+                        Value::F64(v) => v.to_pyobject(vm),
+                        Value::Utf8(v) | Value::Utf16(v) => v.to_pyobject(vm),
+                    };
+
+                    (addr as Address, value)
+                })
+                .collect()
+        }
+
+        /// How many results are left
+        #[pygetset]
+        fn count(&self) -> usize {
+            self.0.lock().unwrap().len()
+        }
+
+        /// How many scans were done
+        #[pygetset]
+        fn scans(&self) -> usize {
+            self.0.lock().unwrap().scans()
+        }
+
+        /// Forget every result, so the next scan searches the whole process again
+        #[pymethod]
+        fn reset(&self) {
+            self.0.lock().unwrap().reset();
+        }
+
+        #[pymethod(magic)]
+        fn repr(&self) -> String {
+            let scan = self.0.lock().unwrap();
+            format!("ValueScan({:?}, {} results)", scan.value_type(), scan.len())
+        }
+
+        #[pymethod(magic)]
+        fn str(&self) -> String {
+            self.repr()
+        }
+    }
+
+    /// A chain of pointers from a static address inside a module to a target
+    /// Written as `game.exe+0x1a2b0 -> [0x10, 0x8, 0x3c]`
+    #[pyattr]
+    #[pyclass(name = "PointerChain")]
+    #[derive(Debug, PyPayload)]
+    pub struct PyPointerChain(PointerChain);
+
+    impl Constructor for PyPointerChain {
+        type Args = String;
+
+        fn py_new(
+            _cls: PyTypeRef,
+            chain: Self::Args,
+            vm: &VirtualMachine,
+        ) -> PyResult<PyObjectRef> {
+            let chain = chain
+                .parse::<PointerChain>()
+                .map_err(|e| vm.new_runtime_error(format!("{e}")))?;
+
+            Ok(Self(chain).into_pyobject(vm))
+        }
+    }
+
+    #[pyclass(with(Constructor))]
+    impl PyPointerChain {
+        /// The name of the module the chain starts in
+        #[pygetset]
+        fn module(&self) -> String {
+            self.0.module.clone()
+        }
+
+        /// The address the chain starts at, relative to the module's base
+        #[pygetset]
+        fn rva(&self) -> usize {
+            self.0.rva
+        }
+
+        /// The offsets, in the same format mem.deep_pointer takes
+        #[pygetset]
+        fn offsets(&self) -> Vec<usize> {
+            self.0.offsets.clone()
+        }
+
+        /// The static address the chain starts at, or None if the module isn't loaded
+        #[pygetset]
+        fn base(&self) -> Option<Address> {
+            self.0.base().map(|base| base as _)
+        }
+
+        /// Follow the chain to the address it currently leads to
+        ///
+        /// unsafe fn
+        #[pymethod]
+        fn resolve(&self, vm: &VirtualMachine) -> PyResult<Address> {
+            let address = unsafe { self.0.resolve() };
+            let address = address.map_err(|e| vm.new_runtime_error(format!("{e}")))?;
+
+            Ok(address as _)
+        }
+
+        #[pymethod(magic)]
+        fn repr(&self) -> String {
+            self.0.to_string()
+        }
+
+        #[pymethod(magic)]
+        fn str(&self) -> String {
+            self.0.to_string()
+        }
+    }
+
+    /// A match found while scanning a Module or Segment, along with the module it's in
+    #[pyattr]
+    #[pyclass(name = "ScanResult")]
+    #[derive(Debug, PyPayload)]
+    pub struct PyScanResult(RegionScan);
+
+    #[pyclass]
+    impl PyScanResult {
+        /// The address of the match
+        #[pygetset]
+        fn address(&self) -> Address {
+            self.0.addr as _
+        }
+
+        /// The name of the module the match is in, or None
+        #[pygetset]
+        fn module(&self) -> Option<String> {
+            self.0.module.clone()
+        }
+
+        /// The address of the match relative to the module's base, or None if it's not in a module
+        #[pygetset]
+        fn rva(&self) -> Option<usize> {
+            self.0.rva
+        }
+
+        #[pymethod(magic)]
+        fn repr(&self) -> String {
+            match (&self.0.module, self.0.rva) {
+                (Some(module), Some(rva)) => {
+                    format!("ScanResult({:#x}, {module}+{rva:#x})", self.0.addr as usize)
+                }
+                _ => format!("ScanResult({:#x})", self.0.addr as usize),
+            }
+        }
+
+        #[pymethod(magic)]
+        fn str(&self) -> String {
+            self.repr()
+        }
     }
 }
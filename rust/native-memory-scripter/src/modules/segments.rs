@@ -2,6 +2,8 @@ use rustpython_vm::pymodule;
 
 #[pymodule]
 pub mod segments {
+    use std::ops::Deref;
+
     use mutation::segments::{self, Segment};
     use rustpython_vm::{
         convert::ToPyObject as _, pyclass, PyObjectRef, PyPayload, VirtualMachine,
@@ -25,7 +27,14 @@ pub mod segments {
     #[pyattr]
     #[pyclass(name = "Segment")]
     #[derive(Debug, PyPayload)]
-    struct PySegment(Segment);
+    pub struct PySegment(Segment);
+
+    impl Deref for PySegment {
+        type Target = Segment;
+        fn deref(&self) -> &Self::Target {
+            &self.0
+        }
+    }
 
     #[pyclass]
     impl PySegment {